@@ -21,6 +21,12 @@ pub struct Cli {
     /// Start a agent
     #[clap(short = 'a', long)]
     pub agent: Option<String>,
+    /// Show which files the agent's RAG would index and their estimated tokens, then exit
+    #[clap(long, requires = "agent")]
+    pub plan_rag: bool,
+    /// Start the agent without loading its RAG
+    #[clap(long, requires = "agent")]
+    pub no_rag: bool,
     /// Start a RAG
     #[clap(short = 'R', long)]
     pub rag: Option<String>,
@@ -69,6 +75,9 @@ pub struct Cli {
     /// List all RAGs
     #[clap(long)]
     pub list_rags: bool,
+    /// List the document formats RAGs can load
+    #[clap(long)]
+    pub list_formats: bool,
     /// Input text
     #[clap(trailing_var_arg = true)]
     text: Vec<String>,
@@ -87,4 +96,44 @@ impl Cli {
         }
         Some(text)
     }
+
+    /// Whether to start `default_agent`, which only happens when entering the REPL
+    /// without selecting a prompt, role, session, rag or mode.
+    pub fn use_default_agent(&self, no_input: bool) -> bool {
+        no_input
+            && self.agent.is_none()
+            && self.prompt.is_none()
+            && self.role.is_none()
+            && self.session.is_none()
+            && self.rag.is_none()
+            && !self.execute
+            && !self.code
+            && !self.info
+            && !self.list_sessions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn use_default_agent(args: &[&str]) -> bool {
+        let cli =
+            Cli::try_parse_from(std::iter::once("aichat").chain(args.iter().copied())).unwrap();
+        let no_input = cli.text().is_none() && cli.file.is_empty();
+        cli.use_default_agent(no_input)
+    }
+
+    #[test]
+    fn test_use_default_agent() {
+        assert!(use_default_agent(&[]));
+        assert!(use_default_agent(&["-m", "openai:gpt-4o"]));
+        assert!(!use_default_agent(&["hello"]));
+        assert!(!use_default_agent(&["-f", "notes.md"]));
+        assert!(!use_default_agent(&["--info"]));
+        assert!(!use_default_agent(&["--session"]));
+        assert!(!use_default_agent(&["-s", "work"]));
+        assert!(!use_default_agent(&["-r", "coder"]));
+        assert!(!use_default_agent(&["-a", "coder"]));
+    }
 }
@@ -241,6 +241,7 @@ fn meta_llama_build_chat_completions_body(
         temperature,
         top_p,
         functions: _,
+        response_format: _,
         stream: _,
     } = data;
     let prompt = generate_prompt(&messages, pt)?;
@@ -265,6 +266,7 @@ fn mistral_build_chat_completions_body(data: ChatCompletionsData, model: &Model)
         temperature,
         top_p,
         functions: _,
+        response_format: _,
         stream: _,
     } = data;
     let prompt = generate_prompt(&messages, MISTRAL_PROMPT_FORMAT)?;
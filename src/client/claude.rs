@@ -138,6 +138,7 @@ pub fn claude_build_chat_completions_body(
         temperature,
         top_p,
         functions,
+        response_format: _,
         stream,
     } = data;
 
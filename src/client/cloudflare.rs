@@ -140,6 +140,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         temperature,
         top_p,
         functions: _,
+        response_format: _,
         stream,
     } = data;
 
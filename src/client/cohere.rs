@@ -184,6 +184,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         temperature,
         top_p,
         functions,
+        response_format: _,
         stream,
     } = data;
 
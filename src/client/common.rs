@@ -16,7 +16,7 @@ use fancy_regex::Regex;
 use indexmap::IndexMap;
 use lazy_static::lazy_static;
 use reqwest::{Client as ReqwestClient, ClientBuilder, Proxy, RequestBuilder};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{env, future::Future, time::Duration};
 use tokio::sync::mpsc::unbounded_channel;
@@ -486,9 +486,31 @@ pub struct ChatCompletionsData {
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
     pub functions: Option<Vec<FunctionDeclaration>>,
+    pub response_format: Option<ResponseFormat>,
     pub stream: bool,
 }
 
+/// The shape the reply must take: any JSON object, or JSON matching a schema.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    JsonObject,
+    JsonSchema(Value),
+}
+
+impl ResponseFormat {
+    /// The `response_format` parameter of the OpenAI chat completions API.
+    pub fn to_openai(&self) -> Value {
+        match self {
+            ResponseFormat::JsonObject => json!({ "type": "json_object" }),
+            ResponseFormat::JsonSchema(schema) => json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response", "schema": schema },
+            }),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ChatCompletionsOutput {
     pub text: String,
@@ -507,7 +529,7 @@ impl ChatCompletionsOutput {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EmbeddingsData {
     pub texts: Vec<String>,
     pub query: bool,
@@ -235,6 +235,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Valu
         temperature,
         top_p,
         functions,
+        response_format: _,
         stream,
     } = data;
 
@@ -192,6 +192,10 @@ impl Model {
         self.data.supports_function_calling
     }
 
+    pub fn supports_response_format(&self) -> bool {
+        self.data.supports_response_format
+    }
+
     pub fn default_chunk_size(&self) -> usize {
         self.data.default_chunk_size.unwrap_or(1000)
     }
@@ -280,6 +284,8 @@ pub struct ModelData {
     pub supports_vision: bool,
     #[serde(default)]
     pub supports_function_calling: bool,
+    #[serde(default)]
+    pub supports_response_format: bool,
 
     // embedding-only properties
     pub output_vector_size: Option<usize>,
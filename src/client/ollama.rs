@@ -157,6 +157,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         temperature,
         top_p,
         functions: _,
+        response_format,
         stream,
     } = data;
 
@@ -232,6 +233,11 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
     if let Some(v) = top_p {
         body["options"]["top_p"] = v.into();
     }
+    match response_format {
+        Some(ResponseFormat::JsonObject) => body["format"] = "json".into(),
+        Some(ResponseFormat::JsonSchema(schema)) => body["format"] = schema,
+        None => {}
+    }
 
     Ok(body)
 }
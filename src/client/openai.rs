@@ -169,6 +169,7 @@ pub fn openai_build_chat_completions_body(data: ChatCompletionsData, model: &Mod
         temperature,
         top_p,
         functions,
+        response_format,
         stream,
     } = data;
 
@@ -224,6 +225,9 @@ pub fn openai_build_chat_completions_body(data: ChatCompletionsData, model: &Mod
     if stream {
         body["stream"] = true.into();
     }
+    if let Some(response_format) = response_format {
+        body["response_format"] = response_format.to_openai();
+    }
     if let Some(functions) = functions {
         body["tools"] = functions
             .iter()
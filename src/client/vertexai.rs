@@ -259,6 +259,7 @@ pub fn gemini_build_chat_completions_body(
         temperature,
         top_p,
         functions,
+        response_format: _,
         stream: _,
     } = data;
 
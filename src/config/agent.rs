@@ -1,12 +1,19 @@
 use super::*;
 
 use crate::{
-    client::Model,
-    function::{Functions, FunctionsFilter, SELECTED_ALL_FUNCTIONS},
+    client::{Model, ResponseFormat},
+    function::{deny_functions, Functions, FunctionsFilter, SELECTED_ALL_FUNCTIONS},
 };
 
-use anyhow::{Context, Result};
-use std::{fs::read_to_string, path::Path};
+use anyhow::{bail, Context, Result};
+use indexmap::{IndexMap, IndexSet};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -21,24 +28,100 @@ pub struct Agent {
     rag: Option<Arc<Rag>>,
     #[serde(skip)]
     model: Model,
+    model_source: ModelSource,
+    #[serde(skip)]
+    functions_denylist: Option<FunctionsFilter>,
+    sources: AgentSources,
+    #[serde(skip)]
+    mtimes: AgentMtimes,
+    /// Set when started without its RAG; reloading keeps it off.
+    #[serde(skip)]
+    skip_rag: bool,
+    /// The prompts of the definition's conversation starters.
+    #[serde(skip)]
+    starter_texts: Vec<String>,
+}
+
+/// The agents whose functions and RAG an agent uses, itself unless it `extends` another.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+struct AgentSources {
+    /// The agent followed by the agents it `extends`, up to the root.
+    lineage: Vec<String>,
+    functions: String,
+    rag: String,
+    #[serde(skip)]
+    document_paths: Vec<String>,
+}
+
+impl AgentSources {
+    fn new(
+        agent_definitions: &AgentDefinitionCache,
+        name: &str,
+        definition: &AgentDefinition,
+        agent_config: &AgentConfig,
+    ) -> Result<Self> {
+        let lineage = agent_lineage(name, definition, |parent| {
+            agent_definitions.load(&Config::agent_definition_file(parent)?)
+        })?;
+        let find = |has: &dyn Fn(&str) -> Result<bool>| -> Result<String> {
+            for agent in &lineage {
+                if has(agent)? {
+                    return Ok(agent.clone());
+                }
+            }
+            Ok(name.to_string())
+        };
+        let functions = find(&|agent| Ok(Config::agent_functions_file(agent)?.exists()))?;
+        let rag = find(&|agent| {
+            Ok(Config::agent_rag_file(agent)?.exists()
+                || Config::agent_embeddings_dir(agent)?.is_dir())
+        })?;
+        if functions != name || rag != name {
+            debug!("agent '{name}' uses the functions of '{functions}' and the rag of '{rag}'");
+        }
+        let document_paths = agent_config.rag_document_paths(&rag)?;
+        Ok(Self {
+            lineage,
+            functions,
+            rag,
+            document_paths,
+        })
+    }
+}
+
+/// Where an agent's model came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelSource {
+    AgentConfig,
+    Fallback,
+    #[default]
+    GlobalDefault,
+}
+
+impl std::fmt::Display for ModelSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match self {
+            ModelSource::AgentConfig => "agent config",
+            ModelSource::Fallback => "fallback models",
+            ModelSource::GlobalDefault => "global default",
+        };
+        write!(f, "{value}")
+    }
 }
 
 impl Agent {
+    /// Loads the agent `name`. With `skip_rag`, its rag is left out even when one
+    /// is prebuilt or document paths are configured.
     pub async fn init(
         config: &GlobalConfig,
         name: &str,
+        skip_rag: bool,
         abort_signal: AbortSignal,
     ) -> Result<Self> {
         let definition_path = Config::agent_definition_file(name)?;
-        let functions_path = Config::agent_functions_file(name)?;
-        let rag_path = Config::agent_rag_file(name)?;
-        let embeddings_dir = Config::agent_embeddings_dir(name)?;
-        let definition = AgentDefinition::load(&definition_path)?;
-        let functions = if functions_path.exists() {
-            Functions::init(&functions_path)?
-        } else {
-            Functions::default()
-        };
+        let agent_definitions = config.read().agent_definitions.clone();
+        let definition = agent_definitions.load(&definition_path)?;
         let agent_config = config
             .read()
             .agents
@@ -46,38 +129,287 @@ impl Agent {
             .find(|v| v.name == name)
             .cloned()
             .unwrap_or_else(|| AgentConfig::new(name));
-        let model = {
-            let config = config.read();
-            match agent_config.model_id.as_ref() {
-                Some(model_id) => Model::retrieve_chat(&config, model_id)?,
-                None => config.current_model().clone(),
-            }
+        let sources = AgentSources::new(&agent_definitions, name, &definition, &agent_config)?;
+        let functions_path = Config::agent_functions_file(&sources.functions)?;
+        let rag_path = Config::agent_rag_file(&sources.rag)?;
+        let functions = if functions_path.exists() {
+            Functions::init(&functions_path)?
+        } else {
+            Functions::default()
         };
-        let rag = if rag_path.exists() {
-            Some(Arc::new(Rag::load(config, "rag", &rag_path)?))
-        } else if embeddings_dir.is_dir() {
-            println!("The agent uses an embeddings directory, initializing RAG...");
-            let doc_path = embeddings_dir.display().to_string();
+        definition.validate_tools(&functions).with_context(|| {
+            format!(
+                "Agent '{name}' does not match the functions at '{}'",
+                functions_path.display()
+            )
+        })?;
+        definition.validate_required_functions(&functions, &functions_path)?;
+        let diff = definition.functions_diff(&functions);
+        info!(
+            "agent '{name}' functions: {} present, {} missing, {} extra",
+            diff.present.len(),
+            diff.missing.len(),
+            diff.extra.len()
+        );
+        let (model, model_source) = agent_config.resolve_model(&config.read())?;
+        info!(
+            "agent '{name}' uses model '{}' from {model_source}",
+            model.id()
+        );
+        if definition.response_format.is_some() && !model.supports_response_format() {
+            eprintln!(
+                "{}",
+                warning_text(&format!(
+                    "WARNING: agent '{name}' declares a response_format, but the model '{}' does not support structured output, so it will not be enforced.",
+                    model.id()
+                ))
+            );
+        }
+        if let Some(filter) = &agent_config.dangerously_functions_filter {
+            for pattern in functions.expand_filter(filter).1 {
+                eprintln!(
+                    "{}",
+                    warning_text(&format!(
+                        "WARNING: the dangerously_functions_filter pattern '{pattern}' of agent '{name}' matches no installed function."
+                    ))
+                );
+            }
+        }
+        let doc_paths = &sources.document_paths;
+        let rag = if skip_rag {
+            info!("agent '{name}' started without its rag");
+            None
+        } else if rag_path.exists() {
+            let mut rag = Rag::load(config, "rag", &rag_path)?;
+            check_prebuilt_rag(config, &rag, &rag_path)?;
+            if !doc_paths.is_empty() {
+                rag.refresh(&rag_path, doc_paths, abort_signal).await?;
+            }
+            Some(Arc::new(rag))
+        } else if !doc_paths.is_empty() {
+            println!("The agent uses document paths, initializing RAG...");
             Some(Arc::new(
-                Rag::init(config, "rag", &rag_path, &[doc_path], abort_signal).await?,
+                Rag::init(config, "rag", &rag_path, doc_paths, abort_signal, None).await?,
             ))
         } else {
             None
         };
+        // Taken after the rag is refreshed, so saving it doesn't look like a change.
+        let mtimes = AgentMtimes::new(&sources)?;
 
-        Ok(Self {
+        let mut agent = Self::from_parts(name, definition, model)
+            .with_config(agent_config)
+            .with_functions(functions)
+            .with_functions_denylist(config.read().functions_denylist.clone());
+        if let Some(rag) = rag {
+            agent = agent.with_rag(rag);
+        }
+        agent.model_source = model_source;
+        agent.sources = sources;
+        agent.mtimes = mtimes;
+        agent.skip_rag = skip_rag;
+        Ok(agent)
+    }
+
+    /// Builds an agent from an in-memory definition without touching the disk.
+    /// It has no functions or RAG until `with_functions`/`with_rag`.
+    pub fn from_parts(name: &str, definition: AgentDefinition, model: Model) -> Self {
+        Self {
             name: name.to_string(),
-            config: agent_config,
+            config: AgentConfig::new(name),
+            starter_texts: definition.starter_texts(),
             definition,
-            functions,
-            rag,
+            functions: Functions::default(),
+            rag: None,
             model,
-        })
+            model_source: ModelSource::default(),
+            functions_denylist: None,
+            sources: AgentSources {
+                lineage: vec![name.to_string()],
+                functions: name.to_string(),
+                rag: name.to_string(),
+                document_paths: vec![],
+            },
+            mtimes: AgentMtimes::default(),
+            skip_rag: false,
+        }
+    }
+
+    pub fn with_config(mut self, config: AgentConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_functions(mut self, functions: Functions) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    pub fn with_rag(mut self, rag: Arc<Rag>) -> Self {
+        self.rag = Some(rag);
+        self
+    }
+
+    pub fn with_functions_denylist(mut self, functions_denylist: Option<FunctionsFilter>) -> Self {
+        self.functions_denylist = functions_denylist;
+        self
+    }
+
+    /// Re-reads the definition, functions and RAG when their files, the definitions
+    /// of the agents it extends or its document paths changed on disk, keeping the
+    /// current model and sampling settings. Returns whether anything changed.
+    pub async fn reload(
+        &mut self,
+        config: &GlobalConfig,
+        abort_signal: AbortSignal,
+    ) -> Result<bool> {
+        let name = &self.name;
+        let agent_definitions = config.read().agent_definitions.clone();
+        // Cached by mtime, so this only parses definitions that changed.
+        let definition = agent_definitions.load(&Config::agent_definition_file(name)?)?;
+        let sources = AgentSources::new(&agent_definitions, name, &definition, &self.config)?;
+        let mtimes = AgentMtimes::new(&sources)?;
+        if sources == self.sources && mtimes == self.mtimes {
+            return Ok(false);
+        }
+        let functions_path = Config::agent_functions_file(&sources.functions)?;
+        let functions = if mtimes.functions == self.mtimes.functions
+            && sources.functions == self.sources.functions
+        {
+            self.functions.clone()
+        } else if functions_path.exists() {
+            Functions::init(&functions_path)?
+        } else {
+            Functions::default()
+        };
+        definition.validate_tools(&functions).with_context(|| {
+            format!(
+                "Agent '{name}' does not match the functions at '{}'",
+                functions_path.display()
+            )
+        })?;
+        definition.validate_required_functions(&functions, &functions_path)?;
+        let rag = if self.skip_rag
+            || (mtimes.rag == self.mtimes.rag
+                && sources.rag == self.sources.rag
+                && sources.document_paths == self.sources.document_paths)
+        {
+            self.rag.clone()
+        } else {
+            let rag_path = Config::agent_rag_file(&sources.rag)?;
+            let doc_paths = &sources.document_paths;
+            if rag_path.exists() {
+                let mut rag = Rag::load(config, "rag", &rag_path)?;
+                check_prebuilt_rag(config, &rag, &rag_path)?;
+                if !doc_paths.is_empty() {
+                    rag.refresh(&rag_path, doc_paths, abort_signal).await?;
+                }
+                Some(Arc::new(rag))
+            } else if !doc_paths.is_empty() {
+                Some(Arc::new(
+                    Rag::init(config, "rag", &rag_path, doc_paths, abort_signal, None).await?,
+                ))
+            } else {
+                None
+            }
+        };
+        self.starter_texts = definition.starter_texts();
+        self.definition = definition;
+        self.functions = functions;
+        self.rag = rag;
+        self.mtimes = AgentMtimes::new(&sources)?;
+        self.sources = sources;
+        Ok(true)
+    }
+
+    /// Reports what the agent's document paths would index, without embedding anything.
+    pub async fn plan_rag(config: &GlobalConfig, name: &str) -> Result<String> {
+        let agent_config = config
+            .read()
+            .agents
+            .iter()
+            .find(|v| v.name == name)
+            .cloned()
+            .unwrap_or_else(|| AgentConfig::new(name));
+        let doc_paths = agent_config.rag_document_paths(name)?;
+        if doc_paths.is_empty() {
+            bail!(
+                "Agent '{name}' has no document_paths and no embeddings directory at '{}'",
+                Config::agent_embeddings_dir(name)?.display()
+            );
+        }
+        let mut plan = Rag::plan(config, &doc_paths).await?;
+        plan.estimate = Some(Rag::estimate(config, &plan).await?);
+        let output = serde_yaml::to_string(&plan)?;
+        Ok(output)
+    }
+
+    /// Swaps in a prebuilt rag without embedding anything. It must use the same
+    /// embedding model and dimensions as the agent's current rag.
+    pub fn set_rag(&mut self, config: &GlobalConfig, path: &Path) -> Result<()> {
+        if !path.exists() {
+            bail!("No rag at '{}'", path.display());
+        }
+        let rag = Rag::load(config, "rag", path)?;
+        let expected = match &self.rag {
+            Some(current) => Some((
+                current.embedding_model().id(),
+                current.dimensions(),
+                "the agent's rag",
+            )),
+            None => config
+                .read()
+                .rag_embedding_model
+                .clone()
+                .map(|v| (v, None, "`rag_embedding_model`")),
+        };
+        if let Some((model_id, dimensions, expected_by)) = expected {
+            check_rag_compatible(
+                (&model_id, dimensions),
+                (&rag.embedding_model().id(), rag.dimensions()),
+                expected_by,
+            )
+            .with_context(|| format!("Cannot use the rag at '{}'", path.display()))?;
+        }
+        info!("agent '{}' uses the rag at '{}'", self.name, path.display());
+        self.rag = Some(Arc::new(rag));
+        Ok(())
+    }
+
+    /// Reports the indexed files that went missing or changed, and the files in the
+    /// embeddings directory that are not indexed yet. `None` when the agent has no rag.
+    pub async fn validate_rag(&self) -> Result<Option<RagStatus>> {
+        let Some(rag) = &self.rag else {
+            return Ok(None);
+        };
+        let paths = self.config.rag_document_paths(&self.sources.rag)?;
+        Ok(Some(rag.status(&paths).await?))
     }
 
-    pub fn export(&self) -> Result<String> {
+    pub fn capabilities(&self) -> AgentCapabilities {
+        AgentCapabilities {
+            name: self.name.clone(),
+            version: self.definition.version.clone(),
+            model_id: self.model.id(),
+            has_rag: self.rag.is_some(),
+            function_count: self.functions.names().len(),
+            starter_count: self.definition.conversation_starters.len(),
+        }
+    }
+
+    pub fn export(&self, include_rag_files: bool) -> Result<String> {
         let mut value = serde_json::json!(self);
-        value["functions_dir"] = Config::agent_functions_dir(&self.name)?
+        if include_rag_files {
+            if let Some(rag) = &self.rag {
+                let files = rag.files();
+                value["rag_files_count"] = files.len().into();
+                value["rag_files"] = files.into();
+            }
+        }
+        value["capabilities"] = serde_json::json!(self.capabilities());
+        value["functions_diff"] =
+            serde_json::json!(self.definition.functions_diff(&self.functions));
+        value["functions_dir"] = Config::agent_functions_dir(&self.sources.functions)?
             .display()
             .to_string()
             .into();
@@ -113,16 +445,54 @@ impl Agent {
         self.rag.clone()
     }
 
-    pub fn conversation_staters(&self) -> &[String] {
+    /// The prompts of the conversation starters.
+    pub fn conversation_starters(&self) -> &[String] {
+        &self.starter_texts
+    }
+
+    /// The conversation starters with their labels, categories and display hints.
+    pub fn conversation_starter_descriptors(&self) -> &[ConversationStarter] {
         &self.definition.conversation_starters
     }
+
+    /// The argument slots of the starter at `index`, empty when it is fixed or out of range.
+    pub fn starter_arguments(&self, index: usize) -> Vec<String> {
+        self.definition
+            .conversation_starters
+            .get(index)
+            .map(|v| v.arguments())
+            .unwrap_or_default()
+    }
+
+    /// The role sent to the model: the rendered instructions between the configured
+    /// prefix and suffix, with the resolved model, sampling and functions filter.
+    pub fn effective_role(&self) -> Role {
+        let instructions = self.definition.render_instructions();
+        let instructions = [
+            self.config.instructions_prefix.as_deref(),
+            Some(instructions.as_str()),
+            self.config.instructions_suffix.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("\n\n");
+        let mut role = Role::new("", &instructions);
+        role.sync(self);
+        role.set_response_format(self.definition.response_format.clone());
+        role
+    }
+
+    #[allow(unused)]
+    #[deprecated(note = "use conversation_starters")]
+    pub fn conversation_staters(&self) -> &[String] {
+        self.conversation_starters()
+    }
 }
 
 impl RoleLike for Agent {
     fn to_role(&self) -> Role {
-        let mut role = Role::new("", &self.definition.instructions);
-        role.sync(self);
-        role
+        self.effective_role()
     }
 
     fn model(&self) -> &Model {
@@ -134,24 +504,34 @@ impl RoleLike for Agent {
     }
 
     fn temperature(&self) -> Option<f64> {
-        self.config.temperature
+        self.config.temperature.or(self.definition.temperature)
     }
 
     fn top_p(&self) -> Option<f64> {
-        self.config.top_p
+        self.config.top_p.or(self.definition.top_p)
     }
 
     fn functions_filter(&self) -> Option<FunctionsFilter> {
         if self.functions.is_empty() {
-            None
-        } else {
-            Some(SELECTED_ALL_FUNCTIONS.into())
+            return None;
         }
+        let filter = match &self.config.dangerously_functions_filter {
+            Some(filter) => self.functions.expand_filter(filter).0?,
+            None => {
+                debug!(
+                    "agent '{}' has no dangerously_functions_filter, exposing all functions",
+                    self.name
+                );
+                SELECTED_ALL_FUNCTIONS.to_string()
+            }
+        };
+        Some(deny_functions(&filter, self.functions_denylist.as_ref()))
     }
 
     fn set_model(&mut self, model: &Model) {
         self.config.model_id = Some(model.id());
         self.model = model.clone();
+        self.model_source = ModelSource::AgentConfig;
     }
 
     fn set_temperature(&mut self, value: Option<f64>) {
@@ -162,7 +542,34 @@ impl RoleLike for Agent {
         self.config.top_p = value;
     }
 
-    fn set_functions_filter(&mut self, _value: Option<FunctionsFilter>) {}
+    fn set_functions_filter(&mut self, value: Option<FunctionsFilter>) {
+        self.config.dangerously_functions_filter = value;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct AgentMtimes {
+    /// The definition of every agent in the lineage, since a parent's `extends` decides
+    /// where the functions and RAG come from.
+    definitions: Vec<Option<SystemTime>>,
+    functions: Option<SystemTime>,
+    rag: Option<SystemTime>,
+}
+
+impl AgentMtimes {
+    fn new(sources: &AgentSources) -> Result<Self> {
+        let mtime = |path: PathBuf| path.metadata().and_then(|v| v.modified()).ok();
+        let definitions = sources
+            .lineage
+            .iter()
+            .map(|name| Config::agent_definition_file(name).map(mtime))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            definitions,
+            functions: mtime(Config::agent_functions_file(&sources.functions)?),
+            rag: mtime(Config::agent_rag_file(&sources.rag)?),
+        })
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -170,12 +577,29 @@ pub struct AgentConfig {
     pub name: String,
     #[serde(rename(serialize = "model", deserialize = "model"))]
     pub model_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub dangerously_functions_filter: Option<FunctionsFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rag_top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rag_min_score: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rag_min_score_vector_search: Option<f32>,
+    /// The vector similarity the best retrieved chunk needs for the context to be used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rag_min_context_score: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instructions_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instructions_suffix: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub document_paths: Vec<String>,
 }
 
 impl AgentConfig {
@@ -185,6 +609,70 @@ impl AgentConfig {
             ..Default::default()
         }
     }
+
+    /// The paths or globs the agent's rag indexes: `document_paths` when set, otherwise
+    /// the embeddings directory of `rag_owner` if it exists.
+    fn rag_document_paths(&self, rag_owner: &str) -> Result<Vec<String>> {
+        if !self.document_paths.is_empty() {
+            return Ok(self.document_paths.clone());
+        }
+        let embeddings_dir = Config::agent_embeddings_dir(rag_owner)?;
+        match embeddings_dir.is_dir() {
+            true => Ok(vec![embeddings_dir.display().to_string()]),
+            false => Ok(vec![]),
+        }
+    }
+
+    /// Picks the first of `model` and then `models` that resolves to a configured chat model.
+    fn resolve_model(&self, config: &Config) -> Result<(Model, ModelSource)> {
+        let candidates: Vec<&String> = self.model_id.iter().chain(self.models.iter()).collect();
+        if candidates.is_empty() {
+            return Ok((config.current_model().clone(), ModelSource::GlobalDefault));
+        }
+        let mut errors = vec![];
+        for (index, model_id) in candidates.into_iter().enumerate() {
+            let model_id = match interpolate_env(model_id, |name| std::env::var(name).ok()) {
+                Ok(v) => v,
+                Err(err) => {
+                    errors.push(format!("'{model_id}': {err}"));
+                    continue;
+                }
+            };
+            match Model::retrieve_chat(config, &model_id) {
+                Ok(model) => {
+                    let source = match index {
+                        0 => ModelSource::AgentConfig,
+                        _ => ModelSource::Fallback,
+                    };
+                    return Ok((model, source));
+                }
+                Err(err) => errors.push(format!("'{model_id}': {err}")),
+            }
+        }
+        bail!(
+            "No usable model for agent '{}', {}",
+            self.name,
+            errors.join("; ")
+        )
+    }
+}
+
+/// A compact summary of what an agent offers, for listing and filtering agents.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AgentCapabilities {
+    pub name: String,
+    pub version: String,
+    pub model_id: String,
+    pub has_rag: bool,
+    pub function_count: usize,
+    pub starter_count: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct FunctionsDiff {
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    pub present: Vec<String>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -194,21 +682,234 @@ pub struct AgentDefinition {
     pub description: String,
     #[serde(default)]
     pub version: String,
+    /// A single string, or a list of blocks joined with blank lines.
+    #[serde(deserialize_with = "deserialize_instructions")]
     pub instructions: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
     #[serde(default)]
-    pub conversation_starters: Vec<String>,
+    pub conversation_starters: Vec<ConversationStarter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow_undeclared_tools: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_functions: Vec<String>,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// The agent whose functions and RAG are used when this agent has none of its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// Asks the model to reply with a JSON object (`json_object`), or with JSON
+    /// matching a schema (`json_schema: {...}`).
+    #[serde(
+        default,
+        with = "serde_yaml::with::singleton_map",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub response_format: Option<ResponseFormat>,
+    /// Fields the crate doesn't model, kept so that rewriting a definition preserves them.
+    #[serde(flatten)]
+    pub extra: IndexMap<String, serde_json::Value>,
 }
 
 impl AgentDefinition {
     pub fn load(path: &Path) -> Result<Self> {
         let contents = read_to_string(path)
             .with_context(|| format!("Failed to read agent index file at '{}'", path.display()))?;
-        let definition: Self = serde_yaml::from_str(&contents)
-            .with_context(|| format!("Failed to load agent at '{}'", path.display()))?;
+        let format = match path.extension().and_then(|v| v.to_str()) {
+            Some("toml") => "toml",
+            Some("json") => "json",
+            _ => "yaml",
+        };
+        let definition = Self::parse(&contents, format)
+            .with_context(|| format!("Failed to load agent at '{}' as {format}", path.display()))?;
+        definition
+            .validate()
+            .with_context(|| format!("Invalid agent definition at '{}'", path.display()))?;
         Ok(definition)
     }
 
-    fn banner(&self) -> String {
+    /// Parses an agent index in `format` (yaml, toml or json), pointing at the
+    /// offending line and adding a hint for common authoring mistakes.
+    fn parse(contents: &str, format: &str) -> Result<Self> {
+        let ret = match format {
+            "toml" => toml::from_str(contents).map_err(|err| {
+                let line = err
+                    .span()
+                    .map(|v| contents[..v.start].matches('\n').count() + 1);
+                (err.message().to_string(), line, true)
+            }),
+            "json" => serde_json::from_str(contents).map_err(|err| {
+                let message = err.to_string();
+                let message = match message.rsplit_once(" at line ") {
+                    Some((message, _)) => message.to_string(),
+                    None => message,
+                };
+                (message, Some(err.line()).filter(|v| *v > 0), true)
+            }),
+            _ => serde_yaml::from_str(contents).map_err(|err| {
+                let message = err.to_string();
+                let message = match message.rsplit_once(" at line ") {
+                    Some((message, _)) => message.to_string(),
+                    None => message,
+                };
+                // yaml already prefixes the message with the field path.
+                (message, err.location().map(|v| v.line()), false)
+            }),
+        };
+        ret.map_err(|(mut message, line, name_key)| {
+            if let Some(line) = line {
+                match definition_key_at_line(contents, line) {
+                    Some(key) if name_key => message = format!("{key}: {message}"),
+                    _ => {}
+                }
+                message.push_str(&format!(" at line {line}"));
+            }
+            if let Some(hint) = agent_definition_hint(&message) {
+                message.push_str(&format!("\nhint: {hint}"));
+            }
+            anyhow!("{message}")
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        if self.name.trim().is_empty() {
+            errors.push("missing name".to_string());
+        }
+        if self.instructions.trim().is_empty() {
+            errors.push("missing instructions".to_string());
+        }
+        if !self.version.is_empty() && !is_semver(&self.version) {
+            errors.push(format!(
+                "version '{}' is not a semantic version",
+                self.version
+            ));
+        }
+        if !errors.is_empty() {
+            bail!("{}", errors.join("; "));
+        }
+        Ok(())
+    }
+
+    pub fn validate_tools(&self, functions: &Functions) -> Result<()> {
+        let tools = match &self.tools {
+            Some(tools) => tools,
+            None => return Ok(()),
+        };
+        let mut errors = vec![];
+        for tool in tools {
+            if !functions.contains(tool) {
+                errors.push(format!("tool '{tool}' is declared but not found"));
+            }
+        }
+        if !self.allow_undeclared_tools {
+            for name in functions.names() {
+                if !tools.contains(name) {
+                    errors.push(format!("function '{name}' is not declared in tools"));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            bail!("{}", errors.join("; "));
+        }
+        Ok(())
+    }
+
+    pub fn validate_required_functions(
+        &self,
+        functions: &Functions,
+        functions_path: &Path,
+    ) -> Result<()> {
+        let missing: Vec<&str> = self
+            .required_functions
+            .iter()
+            .filter(|v| !functions.contains(v))
+            .map(|v| v.as_str())
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Agent '{}' requires functions that are not installed: {}. Install them into '{}', see https://github.com/sigoden/llm-functions",
+                self.name,
+                missing.join(", "),
+                functions_path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Compares the functions the agent references, through `required_functions`,
+    /// `tools` or by name in its instructions, with the installed ones.
+    pub fn functions_diff(&self, functions: &Functions) -> FunctionsDiff {
+        let mut referenced: IndexSet<&str> = self
+            .required_functions
+            .iter()
+            .chain(self.tools.iter().flatten())
+            .map(|v| v.as_str())
+            .collect();
+        referenced.extend(
+            functions
+                .names()
+                .iter()
+                .filter(|v| mentions_word(&self.instructions, v))
+                .map(|v| v.as_str()),
+        );
+        let mut diff = FunctionsDiff::default();
+        for name in referenced {
+            match functions.contains(name) {
+                true => diff.present.push(name.to_string()),
+                false => diff.missing.push(name.to_string()),
+            }
+        }
+        diff.extra = functions
+            .names()
+            .iter()
+            .filter(|v| !diff.present.contains(v))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    pub fn render_instructions(&self) -> String {
+        let mut variables: HashMap<String, String> = [
+            (
+                "current_date",
+                chrono::Local::now().format("%Y-%m-%d").to_string(),
+            ),
+            ("os", env::consts::OS.to_string()),
+            ("arch", env::consts::ARCH.to_string()),
+            (
+                "cwd",
+                env::current_dir()
+                    .map(|v| v.display().to_string())
+                    .unwrap_or_default(),
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        variables.extend(self.variables.clone());
+        let (output, unknown) = interpolate_variables(&self.instructions, &variables);
+        for name in unknown {
+            warn!(
+                "Agent '{}' has unknown variable '{{{{{name}}}}}'",
+                self.name
+            );
+        }
+        output
+    }
+
+    pub fn starter_texts(&self) -> Vec<String> {
+        self.conversation_starters
+            .iter()
+            .map(|v| v.text.clone())
+            .collect()
+    }
+
+    pub fn banner(&self) -> String {
         let AgentDefinition {
             name,
             description,
@@ -221,7 +922,7 @@ impl AgentDefinition {
         } else {
             let starters = conversation_starters
                 .iter()
-                .map(|v| format!("- {v}"))
+                .map(|v| format!("- {}", v.label()))
                 .collect::<Vec<_>>()
                 .join("\n");
             format!(
@@ -238,23 +939,1019 @@ impl AgentDefinition {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(from = "ConversationStarterValue", into = "ConversationStarterValue")]
+pub struct ConversationStarter {
+    /// The prompt sent when the starter is used.
+    pub text: String,
+    /// A short caption shown instead of the prompt.
+    pub label: Option<String>,
+    pub category: Option<String>,
+    /// The function the starter is expected to trigger.
+    pub tool: Option<String>,
+    /// A display hint for front-ends, such as an icon name.
+    pub icon: Option<String>,
+}
+
+impl ConversationStarter {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            label: None,
+            category: None,
+            tool: None,
+            icon: None,
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.text)
+    }
+
+    /// The `{name}` slots in the prompt, in first-seen order. `{{...}}` is left alone.
+    pub fn arguments(&self) -> Vec<String> {
+        let mut arguments: IndexSet<String> = IndexSet::new();
+        for (_, name) in starter_slots(&self.text) {
+            arguments.insert(name.to_string());
+        }
+        arguments.into_iter().collect()
+    }
+
+    /// The prompt with every slot replaced by its value from `lookup`.
+    pub fn render(&self, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+        let mut output = String::with_capacity(self.text.len());
+        let mut last = 0;
+        for (start, name) in starter_slots(&self.text) {
+            let Some(value) = lookup(name) else {
+                bail!("Missing a value for '{{{name}}}'");
+            };
+            output.push_str(&self.text[last..start]);
+            output.push_str(&value);
+            last = start + name.len() + 2;
+        }
+        output.push_str(&self.text[last..]);
+        Ok(output)
+    }
+}
+
+/// Finds the `{name}` slots of a starter with their byte offsets, skipping `{{...}}`.
+fn starter_slots(text: &str) -> Vec<(usize, &str)> {
+    let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-');
+    let mut slots = vec![];
+    let mut rest = 0;
+    while let Some(offset) = text[rest..].find('{') {
+        let start = rest + offset;
+        if text[start + 1..].starts_with('{') {
+            rest = match text[start..].find("}}") {
+                Some(end) => start + end + 2,
+                None => text.len(),
+            };
+            continue;
+        }
+        rest = start + 1;
+        if let Some(end) = text[start + 1..].find('}') {
+            let name = &text[start + 1..start + 1 + end];
+            if !name.is_empty() && name.chars().all(is_name_char) {
+                slots.push((start, name));
+                rest = start + end + 2;
+            }
+        }
+    }
+    slots
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InstructionsValue {
+    Text(String),
+    Blocks(Vec<String>),
+}
+
+fn deserialize_instructions<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match InstructionsValue::deserialize(deserializer)? {
+        InstructionsValue::Text(text) => text,
+        InstructionsValue::Blocks(blocks) => blocks
+            .iter()
+            .map(|v| v.trim_end())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+    })
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum ConversationStarterValue {
+    Text(String),
+    Descriptor {
+        #[serde(alias = "prompt")]
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        category: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tool: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        icon: Option<String>,
+    },
+}
+
+impl From<ConversationStarterValue> for ConversationStarter {
+    fn from(value: ConversationStarterValue) -> Self {
+        match value {
+            ConversationStarterValue::Text(text) => Self::new(&text),
+            ConversationStarterValue::Descriptor {
+                text,
+                label,
+                category,
+                tool,
+                icon,
+            } => Self {
+                text,
+                label,
+                category,
+                tool,
+                icon,
+            },
+        }
+    }
+}
+
+impl From<ConversationStarter> for ConversationStarterValue {
+    fn from(value: ConversationStarter) -> Self {
+        let ConversationStarter {
+            text,
+            label,
+            category,
+            tool,
+            icon,
+        } = value;
+        if label.is_none() && category.is_none() && tool.is_none() && icon.is_none() {
+            Self::Text(text)
+        } else {
+            Self::Descriptor {
+                text,
+                label,
+                category,
+                tool,
+                icon,
+            }
+        }
+    }
+}
+
+/// Follows `extends` from `name`, returning the agent names from `name` up to the root.
+fn agent_lineage<F>(name: &str, definition: &AgentDefinition, load: F) -> Result<Vec<String>>
+where
+    F: Fn(&str) -> Result<AgentDefinition>,
+{
+    let mut lineage = vec![name.to_string()];
+    let mut parent = definition.extends.clone();
+    while let Some(name) = parent {
+        let is_cycle = lineage.contains(&name);
+        lineage.push(name.clone());
+        if is_cycle {
+            bail!("Agent inheritance cycle: {}", lineage.join(" -> "));
+        }
+        parent = load(&name)
+            .with_context(|| format!("Failed to load parent agent '{name}'"))?
+            .extends;
+    }
+    Ok(lineage)
+}
+
+/// Renders an agent's banner from its definition alone, without loading its functions or RAG.
+pub fn agent_banner(name: &str) -> Result<String> {
+    let definition = AgentDefinition::load(&Config::agent_definition_file(name)?)?;
+    Ok(definition.banner())
+}
+
 pub fn list_agents() -> Vec<String> {
     list_agents_impl().unwrap_or_default()
 }
 
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AgentSummary {
+    pub name: String,
+    pub description: String,
+    pub version: String,
+}
+
+/// Lists agents with their description and version, skipping agents whose definition can't be read.
+pub fn list_agents_detailed(config: &Config) -> Vec<AgentSummary> {
+    list_agents()
+        .into_iter()
+        .filter_map(|name| {
+            let ret = Config::agent_definition_file(&name)
+                .and_then(|path| config.agent_definitions.load(&path));
+            match ret {
+                Ok(definition) => Some(AgentSummary {
+                    name,
+                    description: definition.description,
+                    version: definition.version,
+                }),
+                Err(err) => {
+                    warn!("Skip agent '{name}', {err}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Agent definitions keyed by path, reused until the file's mtime changes.
+#[derive(Debug, Clone, Default)]
+pub struct AgentDefinitionCache {
+    entries: Arc<Mutex<HashMap<PathBuf, (SystemTime, AgentDefinition)>>>,
+}
+
+impl AgentDefinitionCache {
+    pub fn load(&self, path: &Path) -> Result<AgentDefinition> {
+        let mtime = path.metadata().and_then(|v| v.modified()).ok();
+        if let Some(mtime) = mtime {
+            if let Some((cached_mtime, definition)) = self.entries.lock().get(path) {
+                if *cached_mtime == mtime {
+                    return Ok(definition.clone());
+                }
+            }
+        }
+        let definition = AgentDefinition::load(path)?;
+        if let Some(mtime) = mtime {
+            self.entries
+                .lock()
+                .insert(path.to_path_buf(), (mtime, definition.clone()));
+        }
+        Ok(definition)
+    }
+}
+
+/// The key defined on `line` of a yaml (`key:`), toml (`key =`) or json (`"key":`) document.
+fn definition_key_at_line(contents: &str, line: usize) -> Option<String> {
+    let text = contents.lines().nth(line.checked_sub(1)?)?.trim();
+    let text = text.strip_prefix('-').unwrap_or(text).trim_start();
+    let key = match text.strip_prefix('"') {
+        Some(rest) => rest.split_once('"')?.0,
+        None => text.split([':', '=']).next()?.trim(),
+    };
+    let is_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    is_key.then(|| key.to_string())
+}
+
+fn agent_definition_hint(message: &str) -> Option<String> {
+    let field = message
+        .split_once(": ")
+        .map(|(v, _)| v)
+        .filter(|v| {
+            v.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '[' | ']'))
+        })
+        .map(|v| v.split(['.', '[']).next().unwrap_or(v));
+    let hint = if message.contains("expected a sequence") {
+        match field {
+            Some("conversation_starters") => "`conversation_starters` must be a list, e.g. `conversation_starters: [\"What can you do?\"]` or one `- ` item per line".to_string(),
+            Some(field) => format!("`{field}` must be a list, write one `- ` item per line"),
+            None => return None,
+        }
+    } else if message.contains("expected a map") && field == Some("variables") {
+        "`variables` must be a mapping of `name: value` pairs".to_string()
+    } else if message.contains("expected f64") {
+        format!("`{}` must be a number", field.unwrap_or("the value"))
+    } else if message.contains("expected a boolean") {
+        format!(
+            "`{}` must be `true` or `false`",
+            field.unwrap_or("the value")
+        )
+    } else if message.contains("missing field") {
+        "an agent needs at least `name` and `instructions`".to_string()
+    } else if message.contains("mapping values are not allowed")
+        || message.contains("did not find expected key")
+    {
+        "check the indentation, and quote values that contain `: `".to_string()
+    } else {
+        return None;
+    };
+    Some(hint)
+}
+
+/// Lists the agents named in `agents.txt`, followed by any other folder of the
+/// agents directory that holds a valid definition file.
 fn list_agents_impl() -> Result<Vec<String>> {
     let base_dir = Config::functions_dir()?;
-    let contents = read_to_string(base_dir.join("agents.txt"))?;
-    let agents = contents
-        .split('\n')
-        .filter_map(|line| {
-            let line = line.trim();
-            if line.is_empty() {
-                None
-            } else {
-                Some(line.to_string())
+    let manifest_path = base_dir.join("agents.txt");
+    let mut agents: IndexSet<String> = IndexSet::new();
+    if let Ok(contents) = read_to_string(&manifest_path) {
+        let (names, duplicates) = parse_agents_txt(&contents);
+        if !duplicates.is_empty() {
+            warn!(
+                "Duplicate agents in '{}': {}",
+                manifest_path.display(),
+                duplicates.join(", ")
+            );
+        }
+        agents.extend(names);
+    }
+    agents.extend(scan_agents_dir(&Config::agents_functions_dir()?));
+    Ok(agents.into_iter().collect())
+}
+
+/// Returns the sorted names of the subfolders of `dir` whose definition file parses.
+fn scan_agents_dir(dir: &Path) -> Vec<String> {
+    let Ok(entries) = read_dir(dir) else {
+        return vec![];
+    };
+    let mut agents: Vec<String> = entries
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            let path = find_agent_definition_file(&entry.path())?;
+            match AgentDefinition::load(&path) {
+                Ok(_) => Some(name),
+                Err(err) => {
+                    debug!("Skip agent folder '{}', {err}", entry.path().display());
+                    None
+                }
             }
         })
         .collect();
-    Ok(agents)
+    agents.sort();
+    agents
+}
+
+/// Returns the agent names in first-seen order, skipping blank and `#` comment
+/// lines, along with the names listed more than once.
+fn parse_agents_txt(contents: &str) -> (Vec<String>, Vec<String>) {
+    let mut agents: IndexSet<String> = IndexSet::new();
+    let mut duplicates: IndexSet<String> = IndexSet::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !agents.insert(line.to_string()) {
+            duplicates.insert(line.to_string());
+        }
+    }
+    (
+        agents.into_iter().collect(),
+        duplicates.into_iter().collect(),
+    )
+}
+
+fn is_semver(version: &str) -> bool {
+    let version = version.split_once('+').map(|(v, _)| v).unwrap_or(version);
+    let (core, pre) = match version.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (version, None),
+    };
+    let parts: Vec<&str> = core.split('.').collect();
+    let valid_number = |v: &&str| {
+        !v.is_empty()
+            && v.chars().all(|c| c.is_ascii_digit())
+            && (v.len() == 1 || !v.starts_with('0'))
+    };
+    parts.len() == 3
+        && parts.iter().all(valid_number)
+        && match pre {
+            Some(pre) => pre
+                .split('.')
+                .all(|v| !v.is_empty() && v.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')),
+            None => true,
+        }
+}
+
+/// Fails when a rag embedded with `model` and `dimensions` can't be searched alongside
+/// what `expected_by` expects. Unknown dimensions are not compared.
+fn check_rag_compatible(
+    (expect_model, expect_dimensions): (&str, Option<usize>),
+    (model, dimensions): (&str, Option<usize>),
+    expected_by: &str,
+) -> Result<()> {
+    if expect_model != model {
+        bail!("it is embedded with '{model}', but {expected_by} expects '{expect_model}'");
+    }
+    if let (Some(expect), Some(actual)) = (expect_dimensions, dimensions) {
+        if expect != actual {
+            bail!("it has {actual} dimensions, but {expected_by} expects {expect}");
+        }
+    }
+    Ok(())
+}
+
+/// Fails when a prebuilt rag was embedded with a model other than the configured
+/// `rag_embedding_model`, since searching it would compare vectors across embedding spaces.
+fn check_prebuilt_rag(config: &GlobalConfig, rag: &Rag, rag_path: &Path) -> Result<()> {
+    let configured = config.read().rag_embedding_model.clone().map(|id| {
+        let dimensions = Model::retrieve_embedding(&config.read(), &id)
+            .ok()
+            .and_then(|v| v.data().output_vector_size);
+        (id, dimensions)
+    });
+    let Some((model_id, dimensions)) = configured else {
+        return Ok(());
+    };
+    check_rag_compatible(
+        (&model_id, dimensions),
+        (&rag.embedding_model().id(), rag.dimensions()),
+        "`rag_embedding_model`",
+    )
+    .with_context(|| {
+        format!(
+            "The agent's rag at '{}' does not match the embedding model; delete it to re-index with '{model_id}', or set `rag_embedding_model` back to the model it was built with",
+            rag_path.display()
+        )
+    })
+}
+
+fn mentions_word(text: &str, word: &str) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(word).any(|(index, _)| {
+        let before = text[..index].chars().next_back();
+        let after = text[index + word.len()..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+/// Replaces `${NAME}` tokens with values from `lookup`, failing on unset variables.
+fn interpolate_env(text: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find('}') {
+            Some(end) => end,
+            None => bail!("Unclosed '${{' in '{text}'"),
+        };
+        let name = after[..end].trim();
+        match lookup(name) {
+            Some(value) => output.push_str(&value),
+            None => bail!("Environment variable '{name}' referenced in '{text}' is not set"),
+        }
+        rest = &after[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Replaces `{{name}}` placeholders, keeping `\{{` escapes as literal `{{`.
+/// Returns the rendered text and the names that had no value.
+fn interpolate_variables(text: &str, variables: &HashMap<String, String>) -> (String, Vec<String>) {
+    let mut output = String::with_capacity(text.len());
+    let mut unknown = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("{{");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find("}}") {
+            Some(end) => end,
+            None => {
+                output.push_str(&rest[start..]);
+                return (output, unknown);
+            }
+        };
+        let name = after[..end].trim();
+        let placeholder = &rest[start..start + 2 + end + 2];
+        match variables.get(name) {
+            Some(value) => output.push_str(value),
+            None => {
+                if !name.is_empty() && !unknown.iter().any(|v| v == name) {
+                    unknown.push(name.to_string());
+                }
+                output.push_str(placeholder);
+            }
+        }
+        rest = &after[end + 2..];
+    }
+    output.push_str(rest);
+    (output, unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads `Functions` from a temporary functions.json declaring `names`.
+    fn write_functions_json(names: &[&str]) -> Functions {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let index = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "aichat-functions-{}-{index}.json",
+            std::process::id()
+        ));
+        let declarations: Vec<_> = names
+            .iter()
+            .map(|name| serde_json::json!({"name": name, "description": "", "parameters": {"type": "object"}}))
+            .collect();
+        std::fs::write(&path, serde_json::json!(declarations).to_string()).unwrap();
+        let functions = Functions::init(&path);
+        std::fs::remove_file(&path).unwrap();
+        functions.unwrap()
+    }
+
+    #[test]
+    fn test_validate_definition() {
+        let definition: AgentDefinition =
+            serde_yaml::from_str("name: ''\ninstructions: ' '\nversion: '1.0'").unwrap();
+        assert_eq!(
+            definition.validate().unwrap_err().to_string(),
+            "missing name; missing instructions; version '1.0' is not a semantic version"
+        );
+        assert!(is_semver("0.1.0"));
+        assert!(is_semver("1.2.3-rc.1+build.5"));
+        assert!(!is_semver("01.2.3"));
+    }
+
+    #[test]
+    fn test_validate_required_functions() {
+        let definition: AgentDefinition = serde_yaml::from_str(
+            "name: demo\ninstructions: hi\nrequired_functions: [web_search, fs_cat]",
+        )
+        .unwrap();
+        let err = definition
+            .validate_required_functions(&Functions::default(), Path::new("functions.json"))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Agent 'demo' requires functions that are not installed: web_search, fs_cat. Install them into 'functions.json'"));
+    }
+
+    #[test]
+    fn test_functions_diff() {
+        let functions = write_functions_json(&["web_search", "fs_cat", "fs_ls", "execute_command"]);
+        let definition: AgentDefinition = serde_yaml::from_str(
+            "name: demo\ninstructions: Use fs_cat to read files, not fs_catalog.\nrequired_functions: [web_search, fetch_url]",
+        )
+        .unwrap();
+        assert_eq!(
+            definition.functions_diff(&functions),
+            FunctionsDiff {
+                missing: vec!["fetch_url".into()],
+                extra: vec!["fs_ls".into(), "execute_command".into()],
+                present: vec!["web_search".into(), "fs_cat".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve_model_source() {
+        let config = Config::default();
+        let (_, source) = AgentConfig::new("demo").resolve_model(&config).unwrap();
+        assert_eq!(source, ModelSource::GlobalDefault);
+        let agent_config = AgentConfig {
+            model_id: Some("missing:model".into()),
+            ..AgentConfig::new("demo")
+        };
+        assert!(agent_config.resolve_model(&config).is_err());
+        let agent_config = AgentConfig {
+            model_id: Some("${AICHAT_TEST_UNSET_MODEL}".into()),
+            models: vec!["missing:model".into()],
+            ..AgentConfig::new("demo")
+        };
+        let err = agent_config.resolve_model(&config).unwrap_err().to_string();
+        assert!(err.contains("'AICHAT_TEST_UNSET_MODEL'"));
+        assert!(err.contains("'missing:model'"));
+    }
+
+    #[test]
+    fn test_check_rag_compatible() {
+        let model = "openai:text-embedding-3-small";
+        let expected_by = "the agent's rag";
+        assert!(
+            check_rag_compatible((model, Some(1536)), (model, Some(1536)), expected_by).is_ok()
+        );
+        assert!(check_rag_compatible((model, None), (model, Some(1536)), expected_by).is_ok());
+        assert!(check_rag_compatible((model, Some(1536)), (model, None), expected_by).is_ok());
+        let err =
+            check_rag_compatible((model, Some(1536)), (model, Some(512)), expected_by).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "it has 512 dimensions, but the agent's rag expects 1536"
+        );
+        let err = check_rag_compatible(
+            (model, None),
+            ("ollama:nomic", Some(768)),
+            "`rag_embedding_model`",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "it is embedded with 'ollama:nomic', but `rag_embedding_model` expects 'openai:text-embedding-3-small'"
+        );
+    }
+
+    #[test]
+    fn test_effective_role() {
+        let definition: AgentDefinition =
+            serde_yaml::from_str("name: demo\ninstructions: Help on {{os}}.\ntemperature: 0.2")
+                .unwrap();
+        let agent =
+            Agent::from_parts("demo", definition, Model::default()).with_config(AgentConfig {
+                instructions_prefix: Some("Prefix.".into()),
+                instructions_suffix: Some("Suffix.".into()),
+                top_p: Some(0.5),
+                ..AgentConfig::new("demo")
+            });
+        let role = agent.effective_role();
+        assert_eq!(
+            role.prompt(),
+            format!("Prefix.\n\nHelp on {}.\n\nSuffix.", env::consts::OS)
+        );
+        let role = serde_json::json!(role);
+        assert_eq!(role["temperature"], 0.2);
+        assert_eq!(role["top_p"], 0.5);
+        assert_eq!(agent.to_role().prompt(), agent.effective_role().prompt());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let agent = Agent::from_parts("demo", AgentDefinition::default(), Model::default())
+            .with_functions(write_functions_json(&["web_search", "fs_cat"]));
+        let capabilities = agent.capabilities();
+        assert_eq!(capabilities.name, "demo");
+        assert!(!capabilities.has_rag);
+        assert_eq!(capabilities.function_count, 2);
+        let info: serde_yaml::Value = serde_yaml::from_str(&agent.export(false).unwrap()).unwrap();
+        assert_eq!(info["capabilities"]["function_count"], 2);
+    }
+
+    #[test]
+    fn test_instructions_blocks() {
+        let definition: AgentDefinition =
+            serde_yaml::from_str("name: demo\ninstructions: |\n  one\n  two\n").unwrap();
+        assert_eq!(definition.instructions, "one\ntwo\n");
+        let definition: AgentDefinition = serde_yaml::from_str(
+            "name: demo\ninstructions:\n  - |\n    You are a helper.\n    Be brief.\n  - ''\n  - Use tools.\n",
+        )
+        .unwrap();
+        assert_eq!(
+            definition.instructions,
+            "You are a helper.\nBe brief.\n\nUse tools."
+        );
+        assert!(serde_yaml::from_str::<AgentDefinition>("name: demo\ninstructions: 1").is_err());
+    }
+
+    #[test]
+    fn test_scan_agents_dir() {
+        let dir = std::env::temp_dir().join(format!("aichat-scan-agents-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (name, contents) in [
+            ("beta", Some("name: beta\ninstructions: Help.\n")),
+            ("alpha", Some("name: alpha\ninstructions: Help.\n")),
+            ("broken", Some("name: broken\n")),
+            ("empty", None),
+        ] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            if let Some(contents) = contents {
+                std::fs::write(dir.join(name).join(AGENT_DEFINITION_FILE_NAME), contents).unwrap();
+            }
+        }
+        std::fs::create_dir_all(dir.join("gamma")).unwrap();
+        std::fs::write(
+            dir.join("gamma").join("index.toml"),
+            "name = \"gamma\"\ninstructions = [\"Help.\", \"Be brief.\"]\nconversation_starters = [\"Hi\"]\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+        assert_eq!(scan_agents_dir(&dir), vec!["alpha", "beta", "gamma"]);
+        let definition =
+            AgentDefinition::load(&find_agent_definition_file(&dir.join("gamma")).unwrap())
+                .unwrap();
+        assert_eq!(definition.instructions, "Help.\n\nBe brief.");
+        assert!(scan_agents_dir(&dir.join("missing")).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_error_hint() {
+        let err = AgentDefinition::parse(
+            "name: demo\ninstructions: Help.\nconversation_starters: What can you do?\n",
+            "yaml",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("line 3"), "{err}");
+        assert!(
+            err.contains("hint: `conversation_starters` must be a list"),
+            "{err}"
+        );
+
+        let err = AgentDefinition::parse(
+            "name: demo\ninstructions: Help.\ntemperature: hot\n",
+            "yaml",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("hint: `temperature` must be a number"),
+            "{err}"
+        );
+
+        let err = AgentDefinition::parse("name: demo\n", "yaml")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("hint: an agent needs"), "{err}");
+
+        let err = AgentDefinition::parse(
+            "name = \"demo\"\ninstructions = \"Help.\"\nconversation_starters = \"Hi\"\n",
+            "toml",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("conversation_starters: "), "{err}");
+        assert!(err.contains("at line 3"), "{err}");
+        assert!(
+            err.contains("hint: `conversation_starters` must be a list"),
+            "{err}"
+        );
+
+        let err = AgentDefinition::parse(
+            "{\n  \"name\": \"demo\",\n  \"instructions\": \"Help.\",\n  \"temperature\": \"hot\"\n}",
+            "json",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("at line 4"), "{err}");
+        assert!(
+            err.contains("hint: `temperature` must be a number"),
+            "{err}"
+        );
+        assert!(AgentDefinition::parse("name: demo\ninstructions: Help.\n", "yaml").is_ok());
+    }
+
+    #[test]
+    fn test_parse_agents_txt() {
+        let contents = "# agents\ncoder\n\n  todo  \ncoder\n# coder\ntodo\ncoder\n";
+        assert_eq!(
+            parse_agents_txt(contents),
+            (
+                vec!["coder".to_string(), "todo".to_string()],
+                vec!["coder".to_string(), "todo".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn test_rag_document_paths() {
+        let agent_config = AgentConfig {
+            document_paths: vec!["/docs/**/*.md".into()],
+            ..AgentConfig::new("demo")
+        };
+        assert_eq!(
+            agent_config.rag_document_paths("demo").unwrap(),
+            vec!["/docs/**/*.md".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let lookup = |name: &str| match name {
+            "AGENT_MODEL" => Some("gpt-4o".to_string()),
+            "AGENT_CLIENT" => Some("openai".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            interpolate_env("openai:gpt-4o", lookup).unwrap(),
+            "openai:gpt-4o"
+        );
+        assert_eq!(
+            interpolate_env("${AGENT_CLIENT}:${AGENT_MODEL}", lookup).unwrap(),
+            "openai:gpt-4o"
+        );
+        let err = interpolate_env("${MISSING}", lookup).unwrap_err();
+        assert!(err.to_string().contains("'MISSING'"));
+        assert!(interpolate_env("${AGENT_MODEL", lookup).is_err());
+    }
+
+    #[test]
+    fn test_functions_denylist() {
+        let functions = write_functions_json(&["web_search", "fs_rm", "execute_command"]);
+        let agent = Agent::from_parts("demo", AgentDefinition::default(), Model::default())
+            .with_functions(functions)
+            .with_functions_denylist(Some("fs_rm|execute_.*".into()));
+        let filter = agent.functions_filter().unwrap();
+        let names: Vec<String> = agent
+            .functions()
+            .select(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(names, vec!["web_search"]);
+    }
+
+    #[test]
+    fn test_dangerously_functions_filter() {
+        let functions = write_functions_json(&["web_search", "fs_cat", "fs_rm"]);
+        let mut agent = Agent::from_parts("demo", AgentDefinition::default(), Model::default())
+            .with_functions(functions);
+        assert_eq!(
+            agent.functions_filter().as_deref(),
+            Some(SELECTED_ALL_FUNCTIONS)
+        );
+        agent.set_functions_filter(Some("web_search|fs_cat".into()));
+        assert_eq!(
+            agent.config().dangerously_functions_filter.as_deref(),
+            Some("web_search|fs_cat")
+        );
+        assert_eq!(
+            agent.functions_filter().as_deref(),
+            Some("web_search|fs_cat")
+        );
+        let agent = agent.with_config(AgentConfig::new("demo"));
+        assert_eq!(
+            agent.functions_filter().as_deref(),
+            Some(SELECTED_ALL_FUNCTIONS)
+        );
+    }
+
+    #[test]
+    fn test_functions_filter_patterns() {
+        let functions =
+            write_functions_json(&["web_search", "web_fetch", "fs_read", "fs_list", "fs_rm"]);
+        let (filter, unmatched) = functions.expand_filter("web_*|fs_(read|list)|git_*");
+        assert_eq!(
+            filter.as_deref(),
+            Some("web_search|web_fetch|fs_read|fs_list")
+        );
+        assert_eq!(unmatched, vec!["git_*"]);
+        assert_eq!(functions.expand_filter("git_?").0, None);
+
+        let mut agent = Agent::from_parts("demo", AgentDefinition::default(), Model::default())
+            .with_functions(functions)
+            .with_functions_denylist(Some("web_f*".into()));
+        agent.set_functions_filter(Some("web_*|fs_r?".into()));
+        let names: Vec<String> = agent
+            .functions()
+            .select(&agent.functions_filter().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(names, vec!["web_search", "fs_rm"]);
+    }
+
+    #[test]
+    fn test_definition_extra_fields() {
+        let yaml = "name: demo\ninstructions: hi\nx-owner: docs-team\nmetadata:\n  tags: [a, b]\n";
+        let mut definition = AgentDefinition::parse(yaml, "yaml").unwrap();
+        assert_eq!(definition.extra["x-owner"], "docs-team");
+        definition.version = "1.1.0".into();
+        for (format, output) in [
+            ("yaml", serde_yaml::to_string(&definition).unwrap()),
+            ("toml", toml::to_string(&definition).unwrap()),
+            ("json", serde_json::to_string(&definition).unwrap()),
+        ] {
+            let reloaded = AgentDefinition::parse(&output, format).unwrap();
+            assert_eq!(reloaded.version, "1.1.0");
+            assert_eq!(reloaded.extra, definition.extra);
+        }
+        let agent = Agent::from_parts("demo", definition, Model::default());
+        assert!(agent.export(false).unwrap().contains("x-owner: docs-team"));
+    }
+
+    #[test]
+    fn test_response_format() {
+        let yaml = "name: demo\ninstructions: hi\nresponse_format: json_object";
+        let definition = AgentDefinition::parse(yaml, "yaml").unwrap();
+        assert_eq!(definition.response_format, Some(ResponseFormat::JsonObject));
+        let yaml = "name: demo\ninstructions: hi\nresponse_format:\n  json_schema:\n    type: object\n    required: [answer]";
+        let definition = AgentDefinition::parse(yaml, "yaml").unwrap();
+        let format = definition.response_format.clone().unwrap();
+        assert_eq!(
+            format.to_openai(),
+            serde_json::json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "response",
+                    "schema": { "type": "object", "required": ["answer"] },
+                },
+            })
+        );
+        let toml = "name = \"demo\"\ninstructions = \"hi\"\nresponse_format = \"json_object\"";
+        let parsed = AgentDefinition::parse(toml, "toml").unwrap();
+        assert_eq!(parsed.response_format, Some(ResponseFormat::JsonObject));
+
+        let agent = Agent::from_parts("demo", definition, Model::default());
+        assert_eq!(agent.to_role().response_format(), Some(&format));
+    }
+
+    #[test]
+    fn test_starter_arguments() {
+        let starter =
+            ConversationStarter::new("Explain {topic} to a {level} reader, {topic} first");
+        assert_eq!(starter.arguments(), vec!["topic", "level"]);
+        let rendered = starter.render(|name| match name {
+            "topic" => Some("borrowing".into()),
+            "level" => Some("new".into()),
+            _ => None,
+        });
+        assert_eq!(
+            rendered.unwrap(),
+            "Explain borrowing to a new reader, borrowing first"
+        );
+        assert!(starter.render(|_| None).is_err());
+
+        let fixed = ConversationStarter::new("Run {{os}} checks on {} and { spaced }");
+        assert!(fixed.arguments().is_empty());
+        assert_eq!(fixed.render(|_| None).unwrap(), fixed.text);
+
+        let definition: AgentDefinition = serde_yaml::from_str(
+            "name: demo\ninstructions: hi\nconversation_starters:\n- Hello\n- Explain {topic}",
+        )
+        .unwrap();
+        let agent = Agent::from_parts("demo", definition, Model::default());
+        assert!(agent.starter_arguments(0).is_empty());
+        assert_eq!(agent.starter_arguments(1), vec!["topic"]);
+        assert!(agent.starter_arguments(5).is_empty());
+    }
+
+    #[test]
+    fn test_conversation_starters() {
+        let definition: AgentDefinition = serde_yaml::from_str(
+            "name: demo\ninstructions: hi\nconversation_starters:\n- Hello\n- label: Summarize my week\n  prompt: Summarize what I worked on this week, grouped by project.",
+        )
+        .unwrap();
+        let starters = &definition.conversation_starters;
+        assert_eq!(starters[0].label(), "Hello");
+        assert_eq!(starters[1].label(), "Summarize my week");
+        assert_eq!(
+            starters[1].text,
+            "Summarize what I worked on this week, grouped by project."
+        );
+        assert!(definition
+            .banner()
+            .ends_with("- Hello\n- Summarize my week"));
+        assert_eq!(
+            serde_json::to_value(starters).unwrap(),
+            serde_json::json!(["Hello", {"text": starters[1].text, "label": "Summarize my week"}])
+        );
+
+        let agent = Agent::from_parts("demo", definition, Model::default());
+        assert_eq!(
+            agent.conversation_starters(),
+            [
+                "Hello",
+                "Summarize what I worked on this week, grouped by project."
+            ]
+        );
+        #[allow(deprecated)]
+        let staters = agent.conversation_staters();
+        assert_eq!(staters, agent.conversation_starters());
+        assert_eq!(
+            agent.conversation_starter_descriptors()[1].label(),
+            "Summarize my week"
+        );
+    }
+
+    #[test]
+    fn test_agent_lineage() {
+        let definition = |extends: Option<&str>| AgentDefinition {
+            extends: extends.map(|v| v.to_string()),
+            ..Default::default()
+        };
+        let parents: HashMap<&str, Option<&str>> =
+            [("base", None), ("a", Some("b")), ("b", Some("a"))].into();
+        let load = |name: &str| match parents.get(name) {
+            Some(extends) => Ok(definition(*extends)),
+            None => bail!("not found"),
+        };
+        assert_eq!(
+            agent_lineage("child", &definition(Some("base")), load).unwrap(),
+            vec!["child", "base"]
+        );
+        assert_eq!(
+            agent_lineage("a", &definition(Some("b")), load)
+                .unwrap_err()
+                .to_string(),
+            "Agent inheritance cycle: a -> b -> a"
+        );
+        assert!(agent_lineage("child", &definition(Some("missing")), load).is_err());
+    }
+
+    #[test]
+    fn test_interpolate_variables() {
+        let variables: HashMap<String, String> = [("os", "linux"), ("team", "infra")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let text = "Run on {{os}} for {{ team }}.\n```\n{{os}} \\{{os}}\n```\n{{missing}} {{os";
+        assert_eq!(
+            interpolate_variables(text, &variables),
+            (
+                "Run on linux for infra.\n```\nlinux {{os}}\n```\n{{missing}} {{os".into(),
+                vec!["missing".into()]
+            )
+        );
+    }
 }
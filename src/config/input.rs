@@ -5,6 +5,7 @@ use crate::client::{
     MessageContentPart, MessageRole, Model,
 };
 use crate::function::{ToolResult, ToolResults};
+use crate::rag::best_chunk_score;
 use crate::utils::{base64_encode, sha256, AbortSignal};
 
 use anyhow::{bail, Context, Result};
@@ -16,6 +17,7 @@ use std::{
     fs::File,
     io::Read,
     path::{Path, PathBuf},
+    time::Duration,
 };
 use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
@@ -169,36 +171,76 @@ impl Input {
         if !self.text.is_empty() {
             let rag = self.config.read().rag.clone();
             if let Some(rag) = rag {
-                let (top_k, min_score_vector_search, min_score_keyword_search) = {
+                let (top_k, min_score_vector_search, min_score_keyword_search, min_context_score) = {
                     let config = self.config.read();
+                    let agent_config = config.agent.as_ref().map(|v| v.config());
+                    // Both per-agent scores are cosine similarities, BM25 scores are on
+                    // another scale, so keyword search keeps the global threshold.
+                    let min_score_vector_search = agent_config
+                        .and_then(|v| v.rag_min_score_vector_search.or(v.rag_min_score))
+                        .unwrap_or(config.rag_min_score_vector_search);
                     (
-                        config.rag_top_k,
-                        config.rag_min_score_vector_search,
+                        agent_config
+                            .and_then(|v| v.rag_top_k)
+                            .unwrap_or(config.rag_top_k),
+                        min_score_vector_search,
                         config.rag_min_score_keyword_search,
+                        agent_config.and_then(|v| v.rag_min_context_score),
                     )
                 };
                 let rerank = match self.config.read().rag_rerank_model.clone() {
                     Some(rerank_model_id) => {
-                        let min_score = self.config.read().rag_min_score_rerank;
+                        let (min_score, budget) = {
+                            let config = self.config.read();
+                            (config.rag_min_score_rerank, config.rag_rerank_budget)
+                        };
                         let rerank_model =
                             Model::retrieve_rerank(&self.config.read(), &rerank_model_id)?;
                         let rerank_client = init_client(&self.config, Some(rerank_model))?;
-                        Some((rerank_client, min_score))
+                        Some((rerank_client, min_score, budget.map(Duration::from_millis)))
                     }
                     None => None,
                 };
-                let embeddings = rag
+                let scope = self.config.read().rag_scope.clone();
+                let chunks = rag
                     .search(
                         &self.text,
                         top_k,
                         min_score_vector_search,
                         min_score_keyword_search,
                         rerank,
+                        scope.as_deref(),
                         abort_signal,
                     )
                     .await?;
-                let text = self.config.read().rag_template(&embeddings, &self.text);
-                self.patched_text = Some(text);
+                let best_score = best_chunk_score(&chunks);
+                debug!(
+                    "rag '{}' chunk scores: {:?}",
+                    rag.name(),
+                    chunks.iter().map(|v| v.score).collect::<Vec<_>>()
+                );
+                match min_context_score {
+                    Some(min) if !best_score.is_some_and(|v| v >= min) => {
+                        debug!(
+                            "rag '{}' best score {best_score:?} is below {min}",
+                            rag.name()
+                        );
+                        let text = self.config.read().rag_no_context_template(&self.text);
+                        self.patched_text = Some(text);
+                    }
+                    _ if chunks.is_empty() => {
+                        debug!("rag '{}' found no relevant context", rag.name())
+                    }
+                    _ => {
+                        let embeddings = chunks
+                            .iter()
+                            .map(|v| v.text.as_str())
+                            .collect::<Vec<_>>()
+                            .join("\n\n");
+                        let text = self.config.read().rag_template(&embeddings, &self.text);
+                        self.patched_text = Some(text);
+                    }
+                }
                 self.rag_name = Some(rag.name().to_string());
             }
         }
@@ -237,11 +279,17 @@ impl Input {
         let temperature = self.role().temperature();
         let top_p = self.role().top_p();
         let functions = self.config.read().select_functions(model, self.role());
+        let response_format = self
+            .role()
+            .response_format()
+            .filter(|_| model.supports_response_format())
+            .cloned();
         Ok(ChatCompletionsData {
             messages,
             temperature,
             top_p,
             functions,
+            response_format,
             stream,
         })
     }
@@ -3,7 +3,8 @@ mod input;
 mod role;
 mod session;
 
-pub use self::agent::{list_agents, Agent, AgentConfig};
+use self::agent::AgentDefinitionCache;
+pub use self::agent::{agent_banner, list_agents, list_agents_detailed, Agent, AgentConfig};
 pub use self::input::Input;
 pub use self::role::{Role, RoleLike, CODE_ROLE, EXPLAIN_SHELL_ROLE, SHELL_ROLE};
 use self::session::Session;
@@ -12,14 +13,18 @@ use crate::client::{
     create_client_config, list_chat_models, list_client_types, list_rerank_models, ClientConfig,
     Model, OPENAI_COMPATIBLE_PLATFORMS,
 };
-use crate::function::{FunctionDeclaration, Functions, FunctionsFilter, ToolResult};
-use crate::rag::Rag;
+use crate::function::{
+    deny_functions, filter_to_regex, FunctionDeclaration, Functions, FunctionsFilter, ToolResult,
+};
+use crate::rag::{
+    scope_matcher, BomColumns, DocumentTransforms, LoaderRegistry, Rag, RagDocument, RagStatus,
+};
 use crate::render::{MarkdownRender, RenderOptions};
 use crate::utils::*;
 
 use anyhow::{anyhow, bail, Context, Result};
 use fancy_regex::Regex;
-use inquire::{Confirm, Select};
+use inquire::{required, Confirm, Select, Text};
 use parking_lot::RwLock;
 use serde::Deserialize;
 use serde_json::json;
@@ -48,6 +53,9 @@ const FUNCTIONS_FILE_NAME: &str = "functions.json";
 const FUNCTIONS_BIN_DIR_NAME: &str = "bin";
 const AGENTS_DIR_NAME: &str = "agents";
 const AGENT_DEFINITION_FILE_NAME: &str = "index.yaml";
+/// Looked up in order; YAML stays the default when none exists.
+const AGENT_DEFINITION_FILE_NAMES: [&str; 4] =
+    ["index.yaml", "index.yml", "index.toml", "index.json"];
 const AGENT_EMBEDDINGS_DIR: &str = "embeddings";
 const AGENT_RAG_FILE_NAME: &str = "rag.bin";
 
@@ -75,6 +83,11 @@ And answer according to the language of the user's question.
 Given the context information, answer the query.
 Query: __INPUT__"#;
 
+const RAG_NO_CONTEXT_TEMPLATE: &str = r#"No sufficiently relevant context was found in the knowledge base for this query.
+Tell the user that you don't have relevant information rather than guessing.
+
+Query: __INPUT__"#;
+
 const LEFT_PROMPT: &str = "{color.green}{?session {?agent {agent}>}{session}{?role /}}{!session {?agent {agent}>}}{role}{?rag @{rag}}{color.cyan}{?session )}{!session >}{color.reset} ";
 const RIGHT_PROMPT: &str = "{color.purple}{?session {?consume_tokens {consume_tokens}({consume_percent}%)}{!consume_tokens {consume_tokens}}}{color.reset}";
 
@@ -97,6 +110,7 @@ pub struct Config {
     pub prelude: Option<String>,
     pub repl_prelude: Option<String>,
     pub agent_prelude: Option<String>,
+    pub default_agent: Option<String>,
 
     pub save_session: Option<bool>,
     pub compress_threshold: usize,
@@ -105,9 +119,15 @@ pub struct Config {
 
     pub function_calling: bool,
     pub dangerously_functions_filter: Option<FunctionsFilter>,
+    pub functions_denylist: Option<FunctionsFilter>,
     pub agents: Vec<AgentConfig>,
 
     pub rag_embedding_model: Option<String>,
+    pub rag_embedding_fallback_models: Vec<String>,
+    pub rag_embedding_retries: usize,
+    pub rag_max_embedding_failure_ratio: f32,
+    pub rag_embedding_concurrency: usize,
+    pub rag_embedding_rate_limit: Option<u32>,
     pub rag_rerank_model: Option<String>,
     pub rag_top_k: usize,
     pub rag_chunk_size: Option<usize>,
@@ -115,7 +135,24 @@ pub struct Config {
     pub rag_min_score_vector_search: f32,
     pub rag_min_score_keyword_search: f32,
     pub rag_min_score_rerank: f32,
+    pub rag_rerank_budget: Option<u64>,
     pub rag_template: Option<String>,
+    pub rag_bom_columns: Option<BomColumns>,
+    pub rag_headings_only: bool,
+    pub rag_max_rows: usize,
+    pub rag_load_concurrency: Option<usize>,
+    pub rag_loader_timeout: u64,
+    pub rag_loader_attempts: usize,
+    pub rag_loader_max_output: usize,
+    pub rag_max_download_size: usize,
+    pub rag_pdf_ocr: bool,
+    pub rag_peek_text_files: bool,
+    pub rag_detect_language: bool,
+    pub rag_split_code: bool,
+    pub rag_max_depth: Option<usize>,
+    pub rag_disabled_loaders: Vec<String>,
+    pub rag_max_document_size: usize,
+    pub rag_loader_overlap: usize,
 
     pub highlight: bool,
     pub light_theme: bool,
@@ -133,6 +170,8 @@ pub struct Config {
     #[serde(skip)]
     pub rag: Option<Arc<Rag>>,
     #[serde(skip)]
+    pub rag_scope: Option<String>,
+    #[serde(skip)]
     pub agent: Option<Agent>,
     #[serde(skip)]
     pub model: Model,
@@ -142,6 +181,12 @@ pub struct Config {
     pub working_mode: WorkingMode,
     #[serde(skip)]
     pub last_message: Option<(Input, String)>,
+    #[serde(skip)]
+    pub agent_definitions: AgentDefinitionCache,
+    #[serde(skip)]
+    pub loader_registry: LoaderRegistry,
+    #[serde(skip)]
+    pub document_transforms: DocumentTransforms,
 }
 
 impl Default for Config {
@@ -161,12 +206,19 @@ impl Default for Config {
             prelude: None,
             repl_prelude: None,
             agent_prelude: None,
+            default_agent: None,
 
             function_calling: false,
             dangerously_functions_filter: None,
+            functions_denylist: None,
             agents: vec![],
 
             rag_embedding_model: None,
+            rag_embedding_fallback_models: vec![],
+            rag_embedding_retries: 0,
+            rag_max_embedding_failure_ratio: 0.1,
+            rag_embedding_concurrency: 4,
+            rag_embedding_rate_limit: None,
             rag_rerank_model: None,
             rag_top_k: 4,
             rag_chunk_size: None,
@@ -174,7 +226,24 @@ impl Default for Config {
             rag_min_score_vector_search: 0.0,
             rag_min_score_keyword_search: 0.0,
             rag_min_score_rerank: 0.0,
+            rag_rerank_budget: None,
             rag_template: None,
+            rag_bom_columns: None,
+            rag_headings_only: false,
+            rag_max_rows: 100_000,
+            rag_load_concurrency: None,
+            rag_loader_timeout: 60,
+            rag_loader_attempts: 3,
+            rag_loader_max_output: 64 * 1024 * 1024,
+            rag_max_download_size: 32 * 1024 * 1024,
+            rag_pdf_ocr: false,
+            rag_peek_text_files: false,
+            rag_detect_language: true,
+            rag_split_code: false,
+            rag_max_depth: None,
+            rag_disabled_loaders: vec![],
+            rag_max_document_size: 200_000,
+            rag_loader_overlap: 0,
 
             save_session: None,
             compress_threshold: 4000,
@@ -192,11 +261,15 @@ impl Default for Config {
             role: None,
             session: None,
             rag: None,
+            rag_scope: None,
             agent: None,
             model: Default::default(),
             functions: Default::default(),
             working_mode: WorkingMode::Command,
             last_message: None,
+            agent_definitions: Default::default(),
+            loader_registry: Default::default(),
+            document_transforms: Default::default(),
         }
     }
 }
@@ -225,6 +298,7 @@ impl Config {
 
         config.working_mode = working_mode;
         config.load_roles()?;
+        config.validate_default_agent();
 
         config.setup_model()?;
         config.setup_highlight();
@@ -353,7 +427,11 @@ impl Config {
     }
 
     pub fn agent_definition_file(name: &str) -> Result<PathBuf> {
-        Ok(Self::agent_functions_dir(name)?.join(AGENT_DEFINITION_FILE_NAME))
+        let dir = Self::agent_functions_dir(name)?;
+        Ok(
+            find_agent_definition_file(&dir)
+                .unwrap_or_else(|| dir.join(AGENT_DEFINITION_FILE_NAME)),
+        )
     }
 
     pub fn agent_embeddings_dir(name: &str) -> Result<PathBuf> {
@@ -407,7 +485,11 @@ impl Config {
 
     pub fn extract_role(&self) -> Role {
         let mut role = if let Some(session) = self.session.as_ref() {
-            session.to_role()
+            let mut role = session.to_role();
+            if let Some(agent) = self.agent.as_ref() {
+                role.set_response_format(agent.effective_role().response_format().cloned());
+            }
+            role
         } else if let Some(agent) = self.agent.as_ref() {
             agent.to_role()
         } else if let Some(role) = self.role.as_ref() {
@@ -428,7 +510,7 @@ impl Config {
 
     pub fn info(&self) -> Result<String> {
         if let Some(agent) = &self.agent {
-            let output = agent.export()?;
+            let output = agent.export(false)?;
             if let Some(session) = &self.session {
                 let session = session
                     .export()?
@@ -880,18 +962,35 @@ impl Config {
                         format!("Failed to cleanup previous '{TEMP_RAG_NAME}' rag")
                     })?;
                 }
-                Rag::init(config, TEMP_RAG_NAME, &rag_path, &[], abort_signal).await?
+                Rag::init(config, TEMP_RAG_NAME, &rag_path, &[], abort_signal, None).await?
             }
             Some(name) => {
                 let rag_path = config.read().rag_file(name)?;
                 if !rag_path.exists() {
-                    Rag::init(config, name, &rag_path, &[], abort_signal).await?
+                    Rag::init(config, name, &rag_path, &[], abort_signal, None).await?
                 } else {
                     Rag::load(config, name, &rag_path)?
                 }
             }
         };
-        config.write().rag = Some(Arc::new(rag));
+        let mut config = config.write();
+        config.rag = Some(Arc::new(rag));
+        config.rag_scope = None;
+        Ok(())
+    }
+
+    /// Restricts retrieval to the documents whose path matches `scope`, or lifts the restriction.
+    pub fn set_rag_scope(&mut self, scope: Option<&str>) -> Result<()> {
+        if self.rag.is_none() {
+            bail!("No rag")
+        }
+        let scope = scope
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        if let Some(scope) = &scope {
+            scope_matcher(scope)?;
+        }
+        self.rag_scope = scope;
         Ok(())
     }
 
@@ -903,8 +1002,97 @@ impl Config {
         }
     }
 
+    /// Compares the active rag with its source files. An agent's embeddings directory
+    /// is also scanned for files that are not indexed yet.
+    pub async fn rag_status(config: &GlobalConfig) -> Result<RagStatus> {
+        let (agent, rag) = {
+            let config = config.read();
+            (config.agent.clone(), config.rag.clone())
+        };
+        if let Some(status) = match &agent {
+            Some(agent) => agent.validate_rag().await?,
+            None => None,
+        } {
+            return Ok(status);
+        }
+        match rag {
+            Some(rag) => rag.status::<&str>(&[]).await,
+            None => bail!("No rag"),
+        }
+    }
+
+    /// Appends `paths` to the active rag and swaps in the saved result. Files added
+    /// to an agent's rag outside its document paths are dropped by its next refresh.
+    pub async fn add_rag_documents(
+        config: &GlobalConfig,
+        paths: &[String],
+        force: bool,
+        abort_signal: AbortSignal,
+    ) -> Result<Vec<String>> {
+        let rag = match config.read().rag.clone() {
+            Some(v) => v,
+            None => bail!("No rag"),
+        };
+        if rag.is_temp() {
+            bail!("Cannot add documents to a temporary rag, use `.rag <name>` to create one");
+        }
+        let rag_path = PathBuf::from(rag.path());
+        let mut rag = Rag::load(config, rag.name(), &rag_path)?;
+        let added = rag.add_documents(paths, force, abort_signal).await?;
+        Self::replace_rag(config, rag, &rag_path)?;
+        Ok(added)
+    }
+
+    pub fn import_rag_documents(config: &GlobalConfig, path: &str) -> Result<usize> {
+        let rag = match config.read().rag.clone() {
+            Some(v) => v,
+            None => bail!("No rag"),
+        };
+        if rag.is_temp() {
+            bail!("Cannot import documents to a temporary rag, use `.rag <name>` to create one");
+        }
+        let rag_path = PathBuf::from(rag.path());
+        let mut rag = Rag::load(config, rag.name(), &rag_path)?;
+        let imported = rag.import_documents(path)?;
+        Self::replace_rag(config, rag, &rag_path)?;
+        Ok(imported)
+    }
+
+    pub fn prune_rag(config: &GlobalConfig) -> Result<(usize, usize)> {
+        let rag = match config.read().rag.clone() {
+            Some(v) => v,
+            None => bail!("No rag"),
+        };
+        if rag.is_temp() {
+            bail!("Cannot prune a temporary rag");
+        }
+        let rag_path = PathBuf::from(rag.path());
+        let mut rag = Rag::load(config, rag.name(), &rag_path)?;
+        let removed = rag.prune()?;
+        Self::replace_rag(config, rag, &rag_path)?;
+        Ok(removed)
+    }
+
+    /// Puts the rag saved at `rag_path` back in use, through the agent when there is one.
+    fn replace_rag(config: &GlobalConfig, rag: Rag, rag_path: &Path) -> Result<()> {
+        let agent = config.write().agent.take();
+        match agent {
+            Some(mut agent) => {
+                let ret = agent.set_rag(config, rag_path);
+                if ret.is_ok() {
+                    config.write().rag = agent.rag();
+                }
+                config.write().agent = Some(agent);
+                ret?;
+            }
+            None => config.write().rag = Some(Arc::new(rag)),
+        }
+        Ok(())
+    }
+
     pub fn exit_rag(&mut self) -> Result<()> {
         self.rag.take();
+        self.rag_scope = None;
         Ok(())
     }
 
@@ -940,10 +1128,35 @@ impl Config {
             .replace("__INPUT__", text)
     }
 
+    pub fn rag_no_context_template(&self, text: &str) -> String {
+        RAG_NO_CONTEXT_TEMPLATE.replace("__INPUT__", text)
+    }
+
+    /// Registers a document loader for `extension`, overriding the builtin or
+    /// previously registered one if any.
+    #[allow(unused)]
+    pub fn register_loader<F>(&mut self, extension: &str, loader: F)
+    where
+        F: Fn(&str) -> Result<Vec<RagDocument>> + Send + Sync + 'static,
+    {
+        self.loader_registry.register(extension, loader);
+    }
+
+    /// Adds a transform run over every loaded document before it is chunked and
+    /// embedded, after the ones already registered.
+    #[allow(unused)]
+    pub fn register_document_transform<F>(&mut self, transform: F)
+    where
+        F: Fn(RagDocument) -> Vec<RagDocument> + Send + Sync + 'static,
+    {
+        self.document_transforms.register(transform);
+    }
+
     pub async fn use_agent(
         config: &GlobalConfig,
         name: &str,
         session: Option<&str>,
+        skip_rag: bool,
         abort_signal: AbortSignal,
     ) -> Result<()> {
         if !config.read().function_calling {
@@ -952,8 +1165,9 @@ impl Config {
         if config.read().agent.is_some() {
             bail!("Already in a agent, please run '.exit agent' first to exit the current agent.");
         }
-        let agent = Agent::init(config, name, abort_signal).await?;
+        let agent = Agent::init(config, name, skip_rag, abort_signal).await?;
         config.write().rag = agent.rag();
+        config.write().rag_scope = None;
         config.write().agent = Some(agent);
         let session = session
             .map(|v| v.to_string())
@@ -964,14 +1178,95 @@ impl Config {
         Ok(())
     }
 
+    pub async fn reload_agent(config: &GlobalConfig, abort_signal: AbortSignal) -> Result<bool> {
+        let mut agent = match config.write().agent.take() {
+            Some(v) => v,
+            None => bail!("No agent"),
+        };
+        let ret = agent.reload(config, abort_signal).await;
+        config.write().rag = agent.rag();
+        config.write().agent = Some(agent);
+        ret
+    }
+
+    pub fn set_agent_rag(config: &GlobalConfig, path: &str) -> Result<()> {
+        let mut agent = match config.write().agent.take() {
+            Some(v) => v,
+            None => bail!("No agent"),
+        };
+        let ret = agent.set_rag(config, Path::new(path));
+        if ret.is_ok() {
+            config.write().rag = agent.rag();
+            config.write().rag_scope = None;
+        }
+        config.write().agent = Some(agent);
+        ret
+    }
+
+    /// The active agent, or else the configured `default_agent`.
+    pub fn current_agent_name(&self) -> Option<&str> {
+        match &self.agent {
+            Some(agent) => Some(agent.name()),
+            None => self.default_agent.as_deref(),
+        }
+    }
+
+    /// Drops a `default_agent` that names no installed agent, so a stale setting can't
+    /// keep aichat from starting.
+    fn validate_default_agent(&mut self) {
+        let Some(name) = &self.default_agent else {
+            return;
+        };
+        let agents = list_agents();
+        if !agents.contains(name) {
+            warn!(
+                "Ignore default_agent '{name}', available agents: {}",
+                agents.join(", ")
+            );
+            self.default_agent = None;
+        }
+    }
+
     pub fn agent_info(&self) -> Result<String> {
         if let Some(agent) = &self.agent {
-            agent.export()
+            agent.export(true)
         } else {
             bail!("No agent")
         }
     }
 
+    /// The agent's role as it is sent to the model, after all layering.
+    pub fn agent_role_info(&self) -> Result<String> {
+        if let Some(agent) = &self.agent {
+            agent.effective_role().export()
+        } else {
+            bail!("No agent")
+        }
+    }
+
+    /// Prompts for the argument slots of the starter whose prompt is `text`, if any,
+    /// and returns the prompt to send.
+    pub fn fill_starter(&self, text: &str) -> Result<String> {
+        let Some(agent) = &self.agent else {
+            return Ok(text.to_string());
+        };
+        let Some(index) = agent.conversation_starters().iter().position(|v| v == text) else {
+            return Ok(text.to_string());
+        };
+        let arguments = agent.starter_arguments(index);
+        if arguments.is_empty() {
+            return Ok(text.to_string());
+        }
+        let mut values = HashMap::new();
+        for name in arguments {
+            let value = Text::new(&format!("{name}:"))
+                .with_validator(required!())
+                .prompt()?;
+            values.insert(name, value);
+        }
+        agent.conversation_starter_descriptors()[index].render(|name| values.get(name).cloned())
+    }
+
     pub fn agent_banner(&self) -> Result<String> {
         if let Some(agent) = &self.agent {
             Ok(agent.banner())
@@ -984,6 +1279,7 @@ impl Config {
         self.exit_session()?;
         if self.agent.take().is_some() {
             self.rag.take();
+            self.rag_scope = None;
             self.last_message = None;
         }
         Ok(())
@@ -1024,6 +1320,7 @@ impl Config {
         if self.function_calling {
             let filter = role.functions_filter();
             if let Some(filter) = filter {
+                let filter = deny_functions(&filter, self.functions_denylist.as_ref());
                 functions = match &self.agent {
                     Some(agent) => agent.functions().select(&filter),
                     None => self.functions.select(&filter),
@@ -1050,7 +1347,7 @@ impl Config {
         match dangerously_functions_filter {
             None => false,
             Some(regex) => {
-                let regex = match Regex::new(&format!("^({regex})$")) {
+                let regex = match Regex::new(&format!("^({})$", filter_to_regex(regex))) {
                     Ok(v) => v,
                     Err(_) => return false,
                 };
@@ -1083,12 +1380,26 @@ impl Config {
                     .map(|v| (v, None))
                     .collect(),
                 ".rag" => self.list_rags().into_iter().map(|v| (v, None)).collect(),
-                ".agent" => list_agents().into_iter().map(|v| (v, None)).collect(),
+                ".agent" => list_agents_detailed(self)
+                    .into_iter()
+                    .map(|v| {
+                        let description = match v.description.is_empty() {
+                            true => None,
+                            false => Some(v.description),
+                        };
+                        (v.name, description)
+                    })
+                    .collect(),
                 ".starter" => match &self.agent {
                     Some(agent) => agent
-                        .conversation_staters()
+                        .conversation_starter_descriptors()
                         .iter()
-                        .map(|v| (v.clone(), None))
+                        .map(|v| {
+                            (
+                                v.text.clone(),
+                                v.label.clone().or_else(|| v.category.clone()),
+                            )
+                        })
                         .collect(),
                     None => vec![],
                 },
@@ -1591,3 +1902,28 @@ fn complete_option_bool(value: Option<bool>) -> Vec<String> {
         None => vec!["true".to_string(), "false".to_string()],
     }
 }
+
+/// Finds the agent definition in `dir`, in any of the supported formats.
+fn find_agent_definition_file(dir: &Path) -> Option<PathBuf> {
+    AGENT_DEFINITION_FILE_NAMES
+        .iter()
+        .map(|v| dir.join(v))
+        .find(|v| v.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_agent() {
+        let mut config = Config {
+            default_agent: Some("aichat-test-agent".into()),
+            ..Default::default()
+        };
+        assert_eq!(config.current_agent_name(), Some("aichat-test-agent"));
+        config.validate_default_agent();
+        assert_eq!(config.default_agent, None);
+        assert_eq!(config.current_agent_name(), None);
+    }
+}
@@ -1,7 +1,7 @@
 use super::*;
 
 use crate::{
-    client::{Message, MessageContent, MessageRole, Model},
+    client::{Message, MessageContent, MessageRole, Model, ResponseFormat},
     function::{FunctionsFilter, SELECTED_ALL_FUNCTIONS},
     utils::{detect_os, detect_shell},
 };
@@ -44,6 +44,12 @@ pub struct Role {
     top_p: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     functions_filter: Option<FunctionsFilter>,
+    #[serde(
+        default,
+        with = "serde_yaml::with::singleton_map",
+        skip_serializing_if = "Option::is_none"
+    )]
+    response_format: Option<ResponseFormat>,
 
     #[serde(skip)]
     model: Model,
@@ -134,6 +140,14 @@ async function timeout(ms) {
         }
     }
 
+    pub fn response_format(&self) -> Option<&ResponseFormat> {
+        self.response_format.as_ref()
+    }
+
+    pub fn set_response_format(&mut self, value: Option<ResponseFormat>) {
+        self.response_format = value;
+    }
+
     pub fn is_derived(&self) -> bool {
         self.name.is_empty()
     }
@@ -19,6 +19,71 @@ pub const SELECTED_ALL_FUNCTIONS: &str = ".*";
 pub type ToolResults = (Vec<ToolResult>, String);
 pub type FunctionsFilter = String;
 
+/// Narrows `filter` so that it never selects a function matched by `denylist`.
+pub fn deny_functions(filter: &str, denylist: Option<&FunctionsFilter>) -> FunctionsFilter {
+    match denylist {
+        Some(denylist) if !denylist.is_empty() => {
+            format!("(?!(?:{})$)(?:{filter})", filter_to_regex(denylist))
+        }
+        _ => filter.to_string(),
+    }
+}
+
+/// Splits `filter` into its top-level `|` alternatives, e.g. `web_*|fs_(read|list)`
+/// into `web_*` and `fs_(read|list)`.
+fn filter_patterns(filter: &str) -> Vec<&str> {
+    let mut patterns = vec![];
+    let (mut depth, mut in_class, mut escaped, mut start) = (0usize, false, false, 0);
+    for (i, c) in filter.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '(' if !in_class => depth += 1,
+            ')' if !in_class => depth = depth.saturating_sub(1),
+            '|' if !in_class && depth == 0 => {
+                patterns.push(&filter[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    patterns.push(&filter[start..]);
+    patterns
+}
+
+/// Patterns made of name characters plus `*` and `?` are globs; anything else is a regex.
+fn is_glob_pattern(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+        && pattern
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '*' | '?'))
+}
+
+fn pattern_to_regex(pattern: &str) -> String {
+    if !is_glob_pattern(pattern) {
+        return pattern.to_string();
+    }
+    pattern
+        .chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            _ => fancy_regex::escape(&c.to_string()).into_owned(),
+        })
+        .collect()
+}
+
+/// Rewrites the glob alternatives of `filter` as regexes.
+pub fn filter_to_regex(filter: &str) -> FunctionsFilter {
+    filter_patterns(filter)
+        .into_iter()
+        .map(pattern_to_regex)
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
 pub fn eval_tool_calls(config: &GlobalConfig, mut calls: Vec<ToolCall>) -> Result<Vec<ToolResult>> {
     let mut output = vec![];
     if calls.is_empty() {
@@ -81,7 +146,7 @@ impl Functions {
     }
 
     pub fn select(&self, filter: &FunctionsFilter) -> Option<Vec<FunctionDeclaration>> {
-        let regex = Regex::new(&format!("^({filter})$")).ok()?;
+        let regex = Regex::new(&format!("^({})$", filter_to_regex(filter))).ok()?;
         let output: Vec<FunctionDeclaration> = self
             .declarations
             .iter()
@@ -95,10 +160,47 @@ impl Functions {
         }
     }
 
+    /// Expands each glob or regex alternative of `filter` into the installed function
+    /// names it matches. Returns `None` when nothing matches, along with the
+    /// alternatives that matched no function.
+    pub fn expand_filter(&self, filter: &str) -> (Option<FunctionsFilter>, Vec<String>) {
+        let mut names: IndexSet<&str> = IndexSet::new();
+        let mut unmatched = vec![];
+        for pattern in filter_patterns(filter) {
+            let matched: Vec<&str> =
+                match Regex::new(&format!("^(?:{})$", pattern_to_regex(pattern))) {
+                    Ok(regex) => self
+                        .names
+                        .iter()
+                        .filter(|v| regex.is_match(v).unwrap_or_default())
+                        .map(|v| v.as_str())
+                        .collect(),
+                    Err(_) => vec![],
+                };
+            if matched.is_empty() {
+                unmatched.push(pattern.to_string());
+            }
+            names.extend(matched);
+        }
+        if names.is_empty() {
+            return (None, unmatched);
+        }
+        let filter = names
+            .into_iter()
+            .map(|v| fancy_regex::escape(v).into_owned())
+            .collect::<Vec<_>>()
+            .join("|");
+        (Some(filter), unmatched)
+    }
+
     pub fn contains(&self, name: &str) -> bool {
         self.names.contains(name)
     }
 
+    pub fn names(&self) -> &IndexSet<String> {
+        &self.names
+    }
+
     pub fn is_empty(&self) -> bool {
         self.names.is_empty()
     }
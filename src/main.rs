@@ -16,10 +16,11 @@ extern crate log;
 use crate::cli::Cli;
 use crate::client::{chat_completion_streaming, list_chat_models, ChatCompletionsOutput};
 use crate::config::{
-    list_agents, Config, GlobalConfig, Input, WorkingMode, CODE_ROLE, EXPLAIN_SHELL_ROLE,
-    SHELL_ROLE, TEMP_SESSION_NAME,
+    agent_banner, list_agents, Agent, Config, GlobalConfig, Input, WorkingMode, CODE_ROLE,
+    EXPLAIN_SHELL_ROLE, SHELL_ROLE, TEMP_SESSION_NAME,
 };
 use crate::function::{eval_tool_calls, need_send_tool_results};
+use crate::rag::{supported_formats, LoaderOptions};
 use crate::render::{render_error, MarkdownRender};
 use crate::repl::Repl;
 use crate::utils::*;
@@ -71,8 +72,16 @@ async fn main() -> Result<()> {
         return Ok(());
     }
     if cli.list_agents {
-        let agents = list_agents().join("\n");
-        println!("{agents}");
+        let agents = list_agents();
+        if *IS_STDOUT_TERMINAL {
+            let banners: Vec<String> = agents
+                .iter()
+                .map(|name| agent_banner(name).unwrap_or_else(|_| format!("# {name}")))
+                .collect();
+            println!("{}", banners.join("\n\n"));
+        } else {
+            println!("{}", agents.join("\n"));
+        }
         return Ok(());
     }
     if cli.list_rags {
@@ -80,6 +89,16 @@ async fn main() -> Result<()> {
         println!("{rags}");
         return Ok(());
     }
+    if cli.list_formats {
+        let options = LoaderOptions::new(&config.read());
+        for format in supported_formats(&options) {
+            match format.missing {
+                Some(missing) => println!("{}\t{} ({missing})", format.extension, format.loader),
+                None => println!("{}\t{}", format.extension, format.loader),
+            }
+        }
+        return Ok(());
+    }
     if let Some(wrap) = &cli.wrap {
         config.write().set_wrap(wrap)?;
     }
@@ -90,12 +109,25 @@ async fn main() -> Result<()> {
         config.write().dry_run = true;
     }
 
-    if let Some(agent) = &cli.agent {
+    if cli.plan_rag {
+        if let Some(agent) = &cli.agent {
+            let plan = Agent::plan_rag(&config, agent).await?;
+            print!("{plan}");
+        }
+        return Ok(());
+    }
+
+    let agent = match &cli.agent {
+        Some(agent) => Some(agent.clone()),
+        None if cli.use_default_agent(no_input) => config.read().default_agent.clone(),
+        None => None,
+    };
+    if let Some(agent) = &agent {
         let session = cli.session.as_ref().map(|v| match v {
             Some(v) => v.as_str(),
             None => TEMP_SESSION_NAME,
         });
-        Config::use_agent(&config, agent, session, abort_signal.clone()).await?
+        Config::use_agent(&config, agent, session, cli.no_rag, abort_signal.clone()).await?
     } else {
         if let Some(prompt) = &cli.prompt {
             config.write().use_prompt(prompt)?;
@@ -0,0 +1,248 @@
+use super::{RagData, RagManifest};
+
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"AICHATRG";
+/// Bumped whenever the layout of `RagData` changes.
+pub const RAG_INDEX_VERSION: u32 = 1;
+const CHECKSUM_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 4 + CHECKSUM_LEN;
+
+/// Encodes `data` behind a header holding the format version and a checksum of the payload.
+pub fn encode_rag_index(data: &RagData) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(data)?;
+    let mut output = Vec::with_capacity(HEADER_LEN + payload.len());
+    output.extend_from_slice(MAGIC);
+    output.extend_from_slice(&RAG_INDEX_VERSION.to_le_bytes());
+    output.extend_from_slice(&Sha256::digest(&payload));
+    output.extend_from_slice(&payload);
+    Ok(output)
+}
+
+pub fn decode_rag_index(bytes: &[u8]) -> Result<RagData> {
+    let Some(rest) = bytes.strip_prefix(MAGIC.as_slice()) else {
+        // Indexes written before the header existed hold the bare payload.
+        return bincode::deserialize(bytes).map_err(|_| {
+            anyhow!("The index file is corrupt or was written by an older version, please re-index")
+        });
+    };
+    if rest.len() < HEADER_LEN - MAGIC.len() {
+        bail!("The index file is corrupt (truncated header), please re-index");
+    }
+    let (version, rest) = rest.split_at(4);
+    let version = u32::from_le_bytes(version.try_into()?);
+    if version > RAG_INDEX_VERSION {
+        bail!("The index was written by a newer version (format {version}, expected {RAG_INDEX_VERSION}), please upgrade or re-index");
+    }
+    if version < RAG_INDEX_VERSION {
+        bail!("The index was written by an older version (format {version}, expected {RAG_INDEX_VERSION}), please re-index");
+    }
+    let (checksum, payload) = rest.split_at(CHECKSUM_LEN);
+    if Sha256::digest(payload).as_slice() != checksum {
+        bail!("The index file is corrupt (checksum mismatch), please re-index");
+    }
+    bincode::deserialize(payload)
+        .map_err(|_| anyhow!("The index file is corrupt (invalid contents), please re-index"))
+}
+
+pub fn read_rag_index(path: &Path) -> Result<RagData> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Failed to read index file '{}'", path.display()))?;
+    decode_rag_index(&bytes)
+}
+
+/// The progress of an `init`, kept next to the rag until it is saved. Each batch of
+/// embedded files is written as a segment of its own, so that checkpointing a large
+/// rag does not rewrite the files embedded before.
+#[derive(Debug)]
+pub struct RagCheckpoint {
+    dir: PathBuf,
+    /// The number of leading files of the rag that the segments hold.
+    files: usize,
+    segments: usize,
+}
+
+impl RagCheckpoint {
+    pub fn new(rag_path: &Path) -> Self {
+        let mut name = rag_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".checkpoint");
+        Self {
+            dir: rag_path.with_file_name(name),
+            files: 0,
+            segments: 0,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Reads the segments back in order, `None` when there are none.
+    pub fn load(&self) -> Result<Option<(RagData, RagManifest)>> {
+        if !self.dir.is_dir() {
+            return Ok(None);
+        }
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&self.dir)?
+            .map(|entry| entry.map(|v| v.path()))
+            .collect::<std::io::Result<_>>()?;
+        paths.retain(|v| v.extension().is_some_and(|v| v == "bin"));
+        paths.sort();
+        let mut ret: Option<(RagData, RagManifest)> = None;
+        for path in paths {
+            let segment = read_rag_index(&path)?;
+            let segment_manifest = RagManifest::load(&path)?.unwrap_or_default();
+            match &mut ret {
+                None => ret = Some((segment, segment_manifest)),
+                Some((data, manifest)) => {
+                    if !data.has_same_settings(&segment) {
+                        bail!("The segment '{}' uses other settings", path.display());
+                    }
+                    data.append(segment);
+                    manifest.files.extend(segment_manifest.files);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Writes the files of `data` added since the last save as a new segment.
+    pub fn save(&mut self, data: &RagData, manifest: &RagManifest) -> Result<()> {
+        if data.files.len() <= self.files {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create '{}'", self.dir.display()))?;
+        let segment = data.files_from(self.files);
+        let segment_manifest = RagManifest {
+            files: manifest
+                .files
+                .iter()
+                .filter(|(path, _)| segment.files.iter().any(|v| &v.path == *path))
+                .map(|(path, fingerprint)| (path.clone(), fingerprint.clone()))
+                .collect(),
+            ..Default::default()
+        };
+        let path = self.dir.join(format!("{:06}.bin", self.segments));
+        write_rag_index(&path, &segment)?;
+        segment_manifest.save(&path)?;
+        self.files = data.files.len();
+        self.segments += 1;
+        Ok(())
+    }
+
+    /// Replaces the segments with a single one holding `data`.
+    pub fn reset(&mut self, data: &RagData, manifest: &RagManifest) -> Result<()> {
+        self.remove();
+        self.files = 0;
+        self.segments = 0;
+        self.save(data, manifest)
+    }
+
+    pub fn remove(&self) {
+        if self.dir.exists() {
+            if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+                warn!("failed to remove '{}', {err}", self.dir.display());
+            }
+        }
+    }
+}
+
+/// Writes through a temporary file so an interrupted save never leaves a partial index behind.
+pub fn write_rag_index(path: &Path, data: &RagData) -> Result<()> {
+    let bytes = encode_rag_index(data)?;
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, bytes)
+        .with_context(|| format!("Failed to write '{}'", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rag::{combine_document_id, FileFingerprint, RagDocument, RagFile};
+
+    #[test]
+    fn test_rag_index_format() {
+        let data = RagData::new("openai:text-embedding-3-small".into(), 1500, 75);
+        let bytes = encode_rag_index(&data).unwrap();
+        let decoded = decode_rag_index(&bytes).unwrap();
+        assert_eq!(decoded.embedding_model, data.embedding_model);
+        assert_eq!(decoded.chunk_size, 1500);
+
+        let legacy = bincode::serialize(&data).unwrap();
+        assert_eq!(decode_rag_index(&legacy).unwrap().chunk_overlap, 75);
+
+        let err = |bytes: &[u8]| decode_rag_index(bytes).unwrap_err().to_string();
+        assert!(err(&bytes[..bytes.len() - 3]).contains("checksum mismatch"));
+        assert!(err(&bytes[..10]).contains("truncated header"));
+        let mut newer = bytes.clone();
+        newer[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&2u32.to_le_bytes());
+        assert!(err(&newer).contains("newer version"));
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 0xff;
+        assert!(err(&tampered).contains("checksum mismatch"));
+        assert!(err(b"garbage").contains("corrupt"));
+    }
+
+    #[test]
+    fn test_rag_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("aichat-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rag_path = dir.join("rag.bin");
+        let mut checkpoint = RagCheckpoint::new(&rag_path);
+        assert_eq!(checkpoint.dir(), dir.join("rag.bin.checkpoint"));
+        assert!(checkpoint.load().unwrap().is_none());
+
+        let mut data = RagData::new("fake".into(), 1000, 0);
+        let mut manifest = RagManifest::default();
+        for (index, path) in ["a", "b", "c"].into_iter().enumerate() {
+            let file = RagFile {
+                path: path.into(),
+                documents: vec![RagDocument::new(path)],
+            };
+            data.add(
+                vec![file],
+                vec![combine_document_id(index, 0)],
+                vec![vec![index as f32]],
+            );
+            manifest.files.insert(
+                path.into(),
+                FileFingerprint {
+                    hash: path.into(),
+                    size: 1,
+                    mtime: None,
+                },
+            );
+            checkpoint.save(&data, &manifest).unwrap();
+        }
+        checkpoint.save(&data, &manifest).unwrap();
+        // Each save writes only the files added since the previous one.
+        let segments = std::fs::read_dir(checkpoint.dir()).unwrap().count();
+        let (loaded, loaded_manifest) = checkpoint.load().unwrap().unwrap();
+        checkpoint.reset(&loaded, &loaded_manifest).unwrap();
+        let reset_segments = std::fs::read_dir(checkpoint.dir()).unwrap().count();
+        checkpoint.remove();
+        let removed = checkpoint.dir().exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(segments, 6);
+        assert_eq!(reset_segments, 2);
+        assert!(!removed);
+        assert_eq!(loaded.files.len(), 3);
+        assert_eq!(loaded.vectors[&combine_document_id(2, 0)], [2.0]);
+        assert_eq!(
+            loaded.get(combine_document_id(1, 0)).unwrap().page_content,
+            "b"
+        );
+        assert_eq!(loaded_manifest.files.len(), 3);
+        // The checkpoint's manifests must not overwrite the rag's own.
+        assert_ne!(
+            RagManifest::path(&checkpoint.dir().join("000000.bin")),
+            RagManifest::path(&rag_path)
+        );
+    }
+}
@@ -0,0 +1,172 @@
+/// Only the start of a document is inspected, which is plenty to tell its language.
+const MAX_SAMPLE_CHARS: usize = 2000;
+const MIN_LETTERS: usize = 20;
+const MIN_STOPWORD_HITS: usize = 2;
+
+const STOPWORDS: [(&str, &[&str]); 7] = [
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "is", "in", "that", "it", "for", "with", "are", "this",
+            "was", "be", "on", "you", "not", "have",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "das", "ist", "nicht", "ein", "eine", "zu", "den", "mit", "sich",
+            "auf", "für", "ich", "auch", "dem", "wird",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "des", "est", "une", "un", "du", "que", "pour", "dans", "pas",
+            "qui", "sur", "avec", "au", "sont",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "la", "los", "las", "y", "que", "es", "del", "una", "por", "con", "para", "se",
+            "no", "como", "su", "al", "está",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "di", "che", "la", "è", "per", "una", "non", "con", "sono", "gli", "della",
+            "del", "le", "si", "anche", "questo", "nel",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "os", "que", "e", "do", "da", "em", "um", "uma", "para", "com", "não", "é", "dos",
+            "das", "se", "mais", "no",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "en", "van", "is", "dat", "niet", "op", "te", "zijn", "voor",
+            "met", "ik", "ook", "aan", "er", "wordt",
+        ],
+    ),
+];
+
+/// Guesses the ISO 639-1 code of the language `text` is written in. Scripts used
+/// by a single language decide on their own; Latin text is scored by stopwords.
+/// Returns `None` for short or ambiguous text.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let sample: String = text.chars().take(MAX_SAMPLE_CHARS).collect();
+    let mut letters = 0;
+    let mut latin = 0;
+    let mut scripts: Vec<(&'static str, usize)> = vec![];
+    for c in sample.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        match script_language(c) {
+            Some(lang) => match scripts.iter_mut().find(|(v, _)| *v == lang) {
+                Some((_, count)) => *count += 1,
+                None => scripts.push((lang, 1)),
+            },
+            None => latin += 1,
+        }
+    }
+    if letters < MIN_LETTERS {
+        return None;
+    }
+    // Kana marks Japanese even when most characters are shared Han ideographs.
+    if scripts.iter().any(|(v, _)| *v == "ja") {
+        if let Some(han) = scripts.iter().position(|(v, _)| *v == "zh") {
+            let (_, count) = scripts.remove(han);
+            if let Some((_, kana)) = scripts.iter_mut().find(|(v, _)| *v == "ja") {
+                *kana += count;
+            }
+        }
+    }
+    if let Some((lang, count)) = scripts.iter().max_by_key(|(_, count)| *count) {
+        if *count > latin {
+            return Some(lang);
+        }
+    }
+    detect_latin_language(&sample)
+}
+
+fn detect_latin_language(text: &str) -> Option<&'static str> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_lowercase())
+        .collect();
+    let mut scores: Vec<(&'static str, usize)> = STOPWORDS
+        .iter()
+        .map(|(lang, stopwords)| {
+            let hits = words
+                .iter()
+                .filter(|word| stopwords.contains(&word.as_str()))
+                .count();
+            (*lang, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    match scores.as_slice() {
+        [(lang, best), (_, second), ..] if *best >= MIN_STOPWORD_HITS && best > second => {
+            Some(lang)
+        }
+        _ => None,
+    }
+}
+
+fn script_language(c: char) -> Option<&'static str> {
+    let lang = match c as u32 {
+        0x0370..=0x03FF => "el",
+        0x0400..=0x04FF => "ru",
+        0x0590..=0x05FF => "he",
+        0x0600..=0x06FF => "ar",
+        0x0900..=0x097F => "hi",
+        0x0E00..=0x0E7F => "th",
+        0x3040..=0x30FF => "ja",
+        0xAC00..=0xD7AF | 0x1100..=0x11FF => "ko",
+        0x4E00..=0x9FFF | 0x3400..=0x4DBF => "zh",
+        _ => return None,
+    };
+    Some(lang)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(
+            detect_language("The quick brown fox jumps over the lazy dog and runs to the forest."),
+            Some("en")
+        );
+        assert_eq!(
+            detect_language("Der schnelle braune Fuchs springt über den faulen Hund und ist weg."),
+            Some("de")
+        );
+        assert_eq!(
+            detect_language(
+                "Le renard brun saute par-dessus le chien et les chats sont dans la maison."
+            ),
+            Some("fr")
+        );
+        assert_eq!(
+            detect_language("Быстрая коричневая лиса прыгает через ленивую собаку."),
+            Some("ru")
+        );
+        assert_eq!(
+            detect_language("素早い茶色の狐がのろまな犬を飛び越えて森へ走っていきました。"),
+            Some("ja")
+        );
+        assert_eq!(
+            detect_language("敏捷的棕色狐狸跳过了那只懒惰的狗然后跑进了森林里面去了。"),
+            Some("zh")
+        );
+        assert_eq!(detect_language("fn main() {}"), None);
+        assert_eq!(detect_language("1234567890 1234567890 1234567890"), None);
+    }
+}
@@ -1,123 +1,2399 @@
 use super::*;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use async_recursion::async_recursion;
+use fancy_regex::Regex;
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use lazy_static::lazy_static;
-use std::{fs::read_to_string, path::Path};
+use std::{
+    fmt,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use which::which;
 
+pub const DEFAULT_MAX_ROWS: usize = 100_000;
+pub const DEFAULT_LOADER_TIMEOUT: u64 = 60;
+pub const DEFAULT_LOADER_ATTEMPTS: usize = 3;
+pub const DEFAULT_LOADER_MAX_OUTPUT: usize = 64 * 1024 * 1024;
+const MIN_PDF_TEXT_CHARS: usize = 32;
+pub const DEFAULT_MAX_DOCUMENT_SIZE: usize = 200_000;
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: usize = 32 * 1024 * 1024;
+const MAX_ARCHIVE_ENTRIES: usize = 10_000;
+
 lazy_static! {
     static ref EXIST_PANDOC: bool = which("pandoc").is_ok();
     static ref EXIST_PDFTOTEXT: bool = which("pdftotext").is_ok();
+    static ref EXIST_PDFINFO: bool = which("pdfinfo").is_ok();
+    static ref EXIST_PDFTOPPM: bool = which("pdftoppm").is_ok();
+    static ref EXIST_TESSERACT: bool = which("tesseract").is_ok();
+    static ref PANDOC_INPUT_FORMATS: Vec<String> = match *EXIST_PANDOC {
+        true => run_command_with_output("pandoc", &["--list-input-formats"], None)
+            .map(|(_, stdout, _)| stdout.lines().map(|v| v.trim().to_string()).collect())
+            .unwrap_or_default(),
+        false => vec![],
+    };
+}
+
+lazy_static! {
+    static ref HTML_HEADING_RE: Regex = Regex::new(r"(?is)<h([1-6])[^>]*>(.*?)</h\1>").unwrap();
+    static ref HTML_TAG_RE: Regex = Regex::new(r"(?s)<[^>]*>").unwrap();
+    static ref HTML_TITLE_RE: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+    static ref XML_TITLE_RE: Regex = Regex::new(r"(?is)<dc:title[^>]*>(.*?)</dc:title>").unwrap();
+    static ref HTML_SCRIPT_RE: Regex =
+        Regex::new(r"(?is)<(script|style|head)[^>]*>.*?</\1>").unwrap();
+    static ref HYPHENATED_BREAK_RE: Regex = Regex::new(r"(\p{L})-\n[ \t]*(\p{Ll})").unwrap();
+    static ref BLANK_LINES_RE: Regex = Regex::new(r"\n{3,}").unwrap();
+}
+
+lazy_static! {
+    static ref RUST_DEFINITION_RE: Regex = Regex::new(
+        r#"^(?:pub(?:\([^)]*\))?\s+)?(?:(?:async|const|unsafe|extern\s+"[^"]*")\s+)*(?:fn|struct|enum|union|trait|mod|type|static|const|macro_rules!)\s*(?<name>\w+)|^(?:unsafe\s+)?impl\b(?:<(?:[^<>]|<[^<>]*>)*>)?\s*(?<name2>[^{]+?)\s*(?:where\b.*)?\{?$"#
+    )
+    .unwrap();
+    static ref PYTHON_DEFINITION_RE: Regex =
+        Regex::new(r"^(?:async\s+)?(?:def|class)\s+(?<name>\w+)").unwrap();
+    static ref JS_DEFINITION_RE: Regex = Regex::new(
+        r"^(?:export\s+)?(?:default\s+)?(?:declare\s+)?(?:abstract\s+)?(?:async\s+)?(?:function\*?|class|interface|type|enum|const|let|var)\s+(?<name>[\w$]+)"
+    )
+    .unwrap();
+    static ref GO_DEFINITION_RE: Regex =
+        Regex::new(r"^(?:func\s+(?:\([^)]*\)\s*)?|type\s+)(?<name>\w+)").unwrap();
+    static ref JAVA_DEFINITION_RE: Regex = Regex::new(
+        r"^(?:(?:public|protected|private|abstract|final|sealed|static)\s+)*(?:class|interface|enum|record|@interface)\s+(?<name>\w+)"
+    )
+    .unwrap();
+    static ref RUBY_DEFINITION_RE: Regex =
+        Regex::new(r"^(?:def|class|module)\s+(?<name>[\w.:]+[?!=]?)").unwrap();
+}
+
+pub type LoaderFn = Arc<dyn Fn(&str) -> Result<Vec<RagDocument>> + Send + Sync>;
+
+#[derive(Clone)]
+pub enum Loader {
+    Builtin(
+        &'static str,
+        fn(&LoaderInput, &LoaderOptions) -> Result<Vec<RagDocument>>,
+    ),
+    Custom(LoaderFn),
+}
+
+/// What a builtin loader converts: the file at `path`, or `data` held in memory
+/// after being downloaded from, decompressed from or extracted from `path`.
+pub struct LoaderInput<'a> {
+    pub path: &'a str,
+    pub extension: &'a str,
+    pub data: Option<&'a [u8]>,
+}
+
+impl LoaderInput<'_> {
+    /// The argument naming the input for a tool, `-` when it is piped to stdin.
+    fn arg(&self) -> &str {
+        match self.data {
+            Some(_) => "-",
+            None => self.path,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LoaderRegistry {
+    loaders: IndexMap<String, Loader>,
+}
+
+impl Default for LoaderRegistry {
+    fn default() -> Self {
+        let mut loaders = IndexMap::new();
+        for (extension, name, loader) in [
+            (
+                "docx",
+                "pandoc",
+                load_with_pandoc as fn(&LoaderInput, &LoaderOptions) -> _,
+            ),
+            ("epub", "pandoc", load_with_pandoc),
+            ("odt", "pandoc", load_with_pandoc),
+            ("rtf", "pandoc", load_with_pandoc),
+            ("org", "pandoc", load_with_pandoc),
+            ("rst", "pandoc", load_with_pandoc),
+            ("pptx", "pandoc", load_slides_with_pandoc),
+            ("odp", "pandoc", load_slides_with_pandoc),
+            ("pdf", "pdftotext", load_with_pdftotext),
+        ] {
+            loaders.insert(extension.to_string(), Loader::Builtin(name, loader));
+        }
+        Self { loaders }
+    }
+}
+
+impl fmt::Debug for LoaderRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.loaders.keys()).finish()
+    }
+}
+
+impl LoaderRegistry {
+    /// Registers a loader for `extension`, replacing any existing one.
+    pub fn register<F>(&mut self, extension: &str, loader: F)
+    where
+        F: Fn(&str) -> Result<Vec<RagDocument>> + Send + Sync + 'static,
+    {
+        let extension = extension.trim_start_matches('.').to_lowercase();
+        self.loaders
+            .insert(extension, Loader::Custom(Arc::new(loader)));
+    }
+
+    pub fn get(&self, extension: &str) -> Option<&Loader> {
+        self.loaders.get(extension)
+    }
+}
+
+pub type DocumentTransform = Arc<dyn Fn(RagDocument) -> Vec<RagDocument> + Send + Sync>;
+
+/// Transforms chained over loaded documents, e.g. to redact or strip boilerplate.
+/// A transform may split a document or drop it by returning an empty list.
+#[derive(Clone, Default)]
+pub struct DocumentTransforms {
+    transforms: Vec<DocumentTransform>,
+}
+
+impl fmt::Debug for DocumentTransforms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DocumentTransforms({})", self.transforms.len())
+    }
+}
+
+impl DocumentTransforms {
+    pub fn register<F>(&mut self, transform: F)
+    where
+        F: Fn(RagDocument) -> Vec<RagDocument> + Send + Sync + 'static,
+    {
+        self.transforms.push(Arc::new(transform));
+    }
+
+    pub fn apply(&self, documents: Vec<RagDocument>) -> Vec<RagDocument> {
+        self.transforms
+            .iter()
+            .fold(documents, |documents, transform| {
+                documents.into_iter().flat_map(|v| transform(v)).collect()
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoaderOptions {
+    pub bom_columns: Option<BomColumns>,
+    pub headings_only: bool,
+    pub max_rows: usize,
+    pub concurrency: usize,
+    pub tool_timeout: Duration,
+    pub tool_attempts: usize,
+    pub max_output_bytes: usize,
+    pub max_download_bytes: usize,
+    pub pdf_ocr: bool,
+    pub max_document_chars: usize,
+    pub registry: LoaderRegistry,
+    pub transforms: DocumentTransforms,
+    pub abort_signal: Option<AbortSignal>,
+    /// The suffix filter each listed `.zip` archive was selected with.
+    pub archive_suffixes: HashMap<String, Vec<String>>,
+    pub peek_text_files: bool,
+    pub detect_language: bool,
+    /// Splits source files at their top-level definitions instead of as plain text.
+    pub split_code: bool,
+    /// How many directory levels below a listed directory are walked, `0` for its own files only.
+    pub max_depth: Option<usize>,
+    /// Characters of the previous piece repeated at the start of each markdown section
+    /// or size-capped part.
+    pub split_overlap: usize,
+    /// Builtin loaders, by tool name, that must not run even when installed.
+    pub disabled_loaders: Vec<String>,
+    pub skipped: SkippedFiles,
+}
+
+impl LoaderOptions {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            bom_columns: config.rag_bom_columns.clone(),
+            headings_only: config.rag_headings_only,
+            max_rows: config.rag_max_rows,
+            concurrency: config
+                .rag_load_concurrency
+                .unwrap_or_else(default_concurrency),
+            tool_timeout: Duration::from_secs(config.rag_loader_timeout),
+            tool_attempts: config.rag_loader_attempts,
+            max_output_bytes: config.rag_loader_max_output,
+            max_download_bytes: config.rag_max_download_size,
+            pdf_ocr: config.rag_pdf_ocr,
+            max_document_chars: config.rag_max_document_size,
+            registry: config.loader_registry.clone(),
+            transforms: config.document_transforms.clone(),
+            abort_signal: None,
+            archive_suffixes: HashMap::new(),
+            peek_text_files: config.rag_peek_text_files,
+            detect_language: config.rag_detect_language,
+            split_code: config.rag_split_code,
+            max_depth: config.rag_max_depth,
+            split_overlap: config.rag_loader_overlap,
+            disabled_loaders: config.rag_disabled_loaders.clone(),
+            skipped: SkippedFiles::default(),
+        }
+    }
+}
+
+impl Default for LoaderOptions {
+    fn default() -> Self {
+        Self {
+            bom_columns: None,
+            headings_only: false,
+            max_rows: DEFAULT_MAX_ROWS,
+            concurrency: default_concurrency(),
+            tool_timeout: Duration::from_secs(DEFAULT_LOADER_TIMEOUT),
+            tool_attempts: DEFAULT_LOADER_ATTEMPTS,
+            max_output_bytes: DEFAULT_LOADER_MAX_OUTPUT,
+            max_download_bytes: DEFAULT_MAX_DOWNLOAD_SIZE,
+            pdf_ocr: false,
+            max_document_chars: DEFAULT_MAX_DOCUMENT_SIZE,
+            registry: LoaderRegistry::default(),
+            transforms: DocumentTransforms::default(),
+            abort_signal: None,
+            archive_suffixes: HashMap::new(),
+            peek_text_files: false,
+            detect_language: true,
+            split_code: false,
+            max_depth: None,
+            split_overlap: 0,
+            disabled_loaders: vec![],
+            skipped: SkippedFiles::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    UnsupportedExtension,
+    MissingTool,
+    ParseError,
+    TooLarge,
+    Binary,
+    DisabledLoader,
+    Unreadable,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self {
+            SkipReason::UnsupportedExtension => "unsupported_extension",
+            SkipReason::MissingTool => "missing_tool",
+            SkipReason::ParseError => "parse_error",
+            SkipReason::TooLarge => "too_large",
+            SkipReason::Binary => "binary",
+            SkipReason::DisabledLoader => "disabled_loader",
+            SkipReason::Unreadable => "unreadable",
+        };
+        write!(f, "{value}")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedFile {
+    pub path: String,
+    pub reason: SkipReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
 }
 
-pub fn load(path: &str, extension: &str) -> Result<Vec<RagDocument>> {
+/// The files left out while listing or loading, shared by every clone of a `LoaderOptions`.
+#[derive(Debug, Clone, Default)]
+pub struct SkippedFiles(Arc<parking_lot::Mutex<Vec<SkippedFile>>>);
+
+impl SkippedFiles {
+    pub fn push(&self, path: &str, reason: SkipReason, detail: Option<String>) {
+        debug!("skip '{path}', {reason}");
+        self.0.lock().push(SkippedFile {
+            path: path.to_string(),
+            reason,
+            detail,
+        });
+    }
+
+    /// Records a file that failed to load under the reason carried by `err`.
+    pub fn push_error(&self, path: &str, err: &anyhow::Error) {
+        // A skip error says why on its own, the context around it only repeats the path.
+        let (reason, detail) = match err.downcast_ref::<SkipError>() {
+            Some(v) => (v.reason, v.to_string()),
+            None => (SkipReason::ParseError, format!("{err:#}")),
+        };
+        self.push(path, reason, Some(detail));
+    }
+
+    pub fn take(&self) -> Vec<SkippedFile> {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
+
+/// An error that means the file can't be indexed as is, rather than a failure of the run.
+#[derive(Debug)]
+pub struct SkipError {
+    pub reason: SkipReason,
+    message: String,
+}
+
+impl fmt::Display for SkipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SkipError {}
+
+pub fn skip_error(reason: SkipReason, message: String) -> anyhow::Error {
+    SkipError { reason, message }.into()
+}
+
+/// Fails with a cancellation error once `abort_signal` has been triggered.
+pub fn check_aborted(abort_signal: Option<&AbortSignal>) -> Result<()> {
+    match abort_signal {
+        Some(v) if v.aborted() => bail!("Aborted!"),
+        _ => Ok(()),
+    }
+}
+
+fn default_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|v| v.get())
+        .unwrap_or(1)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BomColumns {
+    pub part_number: String,
+    pub quantity: Option<String>,
+    pub unit: Option<String>,
+}
+
+pub fn load(path: &str, extension: &str, options: &LoaderOptions) -> Result<Vec<RagDocument>> {
+    let documents = load_file(path, extension, options, true)?;
+    Ok(finish_documents(path, documents, options))
+}
+
+/// Loads `data` as the contents of a file at `path`, which only names the documents.
+/// The formats parsed in-process never touch the filesystem.
+pub fn load_from_bytes(
+    path: &str,
+    extension: &str,
+    data: &[u8],
+    options: &LoaderOptions,
+) -> Result<Vec<RagDocument>> {
+    let documents = load_bytes(path, extension, data, options, true)?;
+    Ok(finish_documents(path, documents, options))
+}
+
+fn finish_documents(
+    path: &str,
+    documents: Vec<RagDocument>,
+    options: &LoaderOptions,
+) -> Vec<RagDocument> {
+    let mut documents =
+        split_large_documents(documents, options.max_document_chars, options.split_overlap);
+    for document in documents.iter_mut() {
+        document
+            .metadata
+            .entry("source".into())
+            .or_insert_with(|| path.to_string());
+        let file_name = document.metadata.get("entry").map_or(path, |v| v.as_str());
+        let file_name = match is_url(file_name) {
+            true => None,
+            false => Path::new(file_name)
+                .file_name()
+                .map(|v| v.to_string_lossy().to_string()),
+        };
+        let title = file_name.unwrap_or_else(|| path.to_string());
+        document.metadata.entry("title".into()).or_insert(title);
+    }
+    documents
+}
+
+/// Records `title` in the `title` metadata of documents that don't have one yet.
+fn set_title(documents: &mut [RagDocument], title: Option<String>) {
+    let Some(title) = title
+        .map(|v| v.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|v| !v.is_empty())
+    else {
+        return;
+    };
+    for document in documents.iter_mut() {
+        document
+            .metadata
+            .entry("title".into())
+            .or_insert_with(|| title.clone());
+    }
+}
+
+fn load_file(
+    path: &str,
+    extension: &str,
+    options: &LoaderOptions,
+    allow_archive: bool,
+) -> Result<Vec<RagDocument>> {
+    let extension = match is_known_extension(extension, options) {
+        true => extension,
+        false => match sniff_extension(path)? {
+            Some(v) => v,
+            None => {
+                return Err(skip_error(
+                    SkipReason::Binary,
+                    format!("'{path}' is an unrecognized binary file"),
+                ))
+            }
+        },
+    };
     match extension {
-        "docx" | "epub" => load_with_pandoc(path),
-        "pdf" => load_with_pdftotext(path),
-        _ => load_plain(path),
+        "gz" => load_gzip(path, options),
+        "zip" if allow_archive => {
+            let file =
+                std::fs::File::open(path).with_context(|| format!("Failed to open '{path}'"))?;
+            load_zip(path, file, options)
+        }
+        "zip" => {
+            debug!("skip nested archive '{path}'");
+            Ok(vec![])
+        }
+        _ => load_documents(path, extension, options),
     }
 }
 
-fn load_plain(path: &str) -> Result<Vec<RagDocument>> {
-    let contents = read_to_string(path)?;
-    let document = RagDocument::new(contents);
+fn is_known_extension(extension: &str, options: &LoaderOptions) -> bool {
+    matches!(extension, "gz" | "zip")
+        || PLAIN_EXTENSIONS.contains(&extension)
+        || CODE_EXTENSIONS.contains(&extension)
+        || options.registry.get(extension).is_some()
+}
+
+/// Guesses the extension of a file from its leading bytes. Returns `txt` for
+/// anything that reads as UTF-8 and `None` for unrecognized binary data.
+fn sniff_extension(path: &str) -> Result<Option<&'static str>> {
+    let mut head = vec![];
+    std::fs::File::open(path)
+        .with_context(|| format!("Failed to open '{path}'"))?
+        .take(64 * 1024)
+        .read_to_end(&mut head)?;
+    Ok(sniff_bytes(&head))
+}
+
+fn sniff_bytes(head: &[u8]) -> Option<&'static str> {
+    let contains = |needle: &[u8]| head.windows(needle.len()).any(|v| v == needle);
+    if head.starts_with(b"%PDF") {
+        return Some("pdf");
+    }
+    if head.starts_with(b"\x1f\x8b") {
+        return Some("gz");
+    }
+    if head.starts_with(b"{\\rtf") {
+        return Some("rtf");
+    }
+    if head.starts_with(b"PK\x03\x04") {
+        let extension = if contains(b"application/epub+zip") {
+            "epub"
+        } else if contains(b"application/vnd.oasis.opendocument.text") {
+            "odt"
+        } else if contains(b"application/vnd.oasis.opendocument.presentation") {
+            "odp"
+        } else if contains(b"word/") {
+            "docx"
+        } else if contains(b"ppt/") {
+            "pptx"
+        } else {
+            "zip"
+        };
+        return Some(extension);
+    }
+    let is_utf8 = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // The head may end in the middle of a character.
+        Err(err) => err.error_len().is_none(),
+    };
+    match is_utf8 && !head.contains(&0) {
+        true => Some("txt"),
+        false => None,
+    }
+}
+
+/// Decompresses `path` and loads it by the extension before `.gz`, e.g. `report.md.gz` as markdown.
+fn load_gzip(path: &str, options: &LoaderOptions) -> Result<Vec<RagDocument>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open '{path}'"))?;
+    let data = decompress_gzip(path, file, options.max_output_bytes)?;
+    load_bytes(path, &gzip_inner_extension(path), &data, options, false)
+}
+
+/// The extension of the file compressed in `path`, e.g. `csv` for `rows.csv.gz`.
+fn gzip_inner_extension(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .and_then(|v| Path::new(v).extension())
+        .map(|v| v.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| "txt".into())
+}
+
+/// Loads content held in memory that came from `path`, the in-memory counterpart of
+/// `load_file`. Builtin converters get it piped to stdin, and custom loaders, which
+/// only take a path, read it from a temporary file.
+fn load_bytes(
+    path: &str,
+    extension: &str,
+    data: &[u8],
+    options: &LoaderOptions,
+    allow_archive: bool,
+) -> Result<Vec<RagDocument>> {
+    let extension = match is_known_extension(extension, options) {
+        true => extension,
+        false => match sniff_bytes(&data[..data.len().min(64 * 1024)]) {
+            Some(v) => v,
+            None => {
+                return Err(skip_error(
+                    SkipReason::Binary,
+                    format!("'{path}' is an unrecognized binary file"),
+                ))
+            }
+        },
+    };
+    match extension {
+        "gz" => {
+            let data = decompress_gzip(path, data, options.max_output_bytes)?;
+            load_bytes(path, &gzip_inner_extension(path), &data, options, false)
+        }
+        "zip" if allow_archive => load_zip(path, std::io::Cursor::new(data), options),
+        "zip" => {
+            debug!("skip nested archive '{path}'");
+            Ok(vec![])
+        }
+        _ => match options.registry.get(extension) {
+            Some(Loader::Builtin(name, loader)) => {
+                check_loader_enabled(name, path, options)?;
+                let input = LoaderInput {
+                    path,
+                    extension,
+                    data: Some(data),
+                };
+                loader(&input, options)
+            }
+            Some(Loader::Custom(loader)) => {
+                with_temp_file(path, extension, data, |temp_path| loader(temp_path))
+            }
+            None => parse_documents(path, extension, data, options),
+        },
+    }
+}
+
+fn with_temp_file<T>(
+    path: &str,
+    extension: &str,
+    data: &[u8],
+    f: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+    let temp_path = std::env::temp_dir().join(format!("aichat-{}.{extension}", sha256(path)));
+    std::fs::write(&temp_path, data)?;
+    let ret = f(&temp_path.display().to_string());
+    let _ = std::fs::remove_file(&temp_path);
+    ret
+}
+
+/// Loads every entry of a zip archive by its own extension, keeping the entry
+/// name in the `entry` metadata. Nested archives are skipped.
+fn load_zip<R: Read + std::io::Seek>(
+    path: &str,
+    reader: R,
+    options: &LoaderOptions,
+) -> Result<Vec<RagDocument>> {
+    let mut archive =
+        zip::ZipArchive::new(reader).with_context(|| format!("Failed to read archive '{path}'"))?;
+    if archive.len() > MAX_ARCHIVE_ENTRIES {
+        return Err(skip_error(
+            SkipReason::TooLarge,
+            format!("'{path}' has more than {MAX_ARCHIVE_ENTRIES} entries"),
+        ));
+    }
+    let suffixes = options.archive_suffixes.get(path);
+    let max_bytes = options.max_output_bytes;
+    let mut total_bytes = 0;
+    let mut documents = vec![];
+    for index in 0..archive.len() {
+        check_aborted(options.abort_signal.as_ref())?;
+        let mut entry = archive.by_index(index)?;
+        let name = match entry.enclosed_name() {
+            Some(v) if entry.is_file() => v,
+            _ => continue,
+        };
+        if !is_valid_extension(suffixes, &name) {
+            continue;
+        }
+        let extension = name
+            .extension()
+            .map(|v| v.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let mut data = vec![];
+        (&mut entry)
+            .take((max_bytes - total_bytes) as u64 + 1)
+            .read_to_end(&mut data)
+            .with_context(|| format!("Failed to extract '{}' from '{path}'", name.display()))?;
+        total_bytes += data.len();
+        if total_bytes > max_bytes {
+            return Err(skip_error(
+                SkipReason::TooLarge,
+                format!("'{path}' extracts to more than {max_bytes} bytes"),
+            ));
+        }
+        let entry_name = name.display().to_string();
+        let ret = load_bytes(
+            &format!("{path}/{entry_name}"),
+            &extension,
+            &data,
+            options,
+            false,
+        );
+        let ret = match ret {
+            Err(err) if err.downcast_ref::<SkipError>().is_some() => {
+                options
+                    .skipped
+                    .push_error(&format!("{path}/{entry_name}"), &err);
+                continue;
+            }
+            ret => ret,
+        };
+        for mut document in
+            ret.with_context(|| format!("Failed to load '{entry_name}' from '{path}'"))?
+        {
+            document.metadata.insert("entry".into(), entry_name.clone());
+            documents.push(document);
+        }
+    }
+    Ok(documents)
+}
+
+fn decompress_gzip(path: &str, reader: impl Read, max_bytes: usize) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    flate2::read::GzDecoder::new(reader)
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to decompress '{path}'"))?;
+    if data.len() > max_bytes {
+        return Err(skip_error(
+            SkipReason::TooLarge,
+            format!("'{path}' decompresses to more than {max_bytes} bytes"),
+        ));
+    }
+    Ok(data)
+}
+
+fn load_documents(
+    path: &str,
+    extension: &str,
+    options: &LoaderOptions,
+) -> Result<Vec<RagDocument>> {
+    match options.registry.get(extension) {
+        Some(Loader::Builtin(name, loader)) => {
+            check_loader_enabled(name, path, options)?;
+            let input = LoaderInput {
+                path,
+                extension,
+                data: None,
+            };
+            return loader(&input, options);
+        }
+        Some(Loader::Custom(loader)) => return loader(path),
+        None => {}
+    }
+    let data = std::fs::read(path).with_context(|| format!("Failed to read '{path}'"))?;
+    parse_documents(path, extension, &data, options)
+}
+
+/// Runs the loaders that parse `data` in-process, by `extension`.
+fn parse_documents(
+    path: &str,
+    extension: &str,
+    data: &[u8],
+    options: &LoaderOptions,
+) -> Result<Vec<RagDocument>> {
+    if matches!(extension, "eml" | "mbox") {
+        return load_email(path, data, extension);
+    }
+    let contents = decode_file_text(path, data)?;
+    if options.headings_only {
+        match extension {
+            "md" | "mkd" => return load_headings(path, &contents, parse_markdown_headings),
+            "htm" | "html" => return load_headings(path, &contents, parse_html_headings),
+            _ => {}
+        }
+    }
+    match extension {
+        "csv" | "tsv" => load_structured(path, &contents, extension, options),
+        "json" | "jsonl" => load_json(path, &contents, extension),
+        "md" | "mkd" => load_markdown(&contents, options.split_overlap),
+        "htm" | "html" => load_html(&contents),
+        _ if options.split_code && code_definition_re(extension).is_some() => {
+            load_code(path, &contents, extension)
+        }
+        _ => load_plain(&contents),
+    }
+}
+
+/// Names the extractor `load` uses for `extension`, along with the reason it
+/// can't run on this machine, if any.
+pub fn detect_loader(extension: &str, options: &LoaderOptions) -> (&'static str, Option<String>) {
+    let name = match options.registry.get(extension) {
+        Some(Loader::Builtin(name, _)) => name,
+        Some(Loader::Custom(_)) => return ("custom", None),
+        None => match extension {
+            "md" | "mkd" | "htm" | "html" if options.headings_only => return ("headings", None),
+            "csv" | "tsv" => return ("structured", None),
+            "md" | "mkd" => return ("markdown", None),
+            "htm" | "html" => return ("html", None),
+            "eml" | "mbox" => return ("email", None),
+            _ if options.split_code && code_definition_re(extension).is_some() => {
+                return ("code", None)
+            }
+            "gz" => return ("gzip", None),
+            "zip" => return ("zip", None),
+            _ => return ("plain", None),
+        },
+    };
+    let reason = disabled_loader(name, options).or_else(|| missing_tool(name, cached_tool));
+    (name, reason)
+}
+
+/// Fails when `rag_disabled_loaders` forbids running the external tool `name`.
+fn check_loader_enabled(name: &str, path: &str, options: &LoaderOptions) -> Result<()> {
+    match disabled_loader(name, options) {
+        Some(reason) => Err(skip_error(
+            SkipReason::DisabledLoader,
+            format!("Cannot load '{path}', {reason}"),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn disabled_loader(name: &str, options: &LoaderOptions) -> Option<String> {
+    options
+        .disabled_loaders
+        .iter()
+        .any(|v| v == name)
+        .then(|| format!("the {name} loader is disabled by `rag_disabled_loaders`"))
+}
+
+const PLAIN_EXTENSIONS: [&str; 11] = [
+    "txt", "md", "mkd", "htm", "html", "csv", "tsv", "json", "jsonl", "eml", "mbox",
+];
+
+const CODE_EXTENSIONS: [&str; 14] = [
+    "rs", "py", "pyi", "js", "mjs", "cjs", "jsx", "ts", "mts", "cts", "tsx", "go", "java", "rb",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SupportedFormat {
+    pub extension: String,
+    pub loader: String,
+    pub available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing: Option<String>,
+}
+
+/// Reports the formats that can be loaded, checking the external tools again
+/// instead of relying on the values cached at startup.
+pub fn supported_formats(options: &LoaderOptions) -> Vec<SupportedFormat> {
+    let mut formats: Vec<SupportedFormat> = PLAIN_EXTENSIONS
+        .iter()
+        .chain(CODE_EXTENSIONS.iter())
+        .map(|extension| SupportedFormat {
+            extension: extension.to_string(),
+            loader: detect_loader(extension, options).0.to_string(),
+            available: true,
+            missing: None,
+        })
+        .filter(|v| v.loader != "custom")
+        .collect();
+    for (extension, loader) in &options.registry.loaders {
+        let (loader, missing) = match loader {
+            Loader::Builtin(name, _) => (
+                name.to_string(),
+                disabled_loader(name, options).or_else(|| missing_tool(name, which_tool)),
+            ),
+            Loader::Custom(_) => ("custom".to_string(), None),
+        };
+        formats.retain(|v| &v.extension != extension);
+        formats.push(SupportedFormat {
+            extension: extension.clone(),
+            loader,
+            available: missing.is_none(),
+            missing,
+        });
+    }
+    formats
+}
+
+fn which_tool(name: &str) -> bool {
+    which(name).is_ok()
+}
+
+fn cached_tool(name: &str) -> bool {
+    match name {
+        "pandoc" => *EXIST_PANDOC,
+        "pdftotext" => *EXIST_PDFTOTEXT,
+        _ => true,
+    }
+}
+
+fn missing_tool(name: &str, exists: fn(&str) -> bool) -> Option<String> {
+    match name {
+        "pandoc" if !exists(name) => Some("pandoc is not installed".into()),
+        "pdftotext" if !exists(name) => {
+            Some("pdftotext (part of the poppler package) is not installed".into())
+        }
+        _ => None,
+    }
+}
+
+/// Downloads `url` to a temporary file and loads it with the extractor matching
+/// its content type or URL extension. Returns the extension used.
+pub async fn load_url(url: &str, options: &LoaderOptions) -> Result<(String, Vec<RagDocument>)> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(10))
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(options.tool_timeout)
+        .build()
+        .with_context(|| "Failed to build client")?;
+    let mut res = client.get(url).send().await?.error_for_status()?;
+    let max_bytes = options.max_download_bytes;
+    if res.content_length().unwrap_or_default() > max_bytes as u64 {
+        return Err(skip_error(
+            SkipReason::TooLarge,
+            format!("'{url}' is larger than {max_bytes} bytes"),
+        ));
+    }
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let extension = extension_from_content_type(&content_type)
+        .map(|v| v.to_string())
+        .or_else(|| {
+            let path = res.url().path();
+            Path::new(path)
+                .extension()
+                .map(|v| v.to_string_lossy().to_lowercase())
+        })
+        .unwrap_or_else(|| "txt".into());
+    let mut data = vec![];
+    while let Some(chunk) = res.chunk().await? {
+        check_aborted(options.abort_signal.as_ref())?;
+        data.extend_from_slice(&chunk);
+        if data.len() > max_bytes {
+            return Err(skip_error(
+                SkipReason::TooLarge,
+                format!("'{url}' is larger than {max_bytes} bytes"),
+            ));
+        }
+    }
+    let (options, url_str) = (options.clone(), url.to_string());
+    let ext = extension.clone();
+    let ret = tokio::task::spawn_blocking(move || load_from_bytes(&url_str, &ext, &data, &options))
+        .await?;
+    ret.map(|documents| (extension, documents))
+}
+
+/// Splits documents longer than `max_chars` at paragraph, then line boundaries,
+/// tagging each piece with a `part` index. Each piece after the first starts with
+/// the last `overlap` characters of the one before, at most half of `max_chars`.
+fn split_large_documents(
+    documents: Vec<RagDocument>,
+    max_chars: usize,
+    overlap: usize,
+) -> Vec<RagDocument> {
+    if max_chars == 0 {
+        return documents;
+    }
+    let overlap = overlap.min(max_chars / 2);
+    let mut output = vec![];
+    for document in documents {
+        if document.page_content.chars().count() <= max_chars {
+            output.push(document);
+            continue;
+        }
+        let parts = add_overlaps(
+            split_text(&document.page_content, max_chars - overlap),
+            overlap,
+        );
+        for (index, part) in parts.into_iter().enumerate() {
+            let mut metadata = document.metadata.clone();
+            metadata.insert("part".into(), (index + 1).to_string());
+            output.push(RagDocument::new(part).with_metadata(metadata));
+        }
+    }
+    output
+}
+
+/// Prefixes each text after the first with the last `overlap` characters of the previous one.
+fn add_overlaps(texts: Vec<String>, overlap: usize) -> Vec<String> {
+    if overlap == 0 {
+        return texts;
+    }
+    let mut output: Vec<String> = Vec::with_capacity(texts.len());
+    let mut previous: Option<String> = None;
+    for text in texts {
+        match &previous {
+            Some(previous) => {
+                let start = previous
+                    .char_indices()
+                    .rev()
+                    .nth(overlap - 1)
+                    .map(|(i, _)| i)
+                    .unwrap_or_default();
+                output.push(format!("{}{text}", &previous[start..]));
+            }
+            None => output.push(text.clone()),
+        }
+        previous = Some(text);
+    }
+    output
+}
+
+fn split_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut current_len = 0;
+    for piece in text.split_inclusive("\n\n").flat_map(|paragraph| {
+        if paragraph.chars().count() > max_chars {
+            paragraph.split_inclusive('\n').collect::<Vec<_>>()
+        } else {
+            vec![paragraph]
+        }
+    }) {
+        let mut piece = piece;
+        loop {
+            let piece_len = piece.chars().count();
+            if current_len + piece_len <= max_chars {
+                current.push_str(piece);
+                current_len += piece_len;
+                break;
+            }
+            if current_len > 0 {
+                parts.push(std::mem::take(&mut current));
+                current_len = 0;
+                continue;
+            }
+            let end = piece
+                .char_indices()
+                .nth(max_chars)
+                .map(|(i, _)| i)
+                .unwrap_or(piece.len());
+            parts.push(piece[..end].to_string());
+            piece = &piece[end..];
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+fn extension_from_content_type(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_lowercase();
+    let extension = match mime.as_str() {
+        "application/pdf" => "pdf",
+        "text/html" | "application/xhtml+xml" => "html",
+        "text/markdown" | "text/x-markdown" => "md",
+        "text/csv" => "csv",
+        "text/tab-separated-values" => "tsv",
+        "text/plain" => "txt",
+        "application/json" => "json",
+        "application/epub+zip" => "epub",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => "docx",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+fn load_plain(contents: &str) -> Result<Vec<RagDocument>> {
+    let document = match parse_frontmatter(contents) {
+        Some((metadata, body)) => RagDocument::new(body).with_metadata(metadata),
+        None => RagDocument::new(contents),
+    };
     Ok(vec![document])
 }
 
-fn load_with_pdftotext(path: &str) -> Result<Vec<RagDocument>> {
-    if !*EXIST_PDFTOTEXT {
-        bail!("Need to install pdftotext (part of the poppler package) to load the file.")
+/// Splits a markdown file into one document per H1/H2 section, recording the
+/// heading path (e.g. "Guide > Installation") in the `heading` metadata and the
+/// first H1 as the `title`. Sections overlap by `overlap` characters.
+fn load_markdown(contents: &str, overlap: usize) -> Result<Vec<RagDocument>> {
+    let (metadata, body) = match parse_frontmatter(contents) {
+        Some((metadata, body)) => (metadata, body),
+        None => (RagMetadata::new(), contents),
+    };
+    let (headings, texts): (Vec<_>, Vec<_>) = split_markdown_sections(body).into_iter().unzip();
+    let mut documents: Vec<RagDocument> = headings
+        .into_iter()
+        .zip(add_overlaps(texts, overlap))
+        .map(|(heading, text)| {
+            let mut metadata = metadata.clone();
+            if let Some(heading) = heading {
+                metadata.insert("heading".into(), heading);
+            }
+            RagDocument::new(text).with_metadata(metadata)
+        })
+        .collect();
+    let title = parse_markdown_headings(body)
+        .into_iter()
+        .find(|(level, _, _)| *level == 1)
+        .map(|(_, _, title)| title);
+    set_title(&mut documents, title);
+    Ok(documents)
+}
+
+/// Splits a source file at its top-level definitions, keeping the comments,
+/// attributes and decorators right above each one. Every document records
+/// the `source` path, the starting `line` and, past the file header, the
+/// defined `symbol`.
+fn load_code(path: &str, contents: &str, extension: &str) -> Result<Vec<RagDocument>> {
+    let Some(re) = code_definition_re(extension) else {
+        return load_plain(contents);
+    };
+    let documents = split_code_definitions(contents, re)
+        .into_iter()
+        .map(|(line, symbol, text)| {
+            let mut metadata = RagMetadata::new();
+            metadata.insert("source".into(), path.to_string());
+            metadata.insert("line".into(), line.to_string());
+            if let Some(symbol) = symbol {
+                metadata.insert("symbol".into(), symbol);
+            }
+            RagDocument::new(text).with_metadata(metadata)
+        })
+        .collect();
+    Ok(documents)
+}
+
+fn code_definition_re(extension: &str) -> Option<&'static Regex> {
+    let re: &Regex = match extension {
+        "rs" => &RUST_DEFINITION_RE,
+        "py" | "pyi" => &PYTHON_DEFINITION_RE,
+        "js" | "mjs" | "cjs" | "jsx" | "ts" | "mts" | "cts" | "tsx" => &JS_DEFINITION_RE,
+        "go" => &GO_DEFINITION_RE,
+        "java" => &JAVA_DEFINITION_RE,
+        "rb" => &RUBY_DEFINITION_RE,
+        _ => return None,
+    };
+    Some(re)
+}
+
+/// Returns `(line, symbol, text)` per section; `line` is 1-based.
+fn split_code_definitions(contents: &str, re: &Regex) -> Vec<(usize, Option<String>, String)> {
+    let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+    let mut starts: Vec<(usize, String)> = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        let Ok(Some(captures)) = re.captures(line.trim_end()) else {
+            continue;
+        };
+        let Some(name) = captures.name("name").or_else(|| captures.name("name2")) else {
+            continue;
+        };
+        let mut start = index;
+        while start > 0 && is_code_preamble(lines[start - 1]) {
+            start -= 1;
+        }
+        if starts.last().is_some_and(|(v, _)| *v >= start) {
+            continue;
+        }
+        starts.push((start, name.as_str().trim().to_string()));
+    }
+    let mut sections = vec![];
+    let mut push = |line: usize, symbol: Option<String>, lines: &[&str]| {
+        let text = lines.concat();
+        if !text.trim().is_empty() {
+            sections.push((line + 1, symbol, text));
+        }
+    };
+    let mut current = (0, None);
+    for (start, symbol) in starts {
+        push(current.0, current.1.take(), &lines[current.0..start]);
+        current = (start, Some(symbol));
+    }
+    push(current.0, current.1, &lines[current.0..]);
+    sections
+}
+
+fn is_code_preamble(line: &str) -> bool {
+    ["#", "//", "/*", "*", "@"]
+        .iter()
+        .any(|prefix| line.trim_start().starts_with(prefix))
+        && !line.trim().is_empty()
+}
+
+fn load_html(contents: &str) -> Result<Vec<RagDocument>> {
+    let mut documents = load_plain(contents)?;
+    let title = documents.first().and_then(|v| html_title(&v.page_content));
+    set_title(&mut documents, title);
+    Ok(documents)
+}
+
+fn html_title(html: &str) -> Option<String> {
+    let captures = HTML_TITLE_RE.captures(html).ok()??;
+    Some(strip_html(captures.get(1)?.as_str()))
+}
+
+fn split_markdown_sections(contents: &str) -> Vec<(Option<String>, String)> {
+    let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+    let mut sections = vec![];
+    let mut start = 0;
+    let mut heading: Option<String> = None;
+    let mut title: Option<String> = None;
+    let mut push = |heading: Option<String>, lines: &[&str]| {
+        let text = lines.concat();
+        if !text.trim().is_empty() {
+            sections.push((heading, text));
+        }
+    };
+    for (level, line, name) in parse_markdown_headings(contents) {
+        if level > 2 {
+            continue;
+        }
+        push(heading.take(), &lines[start..line - 1]);
+        start = line - 1;
+        heading = match (level, &title) {
+            (1, _) => {
+                title = Some(name.clone());
+                Some(name)
+            }
+            (_, Some(title)) => Some(format!("{title} > {name}")),
+            (_, None) => Some(name),
+        };
+    }
+    push(heading, &lines[start..]);
+    if sections.is_empty() && !contents.is_empty() {
+        sections.push((None, contents.to_string()));
+    }
+    sections
+}
+
+/// Decodes the contents of the text file at `path`, decoding invalid UTF-8 as Latin-1.
+/// Fails with a skip error for binary files. A leading BOM is dropped and line endings
+/// are normalized to `\n`.
+fn decode_file_text(path: &str, data: &[u8]) -> Result<String> {
+    let text = match decode_text(data.to_vec()) {
+        Ok(text) => text,
+        Err(TextError::Binary) => {
+            return Err(skip_error(
+                SkipReason::Binary,
+                format!("'{path}' is a binary file"),
+            ))
+        }
+        Err(TextError::NotUtf8(text)) => {
+            eprintln!(
+                "{}",
+                warning_text(&format!(
+                    "WARNING: '{path}' is not valid UTF-8, decoded it as Latin-1."
+                ))
+            );
+            text
+        }
+    };
+    Ok(normalize_line_endings(&text))
+}
+
+fn normalize_line_endings(text: &str) -> String {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+#[derive(Debug, PartialEq)]
+enum TextError {
+    Binary,
+    NotUtf8(String),
+}
+
+fn decode_text(data: Vec<u8>) -> std::result::Result<String, TextError> {
+    let head = &data[..data.len().min(8192)];
+    if head.contains(&0) {
+        return Err(TextError::Binary);
+    }
+    match String::from_utf8(data) {
+        Ok(text) => Ok(text),
+        Err(err) => {
+            let text: String = err.into_bytes().iter().map(|&b| b as char).collect();
+            let controls = text
+                .chars()
+                .filter(|c| c.is_control() && !c.is_whitespace())
+                .count();
+            if controls * 10 > text.chars().count() {
+                return Err(TextError::Binary);
+            }
+            Err(TextError::NotUtf8(text))
+        }
+    }
+}
+
+fn parse_frontmatter(contents: &str) -> Option<(RagMetadata, &str)> {
+    let rest = contents
+        .strip_prefix("---\n")
+        .or_else(|| contents.strip_prefix("---\r\n"))?;
+    let mut offset = 0;
+    let (yaml, body) = loop {
+        let line_end = rest[offset..].find('\n').map(|v| offset + v + 1);
+        let line = &rest[offset..line_end.unwrap_or(rest.len())];
+        if line.trim_end() == "---" {
+            break (&rest[..offset], &rest[line_end.unwrap_or(rest.len())..]);
+        }
+        offset = line_end?;
+    };
+    let value: serde_yaml::Mapping = serde_yaml::from_str(yaml).ok()?;
+    let mut metadata = RagMetadata::new();
+    for (key, value) in value {
+        let key = match key {
+            serde_yaml::Value::String(v) => v,
+            _ => continue,
+        };
+        let value = match value {
+            serde_yaml::Value::Null => continue,
+            serde_yaml::Value::String(v) => v,
+            serde_yaml::Value::Sequence(items) => items
+                .iter()
+                .filter_map(|v| match v {
+                    serde_yaml::Value::String(v) => Some(v.clone()),
+                    serde_yaml::Value::Null => None,
+                    v => serde_yaml::to_string(v).ok().map(|v| v.trim().to_string()),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            v => serde_yaml::to_string(&v).ok()?.trim().to_string(),
+        };
+        metadata.insert(key, value);
+    }
+    Some((metadata, body))
+}
+
+fn load_structured(
+    path: &str,
+    contents: &str,
+    extension: &str,
+    options: &LoaderOptions,
+) -> Result<Vec<RagDocument>> {
+    let delimiter = if extension == "tsv" { '\t' } else { ',' };
+    let mut rows = parse_delimited(contents, delimiter, options.max_rows.saturating_add(1));
+    if rows.len() > options.max_rows {
+        warn!(
+            "only the first {} rows of '{path}' are loaded",
+            options.max_rows
+        );
+    }
+    if rows.is_empty() {
+        return Ok(vec![]);
+    }
+    let header: Vec<String> = rows
+        .remove(0)
+        .into_iter()
+        .map(|v| v.trim().to_string())
+        .collect();
+    if header.iter().all(|v| v.is_empty()) {
+        return load_plain(contents);
+    }
+    if let Some(columns) = &options.bom_columns {
+        if let Some(documents) = load_bom(&header, &rows, columns) {
+            return Ok(documents);
+        }
+    }
+    let documents = rows
+        .iter()
+        .enumerate()
+        .map(|(row_index, row)| {
+            let text = row
+                .iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    let name = header.get(index).map(|v| v.as_str()).unwrap_or_default();
+                    format!("{name}: {}", value.trim())
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let mut metadata = RagMetadata::new();
+            metadata.insert("row".into(), (row_index + 1).to_string());
+            RagDocument::new(text).with_metadata(metadata)
+        })
+        .collect();
+    Ok(documents)
+}
+
+/// Loads an `.eml` message, or every message of an `.mbox`, as one document each.
+/// Only the text body is kept; subject, sender, recipients and date go into metadata.
+fn load_email(path: &str, data: &[u8], extension: &str) -> Result<Vec<RagDocument>> {
+    let messages = match extension {
+        "mbox" => split_mbox(data),
+        _ => vec![data.to_vec()],
+    };
+    let mut documents = vec![];
+    for (index, message) in messages.iter().enumerate() {
+        match parse_email(message) {
+            Ok(Some(document)) => documents.push(document),
+            Ok(None) => {}
+            Err(err) => warn!("skip message {} of '{path}', {err}", index + 1),
+        }
+    }
+    Ok(documents)
+}
+
+/// Splits an mbox at its `From ` separator lines, undoing `>From ` quoting.
+fn split_mbox(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut current: Option<Vec<u8>> = None;
+    for line in data.split_inclusive(|v| *v == b'\n') {
+        if line.starts_with(b"From ") {
+            messages.extend(current.replace(vec![]));
+            continue;
+        }
+        let Some(message) = current.as_mut() else {
+            continue;
+        };
+        let quotes = line.iter().take_while(|v| **v == b'>').count();
+        match quotes > 0 && line[quotes..].starts_with(b"From ") {
+            true => message.extend_from_slice(&line[1..]),
+            false => message.extend_from_slice(line),
+        }
+    }
+    messages.extend(current);
+    messages
+}
+
+fn parse_email(data: &[u8]) -> Result<Option<RagDocument>> {
+    use mailparse::MailHeaderMap;
+    let mail = mailparse::parse_mail(data)?;
+    let mut metadata = RagMetadata::new();
+    for (header, key) in [
+        ("Subject", "subject"),
+        ("From", "from"),
+        ("To", "to"),
+        ("Date", "date"),
+    ] {
+        if let Some(value) = mail.headers.get_first_value(header) {
+            if !value.trim().is_empty() {
+                metadata.insert(key.into(), value.trim().to_string());
+            }
+        }
+    }
+    let body = match find_email_body(&mail, "text/plain")? {
+        Some(body) => body,
+        None => match find_email_body(&mail, "text/html")? {
+            Some(html) => strip_html(&html),
+            None => String::new(),
+        },
+    };
+    let body = normalize_text(&body);
+    if body.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(RagDocument::new(body).with_metadata(metadata)))
+}
+
+/// Finds the first non-attachment part of `mimetype`, depth first.
+fn find_email_body(mail: &mailparse::ParsedMail, mimetype: &str) -> Result<Option<String>> {
+    if mail.get_content_disposition().disposition == mailparse::DispositionType::Attachment {
+        return Ok(None);
+    }
+    if mail.subparts.is_empty() {
+        return match mail.ctype.mimetype.eq_ignore_ascii_case(mimetype) {
+            true => Ok(Some(mail.get_body()?)),
+            false => Ok(None),
+        };
+    }
+    for part in &mail.subparts {
+        if let Some(body) = find_email_body(part, mimetype)? {
+            return Ok(Some(body));
+        }
+    }
+    Ok(None)
+}
+
+fn strip_html(html: &str) -> String {
+    let text = HTML_SCRIPT_RE.replace_all(html, "");
+    let text = HTML_TAG_RE.replace_all(&text, "");
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn load_json(path: &str, contents: &str, extension: &str) -> Result<Vec<RagDocument>> {
+    match parse_json_records(contents, extension == "jsonl") {
+        Ok(records) => Ok(records
+            .into_iter()
+            .enumerate()
+            .map(|(index, record)| {
+                let mut metadata = RagMetadata::new();
+                metadata.insert("row".into(), (index + 1).to_string());
+                RagDocument::new(flatten_json(&record)).with_metadata(metadata)
+            })
+            .collect()),
+        Err(err) => {
+            eprintln!(
+                "{}",
+                warning_text(&format!(
+                    "WARNING: failed to parse '{path}' as JSON, loading it as plain text, {err}"
+                ))
+            );
+            load_plain(contents)
+        }
+    }
+}
+
+fn parse_json_records(contents: &str, lines: bool) -> Result<Vec<serde_json::Value>> {
+    if lines {
+        let mut records = vec![];
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(line)
+                .with_context(|| format!("invalid JSON at line {}", index + 1))?;
+            records.push(record);
+        }
+        return Ok(records);
+    }
+    let value: serde_json::Value = serde_json::from_str(contents)?;
+    match value {
+        serde_json::Value::Array(items) if items.iter().all(|v| v.is_object()) => Ok(items),
+        value => Ok(vec![value]),
+    }
+}
+
+/// Renders a JSON value as `key: value` lines with dotted keys for nested fields.
+fn flatten_json(value: &serde_json::Value) -> String {
+    fn walk(prefix: &str, value: &serde_json::Value, lines: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    let key = match prefix.is_empty() {
+                        true => key.clone(),
+                        false => format!("{prefix}.{key}"),
+                    };
+                    walk(&key, value, lines);
+                }
+            }
+            serde_json::Value::Array(items)
+                if items.iter().any(|v| v.is_object() || v.is_array()) =>
+            {
+                for (index, value) in items.iter().enumerate() {
+                    let key = match prefix.is_empty() {
+                        true => index.to_string(),
+                        false => format!("{prefix}.{index}"),
+                    };
+                    walk(&key, value, lines);
+                }
+            }
+            serde_json::Value::Array(items) => {
+                let values: Vec<String> = items.iter().map(json_scalar).collect();
+                push_line(prefix, &values.join(", "), lines);
+            }
+            value => push_line(prefix, &json_scalar(value), lines),
+        }
+    }
+    fn push_line(key: &str, value: &str, lines: &mut Vec<String>) {
+        match key.is_empty() {
+            true => lines.push(value.to_string()),
+            false => lines.push(format!("{key}: {value}")),
+        }
+    }
+    fn json_scalar(value: &serde_json::Value) -> String {
+        match value {
+            serde_json::Value::String(v) => v.clone(),
+            v => v.to_string(),
+        }
+    }
+    let mut lines = vec![];
+    walk("", value, &mut lines);
+    lines.join("\n")
+}
+
+fn load_bom(
+    header: &[String],
+    rows: &[Vec<String>],
+    columns: &BomColumns,
+) -> Option<Vec<RagDocument>> {
+    let find_column = |name: &str| {
+        header
+            .iter()
+            .position(|v| v.eq_ignore_ascii_case(name.trim()))
+    };
+    let part_number_index = find_column(&columns.part_number)?;
+    let quantity_index = columns.quantity.as_deref().and_then(find_column);
+    let unit_index = columns.unit.as_deref().and_then(find_column);
+
+    let mut documents = vec![];
+    for (row_index, row) in rows.iter().enumerate() {
+        let cell = |index: Option<usize>| {
+            index
+                .and_then(|i| row.get(i))
+                .map(|v| v.trim())
+                .unwrap_or_default()
+        };
+        let part_number = cell(Some(part_number_index));
+        let quantity = cell(quantity_index);
+        let unit = cell(unit_index);
+        let mut lines = vec![format!("{}: {part_number}", header[part_number_index])];
+        if let Some(index) = quantity_index {
+            let value = format!("{quantity} {unit}");
+            lines.push(format!("{}: {}", header[index], value.trim()));
+        }
+        for (index, value) in row.iter().enumerate() {
+            if index == part_number_index
+                || Some(index) == quantity_index
+                || Some(index) == unit_index
+            {
+                continue;
+            }
+            let name = header.get(index).map(|v| v.as_str()).unwrap_or_default();
+            lines.push(format!("{name}: {}", value.trim()));
+        }
+        let mut metadata = RagMetadata::new();
+        metadata.insert("row".into(), (row_index + 1).to_string());
+        if !part_number.is_empty() {
+            metadata.insert("part_number".into(), part_number.into());
+            metadata.insert(KEYWORDS_METADATA.into(), part_number.into());
+        }
+        if !quantity.is_empty() {
+            metadata.insert("quantity".into(), quantity.into());
+        }
+        if !unit.is_empty() {
+            metadata.insert("unit".into(), unit.into());
+        }
+        documents.push(RagDocument::new(lines.join("\n")).with_metadata(metadata));
+    }
+    Some(documents)
+}
+
+fn load_headings(
+    path: &str,
+    contents: &str,
+    parse: fn(&str) -> Vec<(usize, usize, String)>,
+) -> Result<Vec<RagDocument>> {
+    let mut stack: Vec<(usize, String)> = vec![];
+    let mut documents = vec![];
+    for (level, line, title) in parse(contents) {
+        while stack.last().map(|(v, _)| *v >= level).unwrap_or_default() {
+            stack.pop();
+        }
+        stack.push((level, title));
+        let heading_path = stack
+            .iter()
+            .map(|(_, v)| v.as_str())
+            .collect::<Vec<_>>()
+            .join(" > ");
+        let mut metadata = RagMetadata::new();
+        metadata.insert("source".into(), path.to_string());
+        metadata.insert("line".into(), line.to_string());
+        metadata.insert("heading".into(), heading_path.clone());
+        let document = RagDocument::new(format!("{heading_path} ({path}:{line})"));
+        documents.push(document.with_metadata(metadata));
+    }
+    Ok(documents)
+}
+
+fn parse_markdown_headings(contents: &str) -> Vec<(usize, usize, String)> {
+    let mut output = vec![];
+    let mut in_code_block = false;
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim_end();
+        if line.trim_start().starts_with("```") || line.trim_start().starts_with("~~~") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+        let level = line.chars().take_while(|v| *v == '#').count();
+        if (1..=6).contains(&level) && line[level..].starts_with(' ') {
+            let title = line[level..].trim().trim_end_matches('#').trim();
+            if !title.is_empty() {
+                output.push((level, index + 1, title.to_string()));
+            }
+        }
+    }
+    output
+}
+
+fn parse_html_headings(contents: &str) -> Vec<(usize, usize, String)> {
+    let mut output = vec![];
+    for captures in HTML_HEADING_RE.captures_iter(contents).flatten() {
+        let (level, title) = match (captures.get(1), captures.get(2)) {
+            (Some(level), Some(title)) => (level, title),
+            _ => continue,
+        };
+        let level = level.as_str().parse().unwrap_or(1);
+        let title = HTML_TAG_RE.replace_all(title.as_str(), "");
+        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+        if title.is_empty() {
+            continue;
+        }
+        let line = contents[..captures.get(0).map(|v| v.start()).unwrap_or_default()]
+            .matches('\n')
+            .count()
+            + 1;
+        output.push((level, line, title));
+    }
+    output
+}
+
+fn load_with_pdftotext(input: &LoaderInput, options: &LoaderOptions) -> Result<Vec<RagDocument>> {
+    if !*EXIST_PDFTOTEXT {
+        return Err(skip_error(
+            SkipReason::MissingTool,
+            "Need to install pdftotext (part of the poppler package) to load the file.".into(),
+        ));
+    }
+    let path = input.path;
+    let contents = run_external_tool("pdftotext", &[input.arg(), "-"], path, input.data, options)?;
+    let text_chars = contents.chars().filter(|v| !v.is_whitespace()).count();
+    if text_chars >= MIN_PDF_TEXT_CHARS {
+        let mut documents = split_pdf_pages(&contents);
+        set_title(&mut documents, pdf_title(input, options));
+        return Ok(documents);
+    }
+    if options.pdf_ocr && *EXIST_PDFTOPPM && *EXIST_TESSERACT {
+        // pdftoppm renders pages to files, so in-memory PDFs need one on disk.
+        let mut documents = match input.data {
+            Some(data) => with_temp_file(path, "pdf", data, |temp_path| {
+                load_pdf_with_ocr(temp_path, options)
+            }),
+            None => load_pdf_with_ocr(path, options),
+        }?;
+        set_title(&mut documents, pdf_title(input, options));
+        return Ok(documents);
+    }
+    let hint = if options.pdf_ocr {
+        "install tesseract and pdftoppm to OCR it"
+    } else {
+        "enable `rag_pdf_ocr` to OCR it"
+    };
+    eprintln!(
+        "{}",
+        warning_text(&format!("WARNING: '{path}' has no text layer, {hint}."))
+    );
+    Ok(split_pdf_pages(&contents))
+}
+
+/// Reads the title from the PDF's document info with pdfinfo, when installed.
+fn pdf_title(input: &LoaderInput, options: &LoaderOptions) -> Option<String> {
+    if !*EXIST_PDFINFO {
+        return None;
+    }
+    let info = run_external_tool("pdfinfo", &[input.arg()], input.path, input.data, options)
+        .map_err(|err| debug!("failed to read the pdf info of '{}', {err}", input.path))
+        .ok()?;
+    parse_pdfinfo_title(&info)
+}
+
+fn parse_pdfinfo_title(info: &str) -> Option<String> {
+    info.lines()
+        .find_map(|line| line.strip_prefix("Title:"))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Reads the `dc:title` from the metadata part of a docx/pptx, odt/odp or epub package.
+fn office_title(input: &LoaderInput) -> Option<String> {
+    let reader: Box<dyn ReadSeek> = match input.data {
+        Some(data) => Box::new(std::io::Cursor::new(data)),
+        None => Box::new(std::fs::File::open(input.path).ok()?),
+    };
+    let mut archive = zip::ZipArchive::new(reader).ok()?;
+    let name = archive
+        .file_names()
+        .find(|v| matches!(*v, "docProps/core.xml" | "meta.xml") || v.ends_with(".opf"))?
+        .to_string();
+    let mut contents = String::new();
+    archive
+        .by_name(&name)
+        .ok()?
+        .take(1024 * 1024)
+        .read_to_string(&mut contents)
+        .ok()?;
+    let captures = XML_TITLE_RE.captures(&contents).ok()??;
+    Some(strip_html(captures.get(1)?.as_str()))
+}
+
+trait ReadSeek: Read + std::io::Seek {}
+
+impl<T: Read + std::io::Seek> ReadSeek for T {}
+
+fn load_pdf_with_ocr(path: &str, options: &LoaderOptions) -> Result<Vec<RagDocument>> {
+    let temp_dir = std::env::temp_dir().join(format!("aichat-ocr-{}", sha256(path)));
+    std::fs::create_dir_all(&temp_dir)?;
+    let ret = (|| {
+        let prefix = temp_dir.join("page").display().to_string();
+        run_external_tool(
+            "pdftoppm",
+            &["-r", "300", "-png", path, &prefix],
+            path,
+            None,
+            options,
+        )?;
+        let mut images: Vec<_> = std::fs::read_dir(&temp_dir)?
+            .filter_map(|entry| entry.ok().map(|v| v.path()))
+            .filter(|v| v.extension().map(|v| v == "png").unwrap_or_default())
+            .collect();
+        images.sort_by_key(|v| {
+            v.file_stem()
+                .and_then(|v| v.to_str())
+                .and_then(|v| v.rsplit('-').next())
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or_default()
+        });
+        let mut documents = vec![];
+        for (index, image) in images.iter().enumerate() {
+            let image = image.display().to_string();
+            let text = run_external_tool("tesseract", &[&image, "-"], path, None, options)?;
+            if text.trim().is_empty() {
+                continue;
+            }
+            let mut metadata = RagMetadata::new();
+            metadata.insert("page".into(), (index + 1).to_string());
+            metadata.insert("ocr".into(), "true".into());
+            documents.push(RagDocument::new(normalize_text(&text)).with_metadata(metadata));
+        }
+        Ok(documents)
+    })();
+    let _ = std::fs::remove_dir_all(&temp_dir);
+    ret
+}
+
+fn split_pdf_pages(contents: &str) -> Vec<RagDocument> {
+    contents
+        .split('\x0c')
+        .enumerate()
+        .filter(|(_, text)| !text.trim().is_empty())
+        .map(|(index, text)| {
+            let mut metadata = RagMetadata::new();
+            metadata.insert("page".into(), (index + 1).to_string());
+            RagDocument::new(normalize_text(text)).with_metadata(metadata)
+        })
+        .collect()
+}
+
+/// Cleans up converter output: drops stray form feeds, joins words hyphenated
+/// across line breaks and collapses runs of blank lines into one.
+fn normalize_text(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace('\x0c', "\n");
+    let text = text
+        .lines()
+        .map(|v| v.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = HYPHENATED_BREAK_RE.replace_all(&text, "$1$2");
+    let text = BLANK_LINES_RE.replace_all(&text, "\n\n");
+    text.trim().to_string()
+}
+
+fn load_with_pandoc(input: &LoaderInput, options: &LoaderOptions) -> Result<Vec<RagDocument>> {
+    let from = pandoc_input_format(input.extension)?;
+    let contents = run_external_tool(
+        "pandoc",
+        &["--from", &from, "--to", "plain", input.arg()],
+        input.path,
+        input.data,
+        options,
+    )?;
+    let mut documents = vec![RagDocument::new(normalize_text(&contents))];
+    set_title(&mut documents, office_title(input));
+    Ok(documents)
+}
+
+fn load_slides_with_pandoc(
+    input: &LoaderInput,
+    options: &LoaderOptions,
+) -> Result<Vec<RagDocument>> {
+    let from = pandoc_input_format(input.extension)?;
+    let contents = run_external_tool(
+        "pandoc",
+        &[
+            "--from",
+            &from,
+            "--to",
+            "markdown",
+            "--wrap",
+            "none",
+            input.arg(),
+        ],
+        input.path,
+        input.data,
+        options,
+    )?;
+    let mut documents = split_slides(&normalize_text(&contents));
+    set_title(&mut documents, office_title(input));
+    Ok(documents)
+}
+
+fn pandoc_input_format(extension: &str) -> Result<String> {
+    if !*EXIST_PANDOC {
+        return Err(skip_error(
+            SkipReason::MissingTool,
+            "Need to install pandoc to load the file.".into(),
+        ));
+    }
+    let format = pandoc_source_format(extension).to_string();
+    if !PANDOC_INPUT_FORMATS.is_empty() && !PANDOC_INPUT_FORMATS.contains(&format) {
+        return Err(skip_error(
+            SkipReason::MissingTool,
+            format!("The installed pandoc can't read '.{extension}' files, upgrade pandoc or convert the file first."),
+        ));
+    }
+    Ok(format)
+}
+
+/// The pandoc reader for files with `extension`. Plain-text markup has no container
+/// for pandoc to detect, so those readers are named explicitly.
+fn pandoc_source_format(extension: &str) -> &str {
+    match extension {
+        "org" => "org",
+        "rst" => "rst",
+        _ => extension,
+    }
+}
+
+/// Splits pandoc's markdown output into one document per slide, using the
+/// shallowest heading level as the slide boundary.
+fn split_slides(contents: &str) -> Vec<RagDocument> {
+    let headings = parse_markdown_headings(contents);
+    let level = match headings.iter().map(|(level, _, _)| *level).min() {
+        Some(v) => v,
+        None => return vec![RagDocument::new(contents)],
+    };
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut starts: Vec<usize> = headings
+        .iter()
+        .filter(|(v, _, _)| *v == level)
+        .map(|(_, line, _)| line - 1)
+        .collect();
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+    let mut documents = vec![];
+    for (index, start) in starts.iter().enumerate() {
+        let end = starts.get(index + 1).copied().unwrap_or(lines.len());
+        let text = lines[*start..end].join("\n");
+        if text.trim().is_empty() {
+            continue;
+        }
+        let mut metadata = RagMetadata::new();
+        metadata.insert("slide".into(), (documents.len() + 1).to_string());
+        documents.push(RagDocument::new(text).with_metadata(metadata));
+    }
+    documents
+}
+
+/// Splits a path glob into its deepest directory without glob characters, the file
+/// extensions to keep, and the pattern that paths below that directory must match,
+/// `None` when any path does. The pattern ends with `*` in place of the file name.
+pub fn parse_glob(path_str: &str) -> Result<(String, Vec<String>, Option<String>)> {
+    let glob_start = match path_str.find(['*', '?', '{', '[']) {
+        Some(v) => v,
+        None => {
+            let (base_path, extensions) = parse_extension_list(path_str);
+            return Ok((base_path, extensions, None));
+        }
+    };
+    let (base_path, rest) = match path_str[..glob_start].rfind(['/', '\\']) {
+        Some(end) => (path_str[..end].to_string(), &path_str[end + 1..]),
+        None => (".".to_string(), path_str),
+    };
+    let segments: Vec<&str> = rest.split(['/', '\\']).collect();
+    let (file_pattern, dirs) = match segments.split_last() {
+        Some(v) => v,
+        None => bail!("Invalid path '{path_str}'"),
+    };
+    let extensions = if *file_pattern == "*" || *file_pattern == "**" {
+        vec![]
+    } else {
+        let extensions_str = match file_pattern.strip_prefix("*.") {
+            Some(v) => v,
+            None => bail!("Invalid path '{path_str}'"),
+        };
+        if let Some(v) = extensions_str.strip_prefix('{') {
+            match v.strip_suffix('}') {
+                Some(v) => v.split(',').map(|s| s.trim().to_string()).collect(),
+                None => bail!("Invalid path '{path_str}'"),
+            }
+        } else if extensions_str.contains(['*', '?', '{', '}', '[']) {
+            bail!("Invalid path '{path_str}'")
+        } else {
+            vec![extensions_str.to_string()]
+        }
+    };
+    let recursive = *file_pattern == "**" || dirs.last() == Some(&"**");
+    let pattern = if recursive && dirs.iter().all(|v| *v == "**") {
+        None
+    } else {
+        let file_pattern = if *file_pattern == "**" { "**" } else { "*" };
+        Some([dirs, &[file_pattern]].concat().join("/"))
+    };
+    Ok((base_path, extensions, pattern))
+}
+
+pub const IGNORE_FILE_NAME: &str = ".aichatignore";
+
+/// Lists the files under `entry_path`. `pattern` is the glob pattern returned by
+/// `parse_glob` that their path relative to `entry_path` must match.
+pub async fn list_files(
+    files: &mut Vec<String>,
+    entry_path: &Path,
+    suffixes: Option<&Vec<String>>,
+    pattern: Option<&str>,
+    options: &LoaderOptions,
+) -> Result<()> {
+    let ignore_path = entry_path.join(IGNORE_FILE_NAME);
+    let ignore = if entry_path.is_dir() && ignore_path.is_file() {
+        let mut builder = GitignoreBuilder::new(entry_path);
+        if let Some(err) = builder.add(&ignore_path) {
+            bail!("Failed to load '{}', {err}", ignore_path.display());
+        }
+        Some(builder.build()?)
+    } else {
+        None
+    };
+    let matcher = match pattern {
+        Some(pattern) => {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("Invalid path pattern '{pattern}'"))?;
+            Some(glob.compile_matcher())
+        }
+        None => None,
+    };
+    // Without `**`, files lie as many directories deep as the pattern has.
+    let pattern_depth = pattern
+        .filter(|v| !v.contains("**"))
+        .map(|v| v.split('/').count() - 1);
+    let mut state = WalkState {
+        visited_dirs: HashSet::new(),
+        seen_files: files.iter().map(|v| file_key(Path::new(v))).collect(),
+        root: entry_path.to_path_buf(),
+        matcher,
+        pattern_depth,
+    };
+    walk_files(
+        files,
+        entry_path,
+        suffixes,
+        ignore.as_ref(),
+        options,
+        &mut state,
+        0,
+    )
+    .await
+}
+
+struct WalkState {
+    /// Symlinked directories can point back up the tree, so each directory is walked once.
+    visited_dirs: HashSet<PathBuf>,
+    /// The same file can be reached through symlinks or `..` segments, so each file is listed once.
+    seen_files: HashSet<PathBuf>,
+    root: PathBuf,
+    /// Matches the paths relative to `root` that a glob selects.
+    matcher: Option<GlobMatcher>,
+    /// The directory depth that the glob reaches, `None` when it recurses.
+    pattern_depth: Option<usize>,
+}
+
+/// Identifies the file behind `path`, resolving symlinks and `.`/`..` segments, and
+/// ignoring case on filesystems that usually are case-insensitive.
+fn file_key(path: &Path) -> PathBuf {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if cfg!(any(target_os = "macos", target_os = "windows")) {
+        PathBuf::from(path.to_string_lossy().to_lowercase())
+    } else {
+        path
+    }
+}
+
+/// Parses the `dir:md,txt` form. A path that exists as-is, or a Windows drive
+/// prefix like `C:`, is not treated as an extension list.
+fn parse_extension_list(path_str: &str) -> (String, Vec<String>) {
+    if let Some((base_path, extensions)) = path_str.rsplit_once(':') {
+        let is_drive = base_path.len() == 1 && base_path.chars().all(|c| c.is_ascii_alphabetic());
+        let is_extension_list = extensions
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, ',' | '_' | '-' | ' ' | '.' | '!'));
+        if !base_path.is_empty() && !is_drive && is_extension_list && !Path::new(path_str).exists()
+        {
+            let extensions = extensions
+                .split(',')
+                .map(|v| match v.trim().strip_prefix('!') {
+                    Some(v) => format!("!{}", v.trim_start_matches('.')),
+                    None => v.trim().trim_start_matches('.').to_string(),
+                })
+                .filter(|v| v != "!")
+                .filter(|v| !v.is_empty())
+                .collect();
+            return (base_path.to_string(), extensions);
+        }
+    }
+    (path_str.to_string(), vec![])
+}
+
+#[async_recursion]
+async fn walk_files(
+    files: &mut Vec<String>,
+    entry_path: &Path,
+    suffixes: Option<&'async_recursion Vec<String>>,
+    ignore: Option<&'async_recursion Gitignore>,
+    options: &'async_recursion LoaderOptions,
+    state: &mut WalkState,
+    depth: usize,
+) -> Result<()> {
+    if !entry_path.exists() {
+        bail!("Not found: {:?}", entry_path);
+    }
+    if entry_path.is_file() {
+        add_file(files, suffixes, entry_path, options, state);
+        return Ok(());
+    }
+    if !entry_path.is_dir() {
+        bail!("Not a directory: {:?}", entry_path);
+    }
+    let canonical_path = entry_path
+        .canonicalize()
+        .unwrap_or_else(|_| entry_path.to_path_buf());
+    if !state.visited_dirs.insert(canonical_path) {
+        debug!("skip visited directory {}", entry_path.display());
+        return Ok(());
+    }
+    let mut reader = tokio::fs::read_dir(entry_path).await?;
+    while let Some(entry) = reader.next_entry().await? {
+        check_aborted(options.abort_signal.as_ref())?;
+        let path = entry.path();
+        let is_dir = path.is_dir();
+        if entry.file_name() == IGNORE_FILE_NAME {
+            continue;
+        }
+        if let Some(ignore) = ignore {
+            if ignore.matched(&path, is_dir).is_ignore() {
+                continue;
+            }
+        }
+        if path.is_file() {
+            add_file(files, suffixes, &path, options, state);
+        } else if is_dir {
+            if options.max_depth.is_some_and(|v| depth >= v) {
+                debug!("skip directory {} beyond the max depth", path.display());
+                continue;
+            }
+            if state.pattern_depth.is_some_and(|v| depth >= v) {
+                continue;
+            }
+            walk_files(files, &path, suffixes, ignore, options, state, depth + 1).await?;
+        }
+    }
+    Ok(())
+}
+
+fn add_file(
+    files: &mut Vec<String>,
+    suffixes: Option<&Vec<String>>,
+    path: &Path,
+    options: &LoaderOptions,
+    state: &mut WalkState,
+) {
+    if let Some(matcher) = &state.matcher {
+        let matched = path
+            .strip_prefix(&state.root)
+            .is_ok_and(|v| matcher.is_match(v));
+        if !matched {
+            return;
+        }
+    }
+    // Archives are treated like directories, their entries are filtered when loading.
+    if (is_archive(path) && !is_excluded_extension(suffixes, path))
+        || is_valid_extension(suffixes, path)
+        || (options.peek_text_files
+            && !is_excluded_extension(suffixes, path)
+            && is_unknown_text_file(path, options))
+    {
+        if !state.seen_files.insert(file_key(path)) {
+            debug!(
+                "skip {}, already listed through another path",
+                path.display()
+            );
+            return;
+        }
+        files.push(path.display().to_string());
+    } else {
+        let path = path.display().to_string();
+        options
+            .skipped
+            .push(&path, SkipReason::UnsupportedExtension, None);
+    }
+}
+
+/// Whether a file without a known extension starts with text, so that `.log`,
+/// `.conf` or extensionless files pass a suffix filter.
+fn is_unknown_text_file(path: &Path, options: &LoaderOptions) -> bool {
+    let extension = path
+        .extension()
+        .map(|v| v.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if is_known_extension(&extension, options) {
+        return false;
+    }
+    let mut head = vec![];
+    let ret = std::fs::File::open(path).and_then(|v| v.take(8192).read_to_end(&mut head));
+    ret.is_ok() && !head.is_empty() && sniff_bytes(&head) == Some("txt")
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("zip"))
+}
+
+/// Checks `path` against a suffix filter. Entries prefixed with `!` exclude files
+/// whose name ends with that suffix (e.g. `!lock`, `!min.js`); they apply after the
+/// plain entries, or on their own to everything when there are no plain entries.
+fn is_valid_extension(suffixes: Option<&Vec<String>>, path: &Path) -> bool {
+    let suffixes = match suffixes {
+        Some(v) if !v.is_empty() => v,
+        _ => return true,
+    };
+    if is_excluded_extension(Some(suffixes), path) {
+        return false;
+    }
+    let mut includes = suffixes.iter().filter(|v| !v.starts_with('!')).peekable();
+    if includes.peek().is_none() {
+        return true;
+    }
+    match path.extension().map(|v| v.to_string_lossy().to_string()) {
+        Some(extension) => includes.any(|v| *v == extension),
+        None => false,
     }
-    let contents = run_external_tool("pdftotext", &[path, "-"])?;
-    let document = RagDocument::new(contents);
-    Ok(vec![document])
 }
 
-fn load_with_pandoc(path: &str) -> Result<Vec<RagDocument>> {
-    if !*EXIST_PANDOC {
-        bail!("Need to install pandoc to load the file.")
-    }
-    let contents = run_external_tool("pandoc", &["--to", "plain", path])?;
-    let document = RagDocument::new(contents);
-    Ok(vec![document])
+fn is_excluded_extension(suffixes: Option<&Vec<String>>, path: &Path) -> bool {
+    let (Some(suffixes), Some(name)) = (suffixes, path.file_name()) else {
+        return false;
+    };
+    let name = name.to_string_lossy();
+    suffixes
+        .iter()
+        .filter_map(|v| v.strip_prefix('!'))
+        .any(|v| name.ends_with(&format!(".{v}")))
 }
 
-pub fn parse_glob(path_str: &str) -> Result<(String, Vec<String>)> {
-    if let Some(start) = path_str.find("/**/*.").or_else(|| path_str.find(r"\**\*.")) {
-        let base_path = path_str[..start].to_string();
-        if let Some(curly_brace_end) = path_str[start..].find('}') {
-            let end = start + curly_brace_end;
-            let extensions_str = &path_str[start + 6..end + 1];
-            let extensions = if extensions_str.starts_with('{') && extensions_str.ends_with('}') {
-                extensions_str[1..extensions_str.len() - 1]
-                    .split(',')
-                    .map(|s| s.to_string())
-                    .collect::<Vec<String>>()
+fn parse_delimited(text: &str, delimiter: char, max_rows: usize) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if in_quotes {
+            if ch == '"' {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
             } else {
-                bail!("Invalid path '{path_str}'");
-            };
-            Ok((base_path, extensions))
+                field.push(ch);
+            }
+        } else if ch == '"' && field.is_empty() {
+            in_quotes = true;
+        } else if ch == delimiter {
+            row.push(std::mem::take(&mut field));
+        } else if ch == '\n' || ch == '\r' {
+            if ch == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            row.push(std::mem::take(&mut field));
+            if row.iter().any(|v| !v.is_empty()) {
+                rows.push(std::mem::take(&mut row));
+                if rows.len() >= max_rows {
+                    return rows;
+                }
+            } else {
+                row.clear();
+            }
         } else {
-            let extensions_str = &path_str[start + 6..];
-            let extensions = vec![extensions_str.to_string()];
-            Ok((base_path, extensions))
+            field.push(ch);
         }
-    } else {
-        Ok((path_str.to_string(), vec![]))
     }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
 }
 
-#[async_recursion]
-pub async fn list_files(
-    files: &mut Vec<String>,
-    entry_path: &Path,
-    suffixes: Option<&Vec<String>>,
-) -> Result<()> {
-    if !entry_path.exists() {
-        bail!("Not found: {:?}", entry_path);
-    }
-    if entry_path.is_file() {
-        add_file(files, suffixes, entry_path);
-        return Ok(());
-    }
-    if !entry_path.is_dir() {
-        bail!("Not a directory: {:?}", entry_path);
-    }
-    let mut reader = tokio::fs::read_dir(entry_path).await?;
-    while let Some(entry) = reader.next_entry().await? {
-        let path = entry.path();
-        if path.is_file() {
-            add_file(files, suffixes, &path);
-        } else if path.is_dir() {
-            list_files(files, &path, suffixes).await?;
+/// Runs a converter on `path`, piping `stdin` to it when the content is held in memory.
+/// Transient failures, a timeout or the tool being killed by a signal, are retried with
+/// exponential backoff; spawn and conversion errors are returned right away.
+fn run_external_tool(
+    cmd: &str,
+    args: &[&str],
+    path: &str,
+    stdin: Option<&[u8]>,
+    options: &LoaderOptions,
+) -> Result<String> {
+    let attempts = options.tool_attempts.max(1);
+    let mut delay = Duration::from_millis(100);
+    let mut attempt = 1;
+    loop {
+        let err = match run_external_tool_once(cmd, args, path, stdin, options) {
+            Ok(output) => return Ok(output),
+            Err(err) => err,
+        };
+        if !is_transient_tool_error(&err) {
+            return Err(err);
         }
+        if attempt >= attempts {
+            return Err(err.context(format!(
+                "`{cmd}` failed on '{path}' after {attempt} attempts"
+            )));
+        }
+        debug!("retry `{cmd}` on '{path}' in {delay:?}, {err}");
+        std::thread::sleep(delay);
+        check_aborted(options.abort_signal.as_ref())?;
+        delay *= 2;
+        attempt += 1;
     }
-    Ok(())
 }
 
-fn add_file(files: &mut Vec<String>, suffixes: Option<&Vec<String>>, path: &Path) {
-    if is_valid_extension(suffixes, path) {
-        files.push(path.display().to_string());
+/// A converter failure that may not happen again on a later run.
+#[derive(Debug)]
+struct TransientToolError(String);
+
+impl fmt::Display for TransientToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
-fn is_valid_extension(suffixes: Option<&Vec<String>>, path: &Path) -> bool {
-    if let Some(suffixes) = suffixes {
-        if !suffixes.is_empty() {
-            if let Some(extension) = path.extension().map(|v| v.to_string_lossy().to_string()) {
-                return suffixes.contains(&extension);
+impl std::error::Error for TransientToolError {}
+
+fn is_transient_tool_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TransientToolError>().is_some()
+}
+
+#[cfg(unix)]
+fn exit_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    std::os::unix::process::ExitStatusExt::signal(status)
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &std::process::ExitStatus) -> Option<i32> {
+    None
+}
+
+fn run_external_tool_once(
+    cmd: &str,
+    args: &[&str],
+    path: &str,
+    stdin: Option<&[u8]>,
+    options: &LoaderOptions,
+) -> Result<String> {
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(match stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(mut writer), Some(data)) = (child.stdin.take(), stdin) {
+        // Written from a thread so a tool that fills stdout before draining stdin
+        // can't deadlock; a tool that exits early just closes the pipe.
+        let data = data.to_vec();
+        std::thread::spawn(move || {
+            let _ = std::io::Write::write_all(&mut writer, &data);
+        });
+    }
+    let max_bytes = options.max_output_bytes;
+    let (tx, rx) = std::sync::mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        std::thread::spawn(move || {
+            let mut buf = vec![];
+            let ret = stdout
+                .take(max_bytes as u64 + 1)
+                .read_to_end(&mut buf)
+                .map(|_| buf);
+            let _ = tx.send(ret);
+        });
+    }
+    let stderr_reader = child.stderr.take().map(|stderr| {
+        std::thread::spawn(move || {
+            let mut buf = String::new();
+            let _ = stderr.take(64 * 1024).read_to_string(&mut buf);
+            buf
+        })
+    });
+    let deadline = Instant::now() + options.tool_timeout;
+    let mut stdout = None;
+    let status = loop {
+        if stdout.is_none() {
+            if let Ok(ret) = rx.try_recv() {
+                let buf: Vec<u8> = ret?;
+                if buf.len() > max_bytes {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(skip_error(
+                        SkipReason::TooLarge,
+                        format!(
+                            "`{cmd}` produced more than {max_bytes} bytes of output for '{path}'"
+                        ),
+                    ));
+                }
+                stdout = Some(buf);
             }
-            return false;
         }
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if let Err(err) = check_aborted(options.abort_signal.as_ref()) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(err);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(TransientToolError(format!(
+                "`{cmd}` timed out after {}s while loading '{path}'",
+                options.tool_timeout.as_secs()
+            ))
+            .into());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let stdout = match stdout {
+        Some(v) => v,
+        None => rx.recv()??,
+    };
+    if stdout.len() > max_bytes {
+        return Err(skip_error(
+            SkipReason::TooLarge,
+            format!("`{cmd}` produced more than {max_bytes} bytes of output for '{path}'"),
+        ));
     }
-    true
-}
-
-fn run_external_tool(cmd: &str, args: &[&str]) -> Result<String> {
-    let (success, stdout, stderr) = run_command_with_output(cmd, args, None)?;
-    if success {
-        return Ok(stdout);
+    let stderr = stderr_reader
+        .and_then(|v| v.join().ok())
+        .unwrap_or_default();
+    if status.success() {
+        return String::from_utf8(stdout).context("Invalid UTF-8 in stdout");
+    }
+    if let Some(signal) = exit_signal(&status) {
+        return Err(TransientToolError(format!("`{cmd}` was killed by signal {signal}")).into());
     }
     let err = if !stderr.is_empty() {
         stderr
@@ -133,22 +2409,1048 @@ mod tests {
 
     #[test]
     fn test_parse_glob() {
-        assert_eq!(parse_glob("dir").unwrap(), ("dir".into(), vec![]));
+        let glob = |base: &str, extensions: &[&str], pattern: Option<&str>| {
+            (
+                base.to_string(),
+                extensions.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+                pattern.map(|v| v.to_string()),
+            )
+        };
+        assert_eq!(parse_glob("dir").unwrap(), glob("dir", &[], None));
         assert_eq!(
             parse_glob("dir/file.md").unwrap(),
-            ("dir/file.md".into(), vec![])
+            glob("dir/file.md", &[], None)
         );
         assert_eq!(
             parse_glob("dir/**/*.md").unwrap(),
-            ("dir".into(), vec!["md".into()])
+            glob("dir", &["md"], None)
         );
         assert_eq!(
             parse_glob("dir/**/*.{md,txt}").unwrap(),
-            ("dir".into(), vec!["md".into(), "txt".into()])
+            glob("dir", &["md", "txt"], None)
         );
         assert_eq!(
             parse_glob("C:\\dir\\**\\*.{md,txt}").unwrap(),
-            ("C:\\dir".into(), vec!["md".into(), "txt".into()])
+            glob("C:\\dir", &["md", "txt"], None)
+        );
+        assert_eq!(
+            parse_glob("dir/*.{md,txt}").unwrap(),
+            glob("dir", &["md", "txt"], Some("*"))
+        );
+        assert_eq!(
+            parse_glob("dir\\*.md").unwrap(),
+            glob("dir", &["md"], Some("*"))
+        );
+        assert_eq!(
+            parse_glob("dir/sub/**/docs/**/*.md").unwrap(),
+            glob("dir/sub", &["md"], Some("**/docs/**/*"))
+        );
+        assert_eq!(
+            parse_glob("C:\\dir\\sub\\**\\docs\\*.{md,txt}").unwrap(),
+            glob("C:\\dir\\sub", &["md", "txt"], Some("**/docs/*"))
+        );
+        assert_eq!(
+            parse_glob("dir/v*/**").unwrap(),
+            glob("dir", &[], Some("v*/**"))
+        );
+        assert_eq!(parse_glob("dir/**").unwrap(), glob("dir", &[], None));
+        assert_eq!(parse_glob("*.md").unwrap(), glob(".", &["md"], Some("*")));
+        assert_eq!(
+            parse_glob("dir:md,txt,pdf").unwrap(),
+            glob("dir", &["md", "txt", "pdf"], None)
+        );
+        assert_eq!(
+            parse_glob("C:\\dir:md, txt").unwrap(),
+            glob("C:\\dir", &["md", "txt"], None)
+        );
+        assert_eq!(parse_glob("dir:").unwrap(), glob("dir", &[], None));
+        assert_eq!(parse_glob("C:\\dir").unwrap(), glob("C:\\dir", &[], None));
+        assert!(parse_glob("dir/**/*.{md,txt").is_err());
+        assert!(parse_glob("dir/**/README.md").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_files_glob() {
+        let root = std::env::temp_dir().join(format!("aichat-glob-{}", std::process::id()));
+        for dir in ["sub/docs/api", "sub/src", "v1", "v2/docs"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "a.md",
+            "b.txt",
+            "c.rs",
+            "sub/d.md",
+            "sub/docs/e.md",
+            "sub/docs/api/f.md",
+            "sub/src/g.md",
+            "v1/h.md",
+            "v2/docs/i.md",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        let list = |pattern: &str| {
+            let root = root.clone();
+            let path = format!("{}/{pattern}", root.display());
+            async move {
+                let (base_path, suffixes, pattern) = parse_glob(&path).unwrap();
+                let mut files = vec![];
+                list_files(
+                    &mut files,
+                    Path::new(&base_path),
+                    Some(&suffixes).filter(|v| !v.is_empty()),
+                    pattern.as_deref(),
+                    &LoaderOptions::default(),
+                )
+                .await
+                .unwrap();
+                let mut files: Vec<String> = files
+                    .iter()
+                    .map(|v| {
+                        let path = Path::new(v).strip_prefix(&root).unwrap();
+                        path.display().to_string()
+                    })
+                    .collect();
+                files.sort();
+                files
+            }
+        };
+        let ret = (
+            list("*.{md,txt}").await,
+            list("**/*.md").await,
+            list("sub/**/docs/**/*.md").await,
+            list("*/docs/*.md").await,
+            list("v*/**").await,
+            list("*").await,
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(ret.0, ["a.md", "b.txt"]);
+        assert_eq!(
+            ret.1,
+            [
+                "a.md",
+                "sub/d.md",
+                "sub/docs/api/f.md",
+                "sub/docs/e.md",
+                "sub/src/g.md",
+                "v1/h.md",
+                "v2/docs/i.md"
+            ]
+        );
+        assert_eq!(ret.2, ["sub/docs/api/f.md", "sub/docs/e.md"]);
+        assert_eq!(ret.3, ["sub/docs/e.md", "v2/docs/i.md"]);
+        assert_eq!(ret.4, ["v1/h.md", "v2/docs/i.md"]);
+        assert_eq!(ret.5, ["a.md", "b.txt", "c.rs"]);
+    }
+
+    #[test]
+    fn test_is_valid_extension() {
+        let suffixes = |v: &[&str]| v.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let check = |suffixes: &Vec<String>, path: &str| {
+            is_valid_extension(Some(suffixes), Path::new(path))
+        };
+
+        let include = suffixes(&["md", "js"]);
+        assert!(check(&include, "a.md"));
+        assert!(check(&include, "app.min.js"));
+        assert!(!check(&include, "Cargo.lock"));
+        assert!(!check(&include, "README"));
+
+        let exclude = suffixes(&["!lock", "!min.js"]);
+        assert!(check(&exclude, "a.md"));
+        assert!(check(&exclude, "README"));
+        assert!(check(&exclude, "app.js"));
+        assert!(!check(&exclude, "Cargo.lock"));
+        assert!(!check(&exclude, "app.min.js"));
+
+        let mixed = suffixes(&["js", "md", "!min.js"]);
+        assert!(check(&mixed, "app.js"));
+        assert!(!check(&mixed, "app.min.js"));
+        assert!(!check(&mixed, "Cargo.lock"));
+
+        assert_eq!(
+            parse_glob("dir:md, !lock,!.min.js").unwrap(),
+            (
+                "dir".into(),
+                vec!["md".into(), "!lock".into(), "!min.js".into()],
+                None
+            )
+        );
+        assert_eq!(
+            parse_glob("dir/**/*.{!lock,!map}").unwrap(),
+            ("dir".into(), vec!["!lock".into(), "!map".into()], None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_files_with_ignore() {
+        let root = std::env::temp_dir().join(format!("aichat-ignore-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs/node_modules/pkg")).unwrap();
+        std::fs::create_dir_all(root.join("build")).unwrap();
+        std::fs::write(
+            root.join(IGNORE_FILE_NAME),
+            "node_modules/\n/build\n*.log\n",
+        )
+        .unwrap();
+        for file in [
+            "a.md",
+            "debug.log",
+            "build/out.md",
+            "docs/b.md",
+            "docs/node_modules/pkg/c.md",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        let mut files = vec![];
+        list_files(&mut files, &root, None, None, &LoaderOptions::default())
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        let mut files: Vec<String> = files
+            .iter()
+            .map(|v| {
+                Path::new(v)
+                    .strip_prefix(&root)
+                    .unwrap()
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["a.md", "docs/b.md"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_external_tool_limits() {
+        let options = LoaderOptions {
+            tool_timeout: Duration::from_secs(1),
+            tool_attempts: 1,
+            max_output_bytes: 1024,
+            ..Default::default()
+        };
+        let err = run_external_tool("sleep", &["5"], "slow.pdf", None, &options).unwrap_err();
+        assert!(format!("{err:#}").contains("timed out after 1s"));
+        let err = run_external_tool("yes", &[], "huge.pdf", None, &options).unwrap_err();
+        assert!(err.to_string().contains("more than 1024 bytes"));
+        let output = run_external_tool("echo", &["hi"], "ok.pdf", None, &options).unwrap();
+        assert_eq!(output, "hi\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_external_tool_stdin() {
+        let options = LoaderOptions::default();
+        let output = run_external_tool("cat", &["-"], "a.txt", Some(b"in memory"), &options);
+        assert_eq!(output.unwrap(), "in memory");
+        let data = vec![b'x'; 1024 * 1024];
+        let output = run_external_tool("cat", &[], "big.txt", Some(&data), &options).unwrap();
+        assert_eq!(output.len(), data.len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_external_tool_retry() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("aichat-tool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("flaky");
+        let marker = dir.join("marker");
+        // Killed by a signal on the first run, succeeds once the marker exists.
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nif [ -e '{0}' ]; then echo ok; else touch '{0}'; kill -9 $$; fi\n",
+                marker.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let options = LoaderOptions {
+            tool_attempts: 2,
+            ..Default::default()
+        };
+        let script_str = script.display().to_string();
+        let output = run_external_tool(&script_str, &[], "a.pdf", None, &options).unwrap();
+        assert_eq!(output, "ok\n");
+        assert!(marker.exists());
+        std::fs::remove_file(&marker).unwrap();
+        let options = LoaderOptions {
+            tool_attempts: 1,
+            ..Default::default()
+        };
+        let err = run_external_tool(&script_str, &[], "a.pdf", None, &options).unwrap_err();
+        assert!(err.to_string().contains("after 1 attempts"));
+        assert_eq!(
+            err.root_cause().to_string(),
+            format!("`{script_str}` was killed by signal 9")
+        );
+
+        // A tool that can't be spawned, or that fails on the input, isn't retried.
+        let options = LoaderOptions {
+            tool_attempts: 2,
+            ..Default::default()
+        };
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let err = run_external_tool(&script_str, &[], "a.pdf", None, &options).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().map(|v| v.kind()),
+            Some(std::io::ErrorKind::PermissionDenied)
+        );
+        let err =
+            run_external_tool("aichat-missing-tool", &[], "a.pdf", None, &options).unwrap_err();
+        assert!(!err.to_string().contains("attempts"));
+        let err = run_external_tool("false", &[], "a.pdf", None, &options).unwrap_err();
+        assert_eq!(err.to_string(), "`false` exited with non-zero.");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_external_tool_abort() {
+        let abort_signal = create_abort_signal();
+        let options = LoaderOptions {
+            abort_signal: Some(abort_signal.clone()),
+            ..Default::default()
+        };
+        let signal = abort_signal.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            signal.set_ctrlc();
+        });
+        let start = Instant::now();
+        let err = run_external_tool("sleep", &["5"], "slow.pdf", None, &options).unwrap_err();
+        assert_eq!(err.to_string(), "Aborted!");
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_list_files_peek_text() {
+        let root = std::env::temp_dir().join(format!("aichat-peek-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        for (name, contents) in [
+            ("a.md", "# A".as_bytes()),
+            ("app.log", b"started"),
+            ("Dockerfile", b"FROM rust"),
+            ("blob", b"\x00\x01\x02"),
+            ("b.txt", b"known but filtered"),
+        ] {
+            std::fs::write(root.join(name), contents).unwrap();
+        }
+        let suffixes = vec!["md".to_string()];
+        let mut plain_files = vec![];
+        let ret = list_files(
+            &mut plain_files,
+            &root,
+            Some(&suffixes),
+            None,
+            &LoaderOptions::default(),
+        )
+        .await;
+        let options = LoaderOptions {
+            peek_text_files: true,
+            ..Default::default()
+        };
+        let mut files = vec![];
+        let ret = ret.and(list_files(&mut files, &root, Some(&suffixes), None, &options).await);
+        std::fs::remove_dir_all(&root).unwrap();
+        ret.unwrap();
+        let names = |files: &[String]| {
+            let mut names: Vec<String> = files
+                .iter()
+                .map(|v| {
+                    Path::new(v)
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&plain_files), vec!["a.md"]);
+        assert_eq!(names(&files), vec!["Dockerfile", "a.md", "app.log"]);
+        let skipped: Vec<String> = options.skipped.take().into_iter().map(|v| v.path).collect();
+        assert_eq!(names(&skipped), vec!["b.txt", "blob"]);
+    }
+
+    #[test]
+    fn test_load_plain_bom_crlf() {
+        let data = "\u{feff}# Title\r\nfirst\r\n\r\nsecond\rthird\r\n";
+        let documents =
+            load_from_bytes("a.txt", "txt", data.as_bytes(), &LoaderOptions::default()).unwrap();
+        assert_eq!(
+            documents[0].page_content,
+            "# Title\nfirst\n\nsecond\nthird\n"
+        );
+    }
+
+    #[test]
+    fn test_split_code_definitions() {
+        let rust = "use std::fmt;\n\n/// Says hi.\n#[inline]\npub fn hello() {\n    println!(\"hi\");\n}\n\nimpl<T: Into<String>> fmt::Display for Wrapper<T> {\n}\n\npub(crate) const fn answer() -> u8 {\n    42\n}\n";
+        let sections = split_code_definitions(rust, code_definition_re("rs").unwrap());
+        let symbols: Vec<_> = sections
+            .iter()
+            .map(|(line, symbol, _)| (*line, symbol.as_deref()))
+            .collect();
+        assert_eq!(
+            symbols,
+            vec![
+                (1, None),
+                (3, Some("hello")),
+                (9, Some("fmt::Display for Wrapper<T>")),
+                (12, Some("answer")),
+            ]
+        );
+        assert!(sections[1]
+            .2
+            .starts_with("/// Says hi.\n#[inline]\npub fn hello()"));
+        assert_eq!(
+            sections.iter().map(|v| v.2.as_str()).collect::<String>(),
+            rust
+        );
+
+        let python =
+            "@cache\ndef load(path):\n    def inner():\n        pass\n\nclass Loader:\n    pass\n";
+        let sections = split_code_definitions(python, code_definition_re("py").unwrap());
+        let symbols: Vec<_> = sections.iter().map(|v| v.1.as_deref()).collect();
+        assert_eq!(symbols, vec![Some("load"), Some("Loader")]);
+
+        let typescript =
+            "import x from 'x';\nexport async function run() {}\nexport interface Options {}\n";
+        let sections = split_code_definitions(typescript, code_definition_re("ts").unwrap());
+        let symbols: Vec<_> = sections.iter().map(|v| v.1.as_deref()).collect();
+        assert_eq!(symbols, vec![None, Some("run"), Some("Options")]);
+
+        assert!(code_definition_re("txt").is_none());
+        let options = LoaderOptions {
+            split_code: true,
+            ..Default::default()
+        };
+        assert_eq!(detect_loader("rs", &options).0, "code");
+        assert_eq!(detect_loader("rs", &LoaderOptions::default()).0, "plain");
+    }
+
+    #[test]
+    fn test_load_titles() {
+        use std::io::Write;
+        let dir = std::env::temp_dir().join(format!("aichat-titles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = LoaderOptions::default();
+        let title = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            let extension = name.rsplit_once('.').map(|(_, v)| v).unwrap_or_default();
+            let documents = load(&path.display().to_string(), extension, &options).unwrap();
+            documents[0].metadata.get("title").cloned()
+        };
+        assert_eq!(
+            title("guide.md", "Intro\n\n# User Guide\n\n## Install\nsteps\n").as_deref(),
+            Some("User Guide")
+        );
+        assert_eq!(
+            title(
+                "report.html",
+                "<html><head><title>Q3 &amp; Q4\n Report</title></head></html>"
+            )
+            .as_deref(),
+            Some("Q3 & Q4 Report")
+        );
+        assert_eq!(title("notes.txt", "plain").as_deref(), Some("notes.txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            parse_pdfinfo_title("Creator: Writer\nTitle:          Q3 Financial Report\nPages: 3\n"),
+            Some("Q3 Financial Report".into())
+        );
+        assert_eq!(parse_pdfinfo_title("Title:\nPages: 3\n"), None);
+
+        let mut data = std::io::Cursor::new(vec![]);
+        let mut writer = zip::ZipWriter::new(&mut data);
+        writer
+            .start_file(
+                "docProps/core.xml",
+                zip::write::SimpleFileOptions::default(),
+            )
+            .unwrap();
+        writer
+            .write_all(b"<cp:coreProperties><dc:title>Annual Plan</dc:title></cp:coreProperties>")
+            .unwrap();
+        writer.finish().unwrap();
+        let data = data.into_inner();
+        let input = LoaderInput {
+            path: "plan.docx",
+            extension: "docx",
+            data: Some(&data),
+        };
+        assert_eq!(office_title(&input), Some("Annual Plan".into()));
+    }
+
+    #[test]
+    fn test_skip_reasons() {
+        let options = LoaderOptions {
+            max_document_chars: 0,
+            ..Default::default()
+        };
+        let binary = load_from_bytes("a.bin", "bin", b"\x00\x01\x02", &options).unwrap_err();
+        let plain = decode_file_text("b.txt", b"\x00\x01\x02").unwrap_err();
+        let skipped = SkippedFiles::default();
+        skipped.push_error("a.bin", &binary.context("Failed to load file at 'a.bin'"));
+        skipped.push_error("b.txt", &plain);
+        skipped.push_error("c.csv", &anyhow::anyhow!("bad row"));
+        let reasons: Vec<SkipReason> = skipped.take().into_iter().map(|v| v.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                SkipReason::Binary,
+                SkipReason::Binary,
+                SkipReason::ParseError
+            ]
+        );
+        assert!(skipped.take().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_list_files_symlink_loop() {
+        let root = std::env::temp_dir().join(format!("aichat-symlink-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/a.md"), "x").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("docs/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("docs/a.md"), root.join("b.md")).unwrap();
+        let mut files = vec![];
+        let ret = list_files(&mut files, &root, None, None, &LoaderOptions::default()).await;
+        std::fs::remove_dir_all(&root).unwrap();
+        ret.unwrap();
+        let files: Vec<String> = files
+            .iter()
+            .map(|v| {
+                Path::new(v)
+                    .strip_prefix(&root)
+                    .unwrap()
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        // `b.md` links to `docs/a.md`, so only the first one reached is listed.
+        assert_eq!(files.len(), 1);
+        assert!(files[0] == "b.md" || files[0] == "docs/a.md");
+    }
+
+    #[tokio::test]
+    async fn test_list_files_same_file_through_other_paths() {
+        let root = std::env::temp_dir().join(format!("aichat-dedup-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/a.md"), "x").unwrap();
+        let options = LoaderOptions::default();
+        let mut files = vec![];
+        list_files(&mut files, &root.join("docs"), None, None, &options)
+            .await
+            .unwrap();
+        list_files(
+            &mut files,
+            &root.join("docs/../docs/a.md"),
+            None,
+            None,
+            &options,
+        )
+        .await
+        .unwrap();
+        list_files(&mut files, &root, None, None, &options)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(files, vec![root.join("docs/a.md").display().to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_list_files_max_depth() {
+        let root = std::env::temp_dir().join(format!("aichat-depth-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("a/b/c")).unwrap();
+        for name in ["top.md", "a/one.md", "a/b/two.md", "a/b/c/three.md"] {
+            std::fs::write(root.join(name), "x").unwrap();
+        }
+        let list = |max_depth| {
+            let root = root.clone();
+            async move {
+                let options = LoaderOptions {
+                    max_depth,
+                    ..Default::default()
+                };
+                let mut files = vec![];
+                list_files(&mut files, &root, None, None, &options)
+                    .await
+                    .unwrap();
+                files.sort();
+                files
+                    .iter()
+                    .map(|v| {
+                        Path::new(v)
+                            .strip_prefix(&root)
+                            .unwrap()
+                            .display()
+                            .to_string()
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+        let depth_0 = list(Some(0)).await;
+        let depth_1 = list(Some(1)).await;
+        let unlimited = list(None).await;
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(depth_0, vec!["top.md"]);
+        assert_eq!(depth_1, vec!["a/one.md", "top.md"]);
+        assert_eq!(unlimited.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_list_files_abort() {
+        let root = std::env::temp_dir().join(format!("aichat-abort-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        for name in ["a.md", "b.md", "docs/c.md"] {
+            std::fs::write(root.join(name), "x").unwrap();
+        }
+        let abort_signal = create_abort_signal();
+        let options = LoaderOptions {
+            abort_signal: Some(abort_signal.clone()),
+            ..Default::default()
+        };
+        let mut files = vec![];
+        list_files(&mut files, &root, None, None, &options)
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 3);
+        abort_signal.set_ctrlc();
+        let mut files = vec![];
+        let err = list_files(&mut files, &root, None, None, &options)
+            .await
+            .unwrap_err();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(err.to_string(), "Aborted!");
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn test_loader_registry() {
+        let mut config = Config::default();
+        assert!(LoaderOptions::new(&config).registry.get("pdf").is_some());
+        config.register_loader(".pdf", |path| Ok(vec![RagDocument::new(path)]));
+        config.register_loader("parquet", |_| Ok(vec![]));
+        // Registering an extension again replaces its loader.
+        config.register_loader("PARQUET", |path| Ok(vec![RagDocument::new(path)]));
+        let options = LoaderOptions::new(&config);
+        let documents = load("missing.pdf", "pdf", &options).unwrap();
+        assert_eq!(documents[0].page_content, "missing.pdf");
+        assert_eq!(documents[0].metadata["source"], "missing.pdf");
+        let documents = load("missing.parquet", "parquet", &options).unwrap();
+        assert_eq!(documents[0].page_content, "missing.parquet");
+        assert_eq!(detect_loader("parquet", &options).0, "custom");
+    }
+
+    #[test]
+    fn test_split_pdf_pages() {
+        let documents = split_pdf_pages("first\x0c\n\x0cthird\x0c");
+        let pages: Vec<_> = documents
+            .iter()
+            .map(|v| (v.page_content.as_str(), v.metadata["page"].as_str()))
+            .collect();
+        assert_eq!(pages, vec![("first", "1"), ("third", "3")]);
+    }
+
+    #[test]
+    fn test_parse_frontmatter() {
+        let (metadata, body) = parse_frontmatter(
+            "---\ntitle: Guide\ntags: [rust, rag]\ndate: 2024-01-02\n---\n# Body\n",
+        )
+        .unwrap();
+        assert_eq!(metadata["title"], "Guide");
+        assert_eq!(metadata["tags"], "rust, rag");
+        assert_eq!(metadata["date"], "2024-01-02");
+        assert_eq!(body, "# Body\n");
+        assert!(parse_frontmatter("# No frontmatter\n---\n").is_none());
+        assert!(parse_frontmatter("---\nunterminated: true\n").is_none());
+    }
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(decode_text("héllo".as_bytes().to_vec()), Ok("héllo".into()));
+        assert_eq!(
+            decode_text(b"caf\xe9".to_vec()),
+            Err(TextError::NotUtf8("café".into()))
+        );
+        assert_eq!(
+            decode_text(b"\x7fELF\x02\x01\x00".to_vec()),
+            Err(TextError::Binary)
+        );
+    }
+
+    #[test]
+    fn test_split_slides() {
+        let documents = split_slides("# Intro\nhello\n\n# Plan\n## Step\nwork\n");
+        let slides: Vec<_> = documents
+            .iter()
+            .map(|v| (v.metadata["slide"].as_str(), v.page_content.as_str()))
+            .collect();
+        assert_eq!(
+            slides,
+            vec![("1", "# Intro\nhello\n"), ("2", "# Plan\n## Step\nwork")]
+        );
+        assert_eq!(split_slides("no headings").len(), 1);
+    }
+
+    #[test]
+    fn test_split_large_documents() {
+        let mut metadata = RagMetadata::new();
+        metadata.insert("source".into(), "a.log".into());
+        let document = RagDocument::new("aaaa\n\nbb\ncc\n\ndddddddddd").with_metadata(metadata);
+        let documents = split_large_documents(vec![document], 8, 0);
+        let parts: Vec<_> = documents
+            .iter()
+            .map(|v| {
+                (
+                    v.page_content.as_str(),
+                    v.metadata["part"].as_str(),
+                    v.metadata["source"].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            vec![
+                ("aaaa\n\n", "1", "a.log"),
+                ("bb\ncc\n\n", "2", "a.log"),
+                ("dddddddd", "3", "a.log"),
+                ("dd", "4", "a.log"),
+            ]
+        );
+        let documents =
+            split_large_documents(vec![RagDocument::new("aaaa\n\nbb\ncc\n\ndddddddddd")], 8, 3);
+        let parts: Vec<_> = documents.iter().map(|v| v.page_content.as_str()).collect();
+        assert_eq!(
+            parts,
+            vec![
+                "aaaa\n",
+                "aa\n\nbb\n",
+                "bb\ncc\n\n",
+                "c\n\nddddd",
+                "dddddddd"
+            ]
+        );
+        assert!(parts.iter().all(|v| v.chars().count() <= 8));
+        assert_eq!(
+            add_overlaps(vec!["héllo".into(), "world".into()], 10),
+            vec!["héllo", "hélloworld"]
+        );
+        assert_eq!(
+            split_large_documents(vec![RagDocument::new("short")], 8, 0)[0]
+                .metadata
+                .get("part"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_supported_formats() {
+        let mut options = LoaderOptions::default();
+        options.registry.register("md", |_| Ok(vec![]));
+        let formats = supported_formats(&options);
+        let find = |extension: &str| formats.iter().find(|v| v.extension == extension).unwrap();
+        assert_eq!(find("txt").loader, "plain");
+        assert_eq!(find("md").loader, "custom");
+        assert_eq!(find("pdf").loader, "pdftotext");
+        assert_eq!(find("pdf").available, which("pdftotext").is_ok());
+        assert_eq!(formats.iter().filter(|v| v.extension == "md").count(), 1);
+        assert!(find("json").available);
+        assert_eq!(find("docx").available, which("pandoc").is_ok());
+    }
+
+    #[test]
+    fn test_disabled_loaders() {
+        let options = LoaderOptions {
+            disabled_loaders: vec!["pdftotext".into()],
+            ..Default::default()
+        };
+        let err = load("report.pdf", "pdf", &options).unwrap_err();
+        let err = err.downcast_ref::<SkipError>().unwrap();
+        assert_eq!(err.reason, SkipReason::DisabledLoader);
+        assert!(err.to_string().contains("`rag_disabled_loaders`"));
+        let err = load_bytes("report.pdf", "pdf", b"%PDF", &options, true).unwrap_err();
+        assert!(err.downcast_ref::<SkipError>().is_some());
+        let (name, reason) = detect_loader("pdf", &options);
+        assert_eq!(name, "pdftotext");
+        assert!(reason.unwrap().contains("disabled"));
+        let format = supported_formats(&options)
+            .into_iter()
+            .find(|v| v.extension == "pdf")
+            .unwrap();
+        assert!(!format.available);
+        assert_eq!(detect_loader("docx", &options).0, "pandoc");
+    }
+
+    #[test]
+    fn test_pandoc_source_format() {
+        assert_eq!(pandoc_source_format("docx"), "docx");
+        assert_eq!(pandoc_source_format("epub"), "epub");
+        assert_eq!(pandoc_source_format("org"), "org");
+        assert_eq!(pandoc_source_format("rst"), "rst");
+        let options = LoaderOptions::default();
+        assert_eq!(detect_loader("org", &options).0, "pandoc");
+        assert_eq!(detect_loader("rst", &options).0, "pandoc");
+        if which("pandoc").is_ok() {
+            let data = b"Guide\n=====\n\nRead the *manual* first.\n";
+            let documents = load_from_bytes("guide.rst", "rst", data, &options).unwrap();
+            assert!(documents[0].page_content.contains("Read the manual first."));
+        }
+    }
+
+    #[test]
+    fn test_load_from_bytes() {
+        use std::io::Write;
+        let options = LoaderOptions::default();
+        let documents =
+            load_from_bytes("inventory/rows.csv", "csv", b"name,qty\nbolt,4\n", &options).unwrap();
+        assert_eq!(documents[0].page_content, "name: bolt\nqty: 4");
+        assert_eq!(documents[0].metadata["source"], "inventory/rows.csv");
+        assert_eq!(documents[0].metadata["title"], "rows.csv");
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"# Guide\nread me\n").unwrap();
+        let data = encoder.finish().unwrap();
+        let documents = load_from_bytes("docs/guide.md.gz", "gz", &data, &options).unwrap();
+        assert_eq!(documents[0].page_content, "# Guide\nread me\n");
+        assert_eq!(documents[0].metadata["source"], "docs/guide.md.gz");
+
+        let code = b"pub fn answer() -> u8 {\n    42\n}\n";
+        let documents = load_from_bytes("src/lib.rs", "rs", code, &options).unwrap();
+        assert_eq!(documents[0].metadata.get("symbol"), None);
+        assert_eq!(documents[0].metadata["source"], "src/lib.rs");
+        let code_options = LoaderOptions {
+            split_code: true,
+            ..Default::default()
+        };
+        let documents = load_from_bytes("src/lib.rs", "rs", code, &code_options).unwrap();
+        assert_eq!(documents[0].metadata["symbol"], "answer");
+        assert_eq!(documents[0].metadata["source"], "src/lib.rs");
+
+        // Downloads are named by their URL.
+        let url = "https://example.com/docs/guide";
+        let documents = load_from_bytes(url, "txt", b"read me", &options).unwrap();
+        assert_eq!(documents[0].metadata["source"], url);
+        assert_eq!(documents[0].metadata["title"], url);
+    }
+
+    #[test]
+    fn test_load_gzip() {
+        use std::io::Write;
+        let path = std::env::temp_dir().join(format!("aichat-gzip-{}.csv.gz", std::process::id()));
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"name,qty\nbolt,4\nnut,8\n").unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+        let path_str = path.display().to_string();
+        let documents = load(&path_str, "gz", &LoaderOptions::default());
+        let err = decompress_gzip(&path_str, std::fs::File::open(&path).unwrap(), 8).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        let documents = documents.unwrap();
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].page_content, "name: bolt\nqty: 4");
+        assert_eq!(documents[1].metadata["source"], path_str);
+        assert!(err.to_string().contains("more than 8 bytes"));
+    }
+
+    #[test]
+    fn test_sniff_bytes() {
+        assert_eq!(sniff_bytes(b"%PDF-1.7\n"), Some("pdf"));
+        assert_eq!(sniff_bytes(b"\x1f\x8b\x08\x00"), Some("gz"));
+        assert_eq!(sniff_bytes(b"{\\rtf1\\ansi"), Some("rtf"));
+        assert_eq!(
+            sniff_bytes(b"PK\x03\x04....[Content_Types].xml....word/document.xml"),
+            Some("docx")
+        );
+        assert_eq!(
+            sniff_bytes(b"PK\x03\x04....mimetypeapplication/epub+zip"),
+            Some("epub")
+        );
+        assert_eq!(sniff_bytes(b"PK\x03\x04....other.bin"), Some("zip"));
+        assert_eq!(sniff_bytes("FROM rust:1\nRUN é".as_bytes()), Some("txt"));
+        assert_eq!(sniff_bytes(&"é".as_bytes()[..1]), Some("txt"));
+        assert_eq!(sniff_bytes(b"\xff\xfe\x00\x01"), None);
+    }
+
+    #[test]
+    fn test_load_zip() {
+        use std::io::Write;
+        let path = std::env::temp_dir().join(format!("aichat-zip-{}.zip", std::process::id()));
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let file_options = zip::write::SimpleFileOptions::default();
+        for (name, contents) in [
+            ("notes/a.md", "# A"),
+            ("b.txt", "plain b"),
+            ("c.log", "skipped by suffix"),
+            ("nested.zip", "PK\x03\x04"),
+        ] {
+            writer.start_file(name, file_options).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+        let path_str = path.display().to_string();
+        let mut options = LoaderOptions::default();
+        options.archive_suffixes.insert(
+            path_str.clone(),
+            vec!["md".into(), "txt".into(), "zip".into()],
+        );
+        let documents = load(&path_str, "zip", &options);
+        let err = load_zip(
+            &path_str,
+            std::fs::File::open(&path).unwrap(),
+            &LoaderOptions {
+                max_output_bytes: 4,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        let documents = documents.unwrap();
+        let entries: Vec<(&str, &str)> = documents
+            .iter()
+            .map(|v| (v.metadata["entry"].as_str(), v.page_content.as_str()))
+            .collect();
+        assert_eq!(entries, vec![("notes/a.md", "# A"), ("b.txt", "plain b")]);
+        assert_eq!(documents[0].metadata["source"], path_str);
+        assert!(err.to_string().contains("more than 4 bytes"));
+    }
+
+    #[test]
+    fn test_split_markdown_sections() {
+        let contents = "intro\n# Guide\nabout\n## Install\nrun it\n```\n# not a heading\n```\n### Linux\napt\n## Usage\nuse it\n# Other\n";
+        let sections = split_markdown_sections(contents);
+        assert_eq!(
+            sections,
+            vec![
+                (None, "intro\n".to_string()),
+                (Some("Guide".to_string()), "# Guide\nabout\n".to_string()),
+                (
+                    Some("Guide > Install".to_string()),
+                    "## Install\nrun it\n```\n# not a heading\n```\n### Linux\napt\n".to_string()
+                ),
+                (
+                    Some("Guide > Usage".to_string()),
+                    "## Usage\nuse it\n".to_string()
+                ),
+                (Some("Other".to_string()), "# Other\n".to_string()),
+            ]
+        );
+        assert_eq!(
+            split_markdown_sections("no headings"),
+            vec![(None, "no headings".to_string())]
+        );
+
+        let documents = load_markdown("# Guide\nfirst part\n## Next\nsecond\n", 5);
+        let texts: Vec<_> = documents
+            .unwrap()
+            .into_iter()
+            .map(|v| v.page_content)
+            .collect();
+        assert_eq!(
+            texts,
+            vec!["# Guide\nfirst part\n", "part\n## Next\nsecond\n"]
+        );
+    }
+
+    #[test]
+    fn test_normalize_text() {
+        let text = "The inter-\nnational agree-\n  ment was signed   \r\n\x0c\n\n\n\nSection 2\nCo-\nOperation and the\nwell-known 1990-\n2000 era.\n\nNext paragraph.\n\n\n";
+        assert_eq!(
+            normalize_text(text),
+            "The international agreement was signed\n\nSection 2\nCo-\nOperation and the\nwell-known 1990-\n2000 era.\n\nNext paragraph."
+        );
+    }
+
+    #[test]
+    fn test_load_email() {
+        let mbox = "From alice@example.com Mon Jan  1 00:00:00 2024\n\
+Subject: Hello\n\
+From: Alice <alice@example.com>\n\
+To: bob@example.com\n\
+Date: Mon, 1 Jan 2024 00:00:00 +0000\n\
+Content-Type: multipart/alternative; boundary=\"b\"\n\
+\n\
+--b\n\
+Content-Type: text/html\n\
+\n\
+<p>html body</p>\n\
+--b\n\
+Content-Type: text/plain\n\
+Content-Transfer-Encoding: base64\n\
+\n\
+cGxhaW4gYm9keQ==\n\
+--b--\n\
+\n\
+From bob@example.com Tue Jan  2 00:00:00 2024\n\
+Subject: Re: Hello\n\
+Content-Type: text/html\n\
+\n\
+<style>p { color: red }</style><p>Fish &amp; chips</p>\n\
+>From the archive\n";
+        let messages = split_mbox(mbox.as_bytes());
+        assert_eq!(messages.len(), 2);
+        let first = parse_email(&messages[0]).unwrap().unwrap();
+        assert_eq!(first.page_content, "plain body");
+        assert_eq!(first.metadata["subject"], "Hello");
+        assert_eq!(first.metadata["from"], "Alice <alice@example.com>");
+        assert_eq!(first.metadata["to"], "bob@example.com");
+        assert_eq!(first.metadata["date"], "Mon, 1 Jan 2024 00:00:00 +0000");
+        let second = parse_email(&messages[1]).unwrap().unwrap();
+        assert_eq!(second.page_content, "Fish & chips\nFrom the archive");
+        assert_eq!(second.metadata["subject"], "Re: Hello");
+    }
+
+    #[test]
+    fn test_flatten_json() {
+        let records = parse_json_records(
+            r#"[{"id": 1, "user": {"name": "Ann", "tags": ["a", "b"]}, "items": [{"sku": "X"}], "note": null}]"#,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            flatten_json(&records[0]),
+            "id: 1\nuser.name: Ann\nuser.tags: a, b\nitems.0.sku: X\nnote: null"
+        );
+        let records = parse_json_records("{\"a\": 1}\n\n{\"a\": 2}\n", true).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(parse_json_records("{\"a\": 1}\n{oops", true).is_err());
+    }
+
+    #[test]
+    fn test_parse_headings() {
+        let markdown = "# Guide\n\n## Install\n```sh\n# not a heading\n```\n## Auth ##\n";
+        assert_eq!(
+            parse_markdown_headings(markdown),
+            vec![
+                (1, 1, "Guide".into()),
+                (2, 3, "Install".into()),
+                (2, 7, "Auth".into())
+            ]
+        );
+        let html = "<h1>Guide</h1>\n<h2 id=\"auth\">Auth <code>API</code></h2>";
+        assert_eq!(
+            parse_html_headings(html),
+            vec![(1, 1, "Guide".into()), (2, 2, "Auth API".into())]
+        );
+    }
+
+    #[test]
+    fn test_parse_delimited() {
+        assert_eq!(
+            parse_delimited("a,b,c\r\n1,\"x, y\",\"say \"\"hi\"\"\"\n\n2,,3", ',', 100),
+            vec![
+                vec!["a", "b", "c"],
+                vec!["1", "x, y", "say \"hi\""],
+                vec!["2", "", "3"],
+            ]
+        );
+        assert_eq!(
+            parse_delimited("a\tb\n1\t2\n3\t4\n", '\t', 2),
+            vec![vec!["a", "b"], vec!["1", "2"]]
         );
     }
 }
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use indexmap::{IndexMap, IndexSet};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{metadata, read, read_to_string, Metadata},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Fingerprints of the files indexed by a rag, stored next to the rag file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RagManifest {
+    #[serde(default)]
+    pub files: IndexMap<String, FileFingerprint>,
+    /// Files whose documents were imported with their embeddings. Syncing leaves them alone.
+    #[serde(default, skip_serializing_if = "IndexSet::is_empty")]
+    pub imported: IndexSet<String>,
+}
+
+impl RagManifest {
+    pub fn path(rag_path: &Path) -> PathBuf {
+        rag_path.with_extension("manifest.yaml")
+    }
+
+    pub fn load(rag_path: &Path) -> Result<Option<Self>> {
+        let path = Self::path(rag_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let err = || format!("Failed to load rag manifest at '{}'", path.display());
+        let contents = read_to_string(&path).with_context(err)?;
+        let manifest = serde_yaml::from_str(&contents).with_context(err)?;
+        Ok(Some(manifest))
+    }
+
+    pub fn save(&self, rag_path: &Path) -> Result<()> {
+        let path = Self::path(rag_path);
+        let contents = serde_yaml::to_string(self)?;
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to save rag manifest to '{}'", path.display()))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFingerprint {
+    pub hash: String,
+    pub size: u64,
+    /// The modification time in nanoseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+}
+
+impl FileFingerprint {
+    pub fn new(path: &str) -> Result<Self> {
+        let mtime = metadata(path).ok().as_ref().and_then(modified_nanos);
+        let data = read(path).with_context(|| format!("Failed to read '{path}'"))?;
+        let mut hasher = Sha256::new();
+        hasher.update(&data);
+        Ok(Self {
+            hash: format!("{:x}", hasher.finalize()),
+            size: data.len() as u64,
+            mtime,
+        })
+    }
+
+    /// Fingerprints the file at `path` again, `None` when its contents changed. The file
+    /// is only hashed when its size is unchanged but its modification time is not.
+    pub fn refresh(&self, path: &str) -> Result<Option<Self>> {
+        let metadata = metadata(path).with_context(|| format!("Failed to read '{path}'"))?;
+        if metadata.len() != self.size {
+            return Ok(None);
+        }
+        if self.mtime.is_some() && self.mtime == modified_nanos(&metadata) {
+            return Ok(Some(self.clone()));
+        }
+        let fingerprint = Self::new(path)?;
+        Ok((fingerprint.hash == self.hash).then_some(fingerprint))
+    }
+
+    /// Whether the file at `path` still has the fingerprinted contents.
+    pub fn is_current(&self, path: &str) -> bool {
+        matches!(self.refresh(path), Ok(Some(_)))
+    }
+}
+
+fn modified_nanos(metadata: &Metadata) -> Option<u64> {
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(mtime.as_nanos()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_fingerprint_refresh() {
+        let path = std::env::temp_dir().join(format!("aichat-fingerprint-{}", std::process::id()));
+        let set_mtime = |secs: u64| {
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+        std::fs::write(&path, "abc").unwrap();
+        set_mtime(1000);
+        let path_str = path.display().to_string();
+        let fingerprint = FileFingerprint::new(&path_str).unwrap();
+        assert_eq!(fingerprint.mtime, Some(1000 * 1_000_000_000));
+
+        // The same size and modification time are trusted without hashing.
+        let tampered = FileFingerprint {
+            hash: "tampered".into(),
+            ..fingerprint.clone()
+        };
+        assert_eq!(tampered.refresh(&path_str).unwrap(), Some(tampered.clone()));
+
+        // A touched file is hashed and keeps its fingerprint under the new time.
+        set_mtime(2000);
+        let touched = fingerprint.refresh(&path_str).unwrap().unwrap();
+        assert_eq!(touched.hash, fingerprint.hash);
+        assert_eq!(touched.mtime, Some(2000 * 1_000_000_000));
+        assert!(!tampered.is_current(&path_str));
+
+        std::fs::write(&path, "abd").unwrap();
+        assert_eq!(touched.refresh(&path_str).unwrap(), None);
+        std::fs::write(&path, "abcd").unwrap();
+        set_mtime(2000);
+        assert!(!touched.is_current(&path_str));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(touched.refresh(&path_str).is_err());
+        assert!(!touched.is_current(&path_str));
+    }
+}
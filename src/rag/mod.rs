@@ -1,5 +1,8 @@
 use self::bm25::*;
+use self::index::*;
+use self::lang_detect::*;
 use self::loader::*;
+use self::manifest::*;
 use self::splitter::*;
 
 use crate::client::*;
@@ -7,20 +10,66 @@ use crate::config::*;
 use crate::utils::*;
 
 mod bm25;
+mod index;
+mod lang_detect;
 mod loader;
+mod manifest;
 mod splitter;
 
+pub use self::loader::{
+    supported_formats, BomColumns, DocumentTransforms, LoaderOptions, LoaderRegistry,
+};
+
 use anyhow::bail;
 use anyhow::{anyhow, Context, Result};
+use futures_util::{stream, StreamExt};
 use hnsw_rs::prelude::*;
 use indexmap::{IndexMap, IndexSet};
 use inquire::{required, validator::Validation, Select, Text};
 use path_absolutize::Absolutize;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{fmt::Debug, io::BufReader, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 
+pub const KEYWORDS_METADATA: &str = "keywords";
+/// The number of files `init` embeds between two checkpoints.
+const RAG_CHECKPOINT_FILES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RagPhase {
+    Loading,
+    Embedding,
+}
+
+/// Reported while indexing. `current` and `total` count files while loading
+/// and chunks while embedding; `path` is the file being processed.
+#[derive(Debug, Clone)]
+pub struct RagProgress {
+    pub current: usize,
+    pub total: usize,
+    pub path: String,
+    pub phase: RagPhase,
+}
+
+impl std::fmt::Display for RagProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let phase = match self.phase {
+            RagPhase::Loading => "Loading files",
+            RagPhase::Embedding => "Embedding chunks",
+        };
+        write!(f, "{phase} [{}/{}] {}", self.current, self.total, self.path)
+    }
+}
+
+pub type RagProgressCallback = Arc<dyn Fn(&RagProgress) + Send + Sync>;
+
 pub struct Rag {
     name: String,
     path: String,
@@ -29,6 +78,33 @@ pub struct Rag {
     bm25: BM25<DocumentId>,
     data: RagData,
     embedding_client: Box<dyn Client>,
+    fallback_embedding_clients: Vec<Box<dyn Client>>,
+    embedding_retries: usize,
+    max_embedding_failure_ratio: f32,
+    embedding_concurrency: usize,
+    embedding_rate_limiter: Option<RateLimiter>,
+    embedding_failures: Vec<EmbeddingFailure>,
+    skipped_files: Vec<SkippedFile>,
+    indexed_files: usize,
+    loader_options: LoaderOptions,
+    manifest: RagManifest,
+    progress_callback: Option<RagProgressCallback>,
+}
+
+/// A document imported with an embedding computed elsewhere.
+#[derive(Debug, Deserialize)]
+struct EmbeddedDocument {
+    page_content: String,
+    #[serde(default)]
+    metadata: RagMetadata,
+    embedding: Vec<f32>,
+}
+
+/// A chunk that could not be embedded while indexing and was left out of the rag.
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingFailure {
+    pub path: String,
+    pub error: String,
 }
 
 impl Debug for Rag {
@@ -49,40 +125,213 @@ impl Rag {
         save_path: &Path,
         doc_paths: &[String],
         abort_signal: AbortSignal,
+        progress_callback: Option<RagProgressCallback>,
     ) -> Result<Self> {
         debug!("init rag: {name}");
         let (embedding_model, chunk_size, chunk_overlap) = Self::config(config)?;
         let data = RagData::new(embedding_model.id(), chunk_size, chunk_overlap);
         let mut rag = Self::create(config, name, save_path, data)?;
+        rag.progress_callback = progress_callback;
+        rag.loader_options.abort_signal = Some(abort_signal.clone());
+        let mut checkpoint = match rag.is_temp() {
+            true => None,
+            false => Some(RagCheckpoint::new(save_path)),
+        };
+        if let Some(checkpoint) = &mut checkpoint {
+            rag.resume_checkpoint(checkpoint)?;
+        }
         let mut paths = doc_paths.to_vec();
         if paths.is_empty() {
             paths = add_doc_paths()?;
         };
         debug!("doc paths: {paths:?}");
         let (stop_spinner_tx, set_spinner_message_tx) = run_spinner("Starting").await;
-        tokio::select! {
-            ret = rag.add_paths(&paths, Some(set_spinner_message_tx)) => {
+        let ret = tokio::select! {
+            ret = rag.add_paths_with_checkpoints(&paths, checkpoint.as_mut(), Some(set_spinner_message_tx)) => {
                 let _ = stop_spinner_tx.send(());
-                ret?;
+                ret
             }
             _ = watch_abort_signal(abort_signal) => {
                 let _ = stop_spinner_tx.send(());
-                bail!("Aborted!")
+                Err(anyhow!("Aborted!"))
             },
         };
-        if !rag.is_temp() {
+        rag.report_skipped_files();
+        if let Some(checkpoint) = &mut checkpoint {
+            if ret.is_err() && !rag.data.files.is_empty() {
+                rag.save_checkpoint(checkpoint)?;
+                eprintln!(
+                    "Saved the progress of {} file(s), run again to resume indexing",
+                    rag.data.files.len()
+                );
+            }
+        }
+        ret?;
+        if let Some(checkpoint) = &checkpoint {
             rag.save(save_path)?;
+            checkpoint.remove();
             println!("✨ Saved rag to '{}'", save_path.display());
         }
         Ok(rag)
     }
 
+    /// Picks up the files embedded by an interrupted `init`, except those that
+    /// changed since. A checkpoint built with other settings is ignored. Either way
+    /// the checkpoint is left holding just the resumed files.
+    fn resume_checkpoint(&mut self, checkpoint: &mut RagCheckpoint) -> Result<()> {
+        match checkpoint.load() {
+            Ok(Some((data, mut manifest))) if data.has_same_settings(&self.data) => {
+                self.data = data;
+                self.data.retain_files(|file| {
+                    is_url(&file.path)
+                        || manifest
+                            .files
+                            .get(&file.path)
+                            .is_some_and(|v| v.is_current(&file.path))
+                });
+                let files = self.files();
+                manifest
+                    .files
+                    .retain(|path, _| files.contains(&path.as_str()));
+                self.manifest = manifest;
+                println!(
+                    "Resuming from a checkpoint, {} file(s) already embedded",
+                    self.data.files.len()
+                );
+            }
+            Ok(Some(_)) => debug!(
+                "ignore the rag checkpoint at '{}', it uses other settings",
+                checkpoint.dir().display()
+            ),
+            Ok(None) => {}
+            Err(err) => warn!(
+                "ignore the rag checkpoint at '{}', {err}",
+                checkpoint.dir().display()
+            ),
+        }
+        checkpoint
+            .reset(&self.data, &self.manifest)
+            .with_context(|| format!("Failed to save the progress of rag '{}'", self.name))
+    }
+
+    fn save_checkpoint(&self, checkpoint: &mut RagCheckpoint) -> Result<()> {
+        checkpoint
+            .save(&self.data, &self.manifest)
+            .with_context(|| format!("Failed to save the progress of rag '{}'", self.name))
+    }
+
     pub fn load(config: &GlobalConfig, name: &str, path: &Path) -> Result<Self> {
         let err = || format!("Failed to load rag '{name}'");
-        let file = std::fs::File::open(path).with_context(err)?;
-        let reader = BufReader::new(file);
-        let data: RagData = bincode::deserialize_from(reader).with_context(err)?;
-        Self::create(config, name, path, data)
+        let data = read_rag_index(path).with_context(err)?;
+        let manifest = RagManifest::load(path)?;
+        let mut rag = Self::create(config, name, path, data)?;
+        rag.manifest = match manifest {
+            Some(v) => v,
+            None => {
+                // Rags saved before manifests existed are treated as up to date.
+                let mut manifest = RagManifest::default();
+                for file in rag.data.files.iter().filter(|v| !is_url(&v.path)) {
+                    if let Ok(fingerprint) = FileFingerprint::new(&file.path) {
+                        manifest.files.insert(file.path.clone(), fingerprint);
+                    }
+                }
+                manifest
+            }
+        };
+        Ok(rag)
+    }
+
+    /// Lists the files `paths` would index without loading or embedding them.
+    pub async fn plan<T: AsRef<Path>>(config: &GlobalConfig, paths: &[T]) -> Result<RagPlan> {
+        let mut loader_options = LoaderOptions::new(&config.read());
+        let mut plan = RagPlan::default();
+        for path in list_paths(paths, &mut loader_options).await? {
+            let extension = Path::new(&path)
+                .extension()
+                .map(|v| v.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            let (loader, missing) = match is_url(&path) {
+                true => ("url", None),
+                false => detect_loader(&extension, &loader_options),
+            };
+            match missing {
+                Some(reason) => plan.skipped.push(RagPlanSkipped { path, reason }),
+                None => {
+                    let size = std::fs::metadata(&path)
+                        .map(|v| v.len())
+                        .unwrap_or_default();
+                    plan.total_bytes += size;
+                    plan.files.push(RagPlanFile {
+                        path,
+                        loader: loader.to_string(),
+                        size,
+                    });
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Loads the files of `plan` without embedding them and estimates the tokens
+    /// that would be sent to the embedding model.
+    pub async fn estimate(config: &GlobalConfig, plan: &RagPlan) -> Result<RagEstimate> {
+        let loader_options = LoaderOptions::new(&config.read());
+        let mut estimate = RagEstimate::default();
+        for file in plan.files.iter().filter(|v| !is_url(&v.path)) {
+            let (path, loader_options) = (file.path.clone(), loader_options.clone());
+            let documents = tokio::task::spawn_blocking(move || {
+                let extension = Path::new(&path)
+                    .extension()
+                    .map(|v| v.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                load(&path, &extension, &loader_options)
+            })
+            .await?;
+            let documents = match documents {
+                Err(err) if err.downcast_ref::<SkipError>().is_some() => {
+                    debug!("skip '{}' in estimate, {err}", file.path);
+                    continue;
+                }
+                ret => ret.with_context(|| format!("Failed to load file at '{}'", file.path))?,
+            };
+            estimate.add_documents(&documents);
+        }
+        let input_price = {
+            let config = config.read();
+            config
+                .rag_embedding_model
+                .as_ref()
+                .and_then(|v| Model::retrieve_embedding(&config, v).ok())
+                .and_then(|v| v.data().input_price)
+        };
+        estimate.cost = input_price.map(|v| v * estimate.tokens as f64 / 1_000_000.0);
+        Ok(estimate)
+    }
+
+    /// Brings a loaded rag up to date with `doc_paths`, saving it when anything changed.
+    pub async fn refresh(
+        &mut self,
+        save_path: &Path,
+        doc_paths: &[String],
+        abort_signal: AbortSignal,
+    ) -> Result<()> {
+        self.loader_options.abort_signal = Some(abort_signal.clone());
+        let (stop_spinner_tx, set_spinner_message_tx) = run_spinner("Checking for changes").await;
+        let changed = tokio::select! {
+            ret = self.sync(doc_paths, Some(set_spinner_message_tx)) => {
+                let _ = stop_spinner_tx.send(());
+                ret?
+            }
+            _ = watch_abort_signal(abort_signal) => {
+                let _ = stop_spinner_tx.send(());
+                bail!("Aborted!")
+            },
+        };
+        if changed && !self.is_temp() {
+            self.save(save_path)?;
+            println!("✨ Saved rag to '{}'", save_path.display());
+        }
+        Ok(())
     }
 
     pub fn create(config: &GlobalConfig, name: &str, path: &Path, data: RagData) -> Result<Self> {
@@ -90,6 +339,39 @@ impl Rag {
         let bm25 = data.build_bm25();
         let embedding_model = Model::retrieve_embedding(&config.read(), &data.embedding_model)?;
         let embedding_client = init_client(config, Some(embedding_model.clone()))?;
+        let (
+            fallback_model_ids,
+            embedding_retries,
+            max_embedding_failure_ratio,
+            embedding_concurrency,
+            embedding_rate_limit,
+        ) = {
+            let config = config.read();
+            (
+                config.rag_embedding_fallback_models.clone(),
+                config.rag_embedding_retries,
+                config.rag_max_embedding_failure_ratio,
+                config.rag_embedding_concurrency,
+                config.rag_embedding_rate_limit,
+            )
+        };
+        let mut fallback_embedding_clients = vec![];
+        for model_id in fallback_model_ids {
+            let model = Model::retrieve_embedding(&config.read(), &model_id)?;
+            if let (Some(expect), Some(actual)) = (
+                embedding_model.data().output_vector_size,
+                model.data().output_vector_size,
+            ) {
+                if expect != actual {
+                    bail!(
+                        "Fallback embedding model '{model_id}' has {actual} dimensions, but '{}' has {expect}; a fallback must have the same dimensions or the rag must be rebuilt",
+                        embedding_model.id()
+                    );
+                }
+            }
+            fallback_embedding_clients.push(init_client(config, Some(model))?);
+        }
+        let loader_options = LoaderOptions::new(&config.read());
         let rag = Rag {
             name: name.to_string(),
             path: path.display().to_string(),
@@ -98,6 +380,17 @@ impl Rag {
             hnsw,
             bm25,
             embedding_client,
+            fallback_embedding_clients,
+            embedding_retries,
+            max_embedding_failure_ratio,
+            embedding_concurrency,
+            embedding_rate_limiter: embedding_rate_limit.and_then(RateLimiter::per_minute),
+            embedding_failures: vec![],
+            skipped_files: vec![],
+            indexed_files: 0,
+            loader_options,
+            manifest: RagManifest::default(),
+            progress_callback: None,
         };
         Ok(rag)
     }
@@ -168,21 +461,43 @@ impl Rag {
 
     pub fn save(&self, path: &Path) -> Result<()> {
         ensure_parent_exists(path)?;
-        let mut file = std::fs::File::create(path)?;
-        bincode::serialize_into(&mut file, &self.data)
+        write_rag_index(path, &self.data)
             .with_context(|| format!("Failed to save rag '{}'", self.name))?;
+        self.manifest.save(path)?;
         Ok(())
     }
 
+    pub fn files(&self) -> Vec<&str> {
+        self.data.files.iter().map(|v| v.path.as_str()).collect()
+    }
+
+    /// Compares the indexed files with the disk. `paths` are the rag's document paths;
+    /// when empty, files that are not indexed yet are not looked for.
+    pub async fn status<T: AsRef<Path>>(&self, paths: &[T]) -> Result<RagStatus> {
+        let listed = match paths.is_empty() {
+            true => vec![],
+            false => list_paths(paths, &mut self.loader_options.clone()).await?,
+        };
+        Ok(RagStatus::new(&self.files(), &self.manifest, &listed))
+    }
+
     pub fn export(&self) -> Result<String> {
-        let files: Vec<_> = self.data.files.iter().map(|v| &v.path).collect();
-        let data = json!({
+        let files = self.files();
+        let mut data = json!({
             "path": self.path,
             "embedding_model": self.embedding_model.id(),
             "chunk_size": self.data.chunk_size,
             "chunk_overlap": self.data.chunk_overlap,
             "files": files,
         });
+        // The chunks the last indexing run left out because they failed to embed.
+        if !self.embedding_failures.is_empty() {
+            data["embedding_failures"] = json!(self.embedding_failures);
+        }
+        // The files the last indexing run left out, with why.
+        if !self.skipped_files.is_empty() {
+            data["skipped_files"] = json!(self.skipped_files);
+        }
         let output = serde_yaml::to_string(&data)
             .with_context(|| format!("Unable to show info about rag '{}'", self.name))?;
         Ok(output)
@@ -192,22 +507,50 @@ impl Rag {
         &self.name
     }
 
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn embedding_model(&self) -> &Model {
+        &self.embedding_model
+    }
+
+    /// The length of the stored vectors, `None` for an empty rag.
+    pub fn dimensions(&self) -> Option<usize> {
+        self.data.vectors.values().next().map(|v| v.len())
+    }
+
     pub fn is_temp(&self) -> bool {
         self.name == TEMP_RAG_NAME
     }
 
+    /// Returns the retrieved chunks, best first, or none when no document meets the
+    /// minimum scores.
+    #[allow(clippy::too_many_arguments)]
     pub async fn search(
         &self,
         text: &str,
         top_k: usize,
         min_score_vector_search: f32,
         min_score_keyword_search: f32,
-        rerank: Option<(Box<dyn Client>, f32)>,
+        rerank: Option<(Box<dyn Client>, f32, Option<Duration>)>,
+        scope: Option<&str>,
         abort_signal: AbortSignal,
-    ) -> Result<String> {
+    ) -> Result<Vec<RagChunk>> {
+        let scope_ids = match scope {
+            Some(scope) => {
+                let ids = self.scope_document_ids(scope)?;
+                if ids.is_empty() {
+                    debug!("no documents match the scope '{scope}'");
+                    return Ok(vec![]);
+                }
+                Some(ids)
+            }
+            None => None,
+        };
         let (stop_spinner_tx, _) = run_spinner("Searching").await;
         let ret = tokio::select! {
-            ret = self.hybird_search(text, top_k, min_score_vector_search, min_score_keyword_search, rerank) => {
+            ret = self.hybird_search(text, top_k, min_score_vector_search, min_score_keyword_search, rerank, scope_ids.as_ref()) => {
                 ret
             }
             _ = watch_abort_signal(abort_signal) => {
@@ -215,7 +558,10 @@ impl Rag {
             },
         };
         let _ = stop_spinner_tx.send(());
-        let output = ret?.join("\n\n");
+        let output = ret?;
+        if output.is_empty() {
+            debug!("no documents meet the minimum score");
+        }
         Ok(output)
     }
 
@@ -224,99 +570,514 @@ impl Rag {
         paths: &[T],
         progress_tx: Option<mpsc::UnboundedSender<String>>,
     ) -> Result<()> {
-        // List files
-        let mut file_paths = vec![];
+        let file_paths = self.list_unindexed_paths(paths, &progress_tx).await?;
+        self.add_files(file_paths, progress_tx).await
+    }
+
+    /// Like `add_paths`, but embeds the files in batches and saves the progress to
+    /// `checkpoint` after each one, so that an interrupted run can resume.
+    async fn add_paths_with_checkpoints<T: AsRef<Path>>(
+        &mut self,
+        paths: &[T],
+        checkpoint: Option<&mut RagCheckpoint>,
+        progress_tx: Option<mpsc::UnboundedSender<String>>,
+    ) -> Result<()> {
+        let Some(checkpoint) = checkpoint else {
+            return self.add_paths(paths, progress_tx).await;
+        };
+        let file_paths = self.list_unindexed_paths(paths, &progress_tx).await?;
+        self.embedding_failures.clear();
+        let mut seen_documents = self.seen_documents();
+        for batch in file_paths.chunks(RAG_CHECKPOINT_FILES) {
+            self.embed_files(batch.to_vec(), &mut seen_documents, progress_tx.clone())
+                .await?;
+            self.save_checkpoint(checkpoint)?;
+        }
+        self.build_stores(&progress_tx);
+        Ok(())
+    }
+
+    async fn list_unindexed_paths<T: AsRef<Path>>(
+        &mut self,
+        paths: &[T],
+        progress_tx: &Option<mpsc::UnboundedSender<String>>,
+    ) -> Result<Vec<String>> {
+        progress(progress_tx, "Listing paths".into());
+        let file_paths = list_paths(paths, &mut self.loader_options)
+            .await?
+            .into_iter()
+            .filter(|path| !self.data.files.iter().any(|v| &v.path == path))
+            .collect();
+        Ok(file_paths)
+    }
+
+    /// Indexes just the files under `paths` and appends them to the rag, saving it
+    /// unless it is temporary. Files already indexed, by path or content, are
+    /// rejected unless `force` is set, in which case they are re-indexed.
+    /// Returns the added files.
+    pub async fn add_documents(
+        &mut self,
+        paths: &[String],
+        force: bool,
+        abort_signal: AbortSignal,
+    ) -> Result<Vec<String>> {
+        self.loader_options.abort_signal = Some(abort_signal.clone());
+        self.loader_options.skipped.take();
+        self.indexed_files = 0;
+        let file_paths = list_paths(paths, &mut self.loader_options).await?;
+        if file_paths.is_empty() {
+            bail!("No files to add from {}", paths.join(", "));
+        }
+        let present = find_present_files(&self.files(), &self.manifest, &file_paths)?;
+        if !present.is_empty() && !force {
+            bail!(
+                "Already in the rag: {}; add --force to re-index",
+                present.join(", ")
+            );
+        }
+        let removed = self
+            .data
+            .retain_files(|file| !file_paths.contains(&file.path));
+        self.manifest
+            .files
+            .retain(|path, _| !file_paths.contains(path));
+        let files_len = self.data.files.len();
+        let (stop_spinner_tx, set_spinner_message_tx) = run_spinner("Adding documents").await;
+        let ret = tokio::select! {
+            ret = self.add_files(file_paths.clone(), Some(set_spinner_message_tx)) => {
+                let _ = stop_spinner_tx.send(());
+                ret
+            }
+            _ = watch_abort_signal(abort_signal) => {
+                let _ = stop_spinner_tx.send(());
+                Err(anyhow!("Aborted!"))
+            },
+        };
+        self.report_skipped_files();
+        ret?;
+        if removed > 0 && self.data.files.len() == files_len {
+            self.hnsw = self.data.build_hnsw();
+            self.bm25 = self.data.build_bm25();
+        }
+        if !self.is_temp() {
+            self.save(Path::new(&self.path))?;
+        }
+        let added = self.files();
+        Ok(file_paths
+            .into_iter()
+            .filter(|v| added.contains(&v.as_str()))
+            .collect())
+    }
+
+    /// Re-indexes only the files under `paths` whose content changed since the last run
+    /// and drops the files that no longer exist. Returns whether the rag changed.
+    pub async fn sync<T: AsRef<Path>>(
+        &mut self,
+        paths: &[T],
+        progress_tx: Option<mpsc::UnboundedSender<String>>,
+    ) -> Result<bool> {
+        self.loader_options.skipped.take();
+        self.indexed_files = 0;
+        let ret = self.sync_paths(paths, progress_tx).await;
+        self.report_skipped_files();
+        ret
+    }
+
+    /// Keeps the files skipped by the run that just ended and warns about them.
+    fn report_skipped_files(&mut self) {
+        self.skipped_files = self.loader_options.skipped.take();
+        if let Some(summary) = summarize_skipped_files(&self.skipped_files, self.indexed_files) {
+            eprintln!("{}", warning_text(&format!("WARNING: {summary}")));
+        }
+    }
+
+    async fn sync_paths<T: AsRef<Path>>(
+        &mut self,
+        paths: &[T],
+        progress_tx: Option<mpsc::UnboundedSender<String>>,
+    ) -> Result<bool> {
         progress(&progress_tx, "Listing paths".into());
-        for path in paths {
-            let path = path
-                .as_ref()
-                .absolutize()
-                .with_context(|| anyhow!("Invalid path '{}'", path.as_ref().display()))?;
-            let path_str = path.display().to_string();
-            if self.data.files.iter().any(|v| v.path == path_str) {
+        let file_paths = list_paths(paths, &mut self.loader_options).await?;
+        let mut changed_paths = vec![];
+        for path in &file_paths {
+            if self.manifest.imported.contains(path) {
+                continue;
+            }
+            if is_url(path) {
+                if !self.data.files.iter().any(|v| &v.path == path) {
+                    changed_paths.push(path.clone());
+                }
                 continue;
             }
-            let (path_str, suffixes) = parse_glob(&path_str)?;
-            let suffixes = if suffixes.is_empty() {
-                None
-            } else {
-                Some(&suffixes)
+            let indexed = self.data.files.iter().any(|v| &v.path == path);
+            let fingerprint = match self.manifest.files.get(path) {
+                Some(fingerprint) if indexed => fingerprint.refresh(path),
+                _ => Ok(None),
             };
-            list_files(&mut file_paths, Path::new(&path_str), suffixes).await?;
+            match fingerprint {
+                Ok(Some(fingerprint)) => {
+                    // A touched but unchanged file keeps its documents under the new time.
+                    self.manifest.files.insert(path.clone(), fingerprint);
+                }
+                Ok(None) => changed_paths.push(path.clone()),
+                Err(err) => {
+                    // The documents of an unreadable file are kept until it can be read again.
+                    let detail = Some(format!("{err:#}"));
+                    self.loader_options
+                        .skipped
+                        .push(path, SkipReason::Unreadable, detail);
+                }
+            }
+        }
+        let imported = &self.manifest.imported;
+        let removed = self.data.retain_files(|file| {
+            imported.contains(&file.path)
+                || (file_paths.contains(&file.path) && !changed_paths.contains(&file.path))
+        });
+        self.manifest
+            .files
+            .retain(|path, _| file_paths.contains(path) && !changed_paths.contains(path));
+        debug!(
+            "sync rag: {} changed, {removed} documents removed",
+            changed_paths.len()
+        );
+        if changed_paths.is_empty() && removed == 0 {
+            return Ok(false);
+        }
+        if changed_paths.is_empty() {
+            self.hnsw = self.data.build_hnsw();
+            self.bm25 = self.data.build_bm25();
+        } else {
+            self.add_files(changed_paths, progress_tx).await?;
+        }
+        Ok(true)
+    }
+
+    async fn add_files(
+        &mut self,
+        file_paths: Vec<String>,
+        progress_tx: Option<mpsc::UnboundedSender<String>>,
+    ) -> Result<()> {
+        self.embedding_failures.clear();
+        let mut seen_documents = self.seen_documents();
+        if self
+            .embed_files(file_paths, &mut seen_documents, progress_tx.clone())
+            .await?
+        {
+            self.build_stores(&progress_tx);
         }
+        Ok(())
+    }
+
+    /// The hashes of the indexed chunks, so that copies of them are not embedded again.
+    fn seen_documents(&self) -> HashSet<String> {
+        self.data
+            .files
+            .iter()
+            .flat_map(|file| &file.documents)
+            .map(|document| sha256(&document.page_content))
+            .collect()
+    }
 
+    fn build_stores(&mut self, progress_tx: &Option<mpsc::UnboundedSender<String>>) {
+        progress(progress_tx, "Building vector store".into());
+        self.hnsw = self.data.build_hnsw();
+        self.bm25 = self.data.build_bm25();
+    }
+
+    /// Loads and embeds `file_paths` into the data, leaving the search stores to the
+    /// caller. Chunks whose hash is in `seen_documents` are dropped as duplicates.
+    /// Returns whether any file was added.
+    async fn embed_files(
+        &mut self,
+        file_paths: Vec<String>,
+        seen_documents: &mut HashSet<String>,
+        progress_tx: Option<mpsc::UnboundedSender<String>>,
+    ) -> Result<bool> {
         // Load files
         let mut rag_files = vec![];
         let file_paths_len = file_paths.len();
         progress(&progress_tx, format!("Loading files [1/{file_paths_len}]"));
-        for path in file_paths {
-            let extension = Path::new(&path)
-                .extension()
-                .map(|v| v.to_string_lossy().to_lowercase())
-                .unwrap_or_default();
-            let separator = detect_separators(&extension);
-            let splitter = RecursiveCharacterTextSplitter::new(
-                self.data.chunk_size,
-                self.data.chunk_overlap,
-                &separator,
+        let (chunk_size, chunk_overlap) = (self.data.chunk_size, self.data.chunk_overlap);
+        // Once a file fails because its tool is missing, the others of its type are
+        // skipped with the same reason instead of being tried one by one.
+        let missing_tools: Arc<parking_lot::Mutex<HashMap<String, String>>> = Default::default();
+        let mut tasks = stream::iter(file_paths)
+            .map(|path| {
+                let loader_options = self.loader_options.clone();
+                let missing_tools = missing_tools.clone();
+                async move {
+                    let missing = file_extension(&path)
+                        .and_then(|extension| missing_tools.lock().get(&extension).cloned());
+                    let ret = match missing {
+                        Some(message) => Err(skip_error(SkipReason::MissingTool, message)),
+                        None => load_rag_file(&path, &loader_options).await,
+                    };
+                    (path, ret)
+                }
+            })
+            .buffered(self.loader_options.concurrency.max(1));
+        let mut fingerprints = vec![];
+        let mut duplicated_documents = 0;
+        while let Some(task) = tasks.next().await {
+            check_aborted(self.loader_options.abort_signal.as_ref())?;
+            let (path, (extension, documents, fingerprint)) = match task {
+                (path, Ok(v)) => (path, v),
+                (path, Err(err)) => {
+                    if let (Some(extension), Some(skip)) =
+                        (file_extension(&path), err.downcast_ref::<SkipError>())
+                    {
+                        if skip.reason == SkipReason::MissingTool {
+                            missing_tools.lock().insert(extension, skip.to_string());
+                        }
+                    }
+                    self.loader_options.skipped.push_error(&path, &err);
+                    continue;
+                }
+            };
+            if let Some(fingerprint) = fingerprint {
+                fingerprints.push((path.clone(), fingerprint));
+            }
+            let mut documents = tokio::task::spawn_blocking(move || {
+                let separator = detect_separators(&extension);
+                let splitter =
+                    RecursiveCharacterTextSplitter::new(chunk_size, chunk_overlap, &separator);
+                splitter.split_documents(&documents, &SplitterChunkHeaderOptions::default())
+            })
+            .await?;
+            duplicated_documents += dedup_documents(&mut documents, seen_documents);
+            let file = RagFile { path, documents };
+            self.report_progress(
+                rag_files.len() + 1,
+                file_paths_len,
+                &file.path,
+                RagPhase::Loading,
             );
-            let documents = load(&path, &extension)
-                .with_context(|| format!("Failed to load file at '{path}'"))?;
-            let documents =
-                splitter.split_documents(&documents, &SplitterChunkHeaderOptions::default());
-            rag_files.push(RagFile { path, documents });
+            rag_files.push(file);
             progress(
                 &progress_tx,
                 format!("Loading files [{}/{file_paths_len}]", rag_files.len()),
             );
         }
 
+        if duplicated_documents > 0 {
+            debug!("skip {duplicated_documents} duplicated documents");
+        }
+        self.indexed_files += rag_files.len();
+
         if rag_files.is_empty() {
-            return Ok(());
+            return Ok(false);
         }
 
         // Convert vectors
-        let mut vector_ids = vec![];
         let mut texts = vec![];
-        for (file_index, file) in rag_files.iter().enumerate() {
-            for (document_index, document) in file.documents.iter().enumerate() {
-                vector_ids.push(combine_document_id(file_index, document_index));
-                texts.push(document.page_content.clone())
+        let mut text_paths = vec![];
+        for file in rag_files.iter() {
+            for document in file.documents.iter() {
+                texts.push(document.page_content.clone());
+                text_paths.push(file.path.as_str());
             }
         }
 
-        let embeddings_data = EmbeddingsData::new(texts, false);
-        let embeddings = self
-            .create_embeddings(embeddings_data, progress_tx.clone())
+        let (embeddings, failures) = self
+            .create_document_embeddings(texts, &text_paths, progress_tx.clone())
             .await?;
+        if let Some(summary) = check_embedding_failures(
+            &failures,
+            embeddings.len(),
+            self.max_embedding_failure_ratio,
+        )? {
+            eprintln!("{}", warning_text(&format!("WARNING: {summary}")));
+        }
 
-        self.data.add(rag_files, vector_ids, embeddings);
-        progress(&progress_tx, "Building vector store".into());
+        // Chunks that failed to embed are dropped, and their files are left out of the
+        // manifest so that the next refresh retries them.
+        let failed_paths: HashSet<&str> = failures.iter().map(|v| v.path.as_str()).collect();
+        fingerprints.retain(|(path, _)| !failed_paths.contains(path.as_str()));
+        let mut embeddings = embeddings.into_iter();
+        let mut vector_ids = vec![];
+        let mut vectors = vec![];
+        let base_file_index = self.data.files.len();
+        for (file_index, file) in rag_files.iter_mut().enumerate() {
+            for document in std::mem::take(&mut file.documents) {
+                if let Some(embedding) = embeddings.next().flatten() {
+                    vector_ids.push(combine_document_id(
+                        base_file_index + file_index,
+                        file.documents.len(),
+                    ));
+                    vectors.push(embedding);
+                    file.documents.push(document);
+                }
+            }
+        }
+        self.embedding_failures.extend(failures);
+
+        self.data.add(rag_files, vector_ids, vectors);
+        self.manifest.files.extend(fingerprints);
+
+        Ok(true)
+    }
+
+    /// Reads the documents of a JSON array or JSON lines file at `path`, each with its
+    /// `page_content`, optional `metadata` and precomputed `embedding`, and adds them
+    /// without calling the embedding model. Saves the rag unless it is temporary.
+    /// Returns the number of imported documents.
+    pub fn import_documents(&mut self, path: &str) -> Result<usize> {
+        let path = Path::new(path)
+            .absolutize()
+            .with_context(|| anyhow!("Invalid path '{path}'"))?
+            .display()
+            .to_string();
+        let contents =
+            std::fs::read_to_string(&path).with_context(|| format!("Failed to read '{path}'"))?;
+        let documents: Vec<EmbeddedDocument> = match contents.trim_start().starts_with('[') {
+            true => serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid embedded documents at '{path}'"))?,
+            false => contents
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(index, line)| {
+                    serde_json::from_str(line).with_context(|| {
+                        format!(
+                            "Invalid embedded document at line {} of '{path}'",
+                            index + 1
+                        )
+                    })
+                })
+                .collect::<Result<_>>()?,
+        };
+        if documents.is_empty() {
+            bail!("No documents in '{path}'");
+        }
+        let documents_len = documents.len();
+        let documents = documents
+            .into_iter()
+            .map(|v| {
+                let document = RagDocument::new(v.page_content).with_metadata(v.metadata);
+                (document, v.embedding)
+            })
+            .collect();
+        self.add_embedded_documents(&path, documents)?;
+        if !self.is_temp() {
+            self.save(Path::new(&self.path))?;
+        }
+        Ok(documents_len)
+    }
+
+    /// Adds documents embedded elsewhere as the file `path`, replacing the documents
+    /// already indexed for it. The file is recorded as imported so that syncing keeps it.
+    pub fn add_embedded_documents(
+        &mut self,
+        path: &str,
+        documents: Vec<(RagDocument, Vec<f32>)>,
+    ) -> Result<()> {
+        let dimension = self
+            .embedding_model
+            .data()
+            .output_vector_size
+            .or_else(|| self.data.vectors.values().next().map(|v| v.len()))
+            .or_else(|| documents.first().map(|(_, v)| v.len()));
+        if let Some(dimension) = dimension {
+            for (index, (_, embedding)) in documents.iter().enumerate() {
+                if embedding.len() != dimension {
+                    bail!(
+                        "The embedding of document {index} in '{path}' has {} dimensions, but '{}' expects {dimension}",
+                        embedding.len(),
+                        self.embedding_model.id()
+                    );
+                }
+            }
+        }
+        self.data.retain_files(|file| file.path != path);
+        self.manifest.files.shift_remove(path);
+        self.manifest.imported.insert(path.to_string());
+        let file_index = self.data.files.len();
+        let (documents, embeddings): (Vec<_>, Vec<_>) = documents.into_iter().unzip();
+        let vector_ids = (0..documents.len())
+            .map(|document_index| combine_document_id(file_index, document_index))
+            .collect();
+        let file = RagFile {
+            path: path.to_string(),
+            documents,
+        };
+        self.data.add(vec![file], vector_ids, embeddings);
         self.hnsw = self.data.build_hnsw();
         self.bm25 = self.data.build_bm25();
-
         Ok(())
     }
 
+    /// Removes the documents whose local source file no longer exists.
+    /// Imported files are kept. Saves the rag unless it is temporary.
+    /// Returns the number of removed files and documents.
+    pub fn prune(&mut self) -> Result<(usize, usize)> {
+        let mut removed_paths = vec![];
+        let imported = &self.manifest.imported;
+        let removed_documents = self.data.retain_files(|file| {
+            let exist = is_url(&file.path)
+                || imported.contains(&file.path)
+                || Path::new(&file.path).exists();
+            if !exist {
+                debug!("prune missing file: {}", file.path);
+                removed_paths.push(file.path.clone());
+            }
+            exist
+        });
+        if !removed_paths.is_empty() {
+            self.manifest
+                .files
+                .retain(|path, _| !removed_paths.contains(path));
+            self.hnsw = self.data.build_hnsw();
+            self.bm25 = self.data.build_bm25();
+            if !self.is_temp() {
+                self.save(Path::new(&self.path))?;
+            }
+        }
+        Ok((removed_paths.len(), removed_documents))
+    }
+
     async fn hybird_search(
         &self,
         query: &str,
         top_k: usize,
         min_score_vector_search: f32,
         min_score_keyword_search: f32,
-        rerank: Option<(Box<dyn Client>, f32)>,
-    ) -> Result<Vec<String>> {
+        rerank: Option<(Box<dyn Client>, f32, Option<Duration>)>,
+        scope_ids: Option<&Vec<DocumentId>>,
+    ) -> Result<Vec<RagChunk>> {
         let (vector_search_result, text_search_result) = tokio::join!(
-            self.vector_search(query, top_k, min_score_vector_search),
-            self.keyword_search(query, top_k, min_score_keyword_search)
+            self.vector_search(query, top_k, min_score_vector_search, scope_ids),
+            self.keyword_search(query, top_k, min_score_keyword_search, scope_ids)
         );
-        let vector_search_ids = vector_search_result?;
+        let mut vector_scores: HashMap<DocumentId, f32> = HashMap::new();
+        let mut vector_search_ids = vec![];
+        for (id, score) in vector_search_result? {
+            let best = vector_scores.entry(id).or_insert(score);
+            *best = best.max(score);
+            vector_search_ids.push(id);
+        }
         let keyword_search_ids = text_search_result?;
         debug!(
             "vector_search_ids: {vector_search_ids:?}, keyword_search_ids: {keyword_search_ids:?}"
         );
         let ids = match rerank {
-            Some((client, min_score)) => {
+            Some((client, min_score, Some(budget))) => {
+                let candidates_len = vector_search_ids.len() + keyword_search_ids.len();
+                let candidates: Vec<DocumentId> = reciprocal_rank_fusion(
+                    vec![vector_search_ids, keyword_search_ids],
+                    vec![1.0, 1.0],
+                    candidates_len,
+                )
+                .into_iter()
+                .filter(|id| self.data.get(*id).is_some())
+                .collect();
+                let ids = self
+                    .rerank_with_budget(client, query, candidates, top_k, min_score as f64, budget)
+                    .await?;
+                debug!("rerank_ids: {ids:?}");
+                ids
+            }
+            Some((client, min_score, None)) => {
                 let min_score = min_score as f64;
                 let ids: IndexSet<DocumentId> = [vector_search_ids, keyword_search_ids]
                     .concat()
@@ -359,18 +1120,75 @@ impl Rag {
             .into_iter()
             .filter_map(|id| {
                 let document = self.data.get(id)?;
-                Some(document.page_content.clone())
+                Some(RagChunk {
+                    text: document.page_content.clone(),
+                    score: vector_scores.get(&id).copied(),
+                })
             })
             .collect();
         Ok(output)
     }
 
+    /// Reranks `candidates` in batches of `top_k` until `budget` runs out. Candidates
+    /// the reranker didn't get to follow the reranked ones in similarity order; having
+    /// no rerank score, they are not held to `min_score`.
+    async fn rerank_with_budget(
+        &self,
+        client: Box<dyn Client>,
+        query: &str,
+        candidates: Vec<DocumentId>,
+        top_k: usize,
+        min_score: f64,
+        budget: Duration,
+    ) -> Result<Vec<DocumentId>> {
+        let started = std::time::Instant::now();
+        let mut reranked = vec![];
+        let mut rest = vec![];
+        for batch in candidates.chunks(top_k.max(1)) {
+            let remaining = budget.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                rest.extend_from_slice(batch);
+                continue;
+            }
+            let documents = batch
+                .iter()
+                .filter_map(|id| Some(self.data.get(*id)?.page_content.clone()))
+                .collect();
+            let data = RerankData::new(query.to_string(), documents, batch.len());
+            match tokio::time::timeout(remaining, client.rerank(data)).await {
+                Ok(list) => {
+                    for item in list? {
+                        if item.relevance_score < min_score {
+                            continue;
+                        }
+                        if let Some(id) = batch.get(item.index) {
+                            reranked.push((*id, item.relevance_score));
+                        }
+                    }
+                }
+                Err(_) => {
+                    debug!("rerank exceeded the budget of {}ms", budget.as_millis());
+                    rest.extend_from_slice(batch);
+                }
+            }
+        }
+        reranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let output = reranked
+            .into_iter()
+            .map(|(id, _)| id)
+            .chain(rest)
+            .take(top_k)
+            .collect();
+        Ok(output)
+    }
+
     async fn vector_search(
         &self,
         query: &str,
         top_k: usize,
         min_score: f32,
-    ) -> Result<Vec<DocumentId>> {
+        scope_ids: Option<&Vec<DocumentId>>,
+    ) -> Result<Vec<(DocumentId, f32)>> {
         let splitter = RecursiveCharacterTextSplitter::new(
             self.data.chunk_size,
             self.data.chunk_overlap,
@@ -378,18 +1196,27 @@ impl Rag {
         );
         let texts = splitter.split_text(query);
         let embeddings_data = EmbeddingsData::new(texts, true);
-        let embeddings = self.create_embeddings(embeddings_data, None).await?;
-        let output = self
-            .hnsw
-            .parallel_search(&embeddings, top_k, 30)
+        let embeddings = self.create_embeddings(embeddings_data, &[], None).await?;
+        let neighbours = match scope_ids {
+            Some(ids) => embeddings
+                .iter()
+                .map(|v| {
+                    self.hnsw
+                        .search_filter(v, top_k, 30, Some(ids as &dyn FilterT))
+                })
+                .collect(),
+            None => self.hnsw.parallel_search(&embeddings, top_k, 30),
+        };
+        let output = neighbours
             .into_iter()
             .flat_map(|list| {
                 list.into_iter()
                     .filter_map(|v| {
-                        if v.distance < min_score {
+                        let score = 1.0 - v.distance;
+                        if score < min_score {
                             return None;
                         }
-                        Some(v.d_id)
+                        Some((v.d_id, score))
                     })
                     .collect::<Vec<_>>()
             })
@@ -402,19 +1229,62 @@ impl Rag {
         query: &str,
         top_k: usize,
         min_score: f32,
+        scope_ids: Option<&Vec<DocumentId>>,
     ) -> Result<Vec<DocumentId>> {
-        let output = self.bm25.search(query, top_k, Some(min_score as f64));
+        let output = match scope_ids {
+            Some(ids) => self
+                .bm25
+                .search(query, usize::MAX, Some(min_score as f64))
+                .into_iter()
+                .filter(|id| ids.binary_search(id).is_ok())
+                .take(top_k)
+                .collect(),
+            None => self.bm25.search(query, top_k, Some(min_score as f64)),
+        };
         Ok(output)
     }
 
-    async fn create_embeddings(
-        &self,
-        data: EmbeddingsData,
-        progress_tx: Option<mpsc::UnboundedSender<String>>,
+    /// Returns the sorted ids of the documents whose file path matches `scope`.
+    fn scope_document_ids(&self, scope: &str) -> Result<Vec<DocumentId>> {
+        let matcher = scope_matcher(scope)?;
+        let mut ids = vec![];
+        for (file_index, file) in self.data.files.iter().enumerate() {
+            if matcher.is_match(&file.path) {
+                ids.extend(
+                    (0..file.documents.len())
+                        .map(|document_index| combine_document_id(file_index, document_index)),
+                );
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn report_progress(&self, current: usize, total: usize, path: &str, phase: RagPhase) {
+        if let Some(callback) = &self.progress_callback {
+            callback(&RagProgress {
+                current,
+                total,
+                path: path.to_string(),
+                phase,
+            });
+        }
+    }
+
+    async fn create_embeddings(
+        &self,
+        data: EmbeddingsData,
+        text_paths: &[&str],
+        progress_tx: Option<mpsc::UnboundedSender<String>>,
     ) -> Result<EmbeddingsOutput> {
         let EmbeddingsData { texts, query } = data;
         let mut output = vec![];
-        let batch_chunks = texts.chunks(self.embedding_model.max_batch_size());
+        let max_batch_size = self
+            .fallback_embedding_clients
+            .iter()
+            .map(|v| v.model().max_batch_size())
+            .fold(self.embedding_model.max_batch_size(), usize::min);
+        let batch_chunks = texts.chunks(max_batch_size);
         let batch_chunks_len = batch_chunks.len();
         progress(
             &progress_tx,
@@ -426,11 +1296,13 @@ impl Rag {
                 query,
             };
             let chunk_output = self
-                .embedding_client
-                .embeddings(chunk_data)
+                .embeddings_with_fallback(chunk_data)
                 .await
                 .context("Failed to create embedding")?;
             output.extend(chunk_output);
+            if let Some(path) = text_paths.get(output.len().saturating_sub(1)) {
+                self.report_progress(output.len(), texts.len(), path, RagPhase::Embedding);
+            }
             progress(
                 &progress_tx,
                 format!("Creating embeddings [{}/{batch_chunks_len}]", index + 1),
@@ -438,6 +1310,268 @@ impl Rag {
         }
         Ok(output)
     }
+
+    /// Embeds the chunks of indexed files, with up to `rag_embedding_concurrency`
+    /// batches in flight. A batch that fails is retried chunk by chunk, so one bad
+    /// chunk only costs itself.
+    async fn create_document_embeddings(
+        &self,
+        texts: Vec<String>,
+        text_paths: &[&str],
+        progress_tx: Option<mpsc::UnboundedSender<String>>,
+    ) -> Result<(Vec<Option<Vec<f32>>>, Vec<EmbeddingFailure>)> {
+        let mut output = vec![];
+        let mut failures = vec![];
+        let max_batch_size = self
+            .fallback_embedding_clients
+            .iter()
+            .map(|v| v.model().max_batch_size())
+            .fold(self.embedding_model.max_batch_size(), usize::min);
+        let batch_chunks = texts.chunks(max_batch_size);
+        let batch_chunks_len = batch_chunks.len();
+        progress(
+            &progress_tx,
+            format!("Creating embeddings [1/{batch_chunks_len}]"),
+        );
+        let mut tasks = stream::iter(batch_chunks.zip(text_paths.chunks(max_batch_size)))
+            .map(|(texts, paths)| self.create_document_batch_embeddings(texts, paths))
+            .buffered(self.embedding_concurrency.max(1));
+        let mut index = 0;
+        while let Some(ret) = tasks.next().await {
+            let (batch_output, batch_failures) = ret?;
+            output.extend(batch_output);
+            failures.extend(batch_failures);
+            index += 1;
+            if let Some(path) = text_paths.get(output.len().saturating_sub(1)) {
+                self.report_progress(output.len(), texts.len(), path, RagPhase::Embedding);
+            }
+            progress(
+                &progress_tx,
+                format!("Creating embeddings [{index}/{batch_chunks_len}]"),
+            );
+        }
+        Ok((output, failures))
+    }
+
+    async fn create_document_batch_embeddings(
+        &self,
+        texts: &[String],
+        text_paths: &[&str],
+    ) -> Result<(Vec<Option<Vec<f32>>>, Vec<EmbeddingFailure>)> {
+        check_aborted(self.loader_options.abort_signal.as_ref())?;
+        let mut output = vec![];
+        let mut failures = vec![];
+        let chunk_data = EmbeddingsData::new(texts.to_vec(), false);
+        match self.embeddings_with_fallback(chunk_data).await {
+            Ok(chunk_output) => output.extend(chunk_output.into_iter().map(Some)),
+            Err(err) if texts.len() == 1 => {
+                failures.push(EmbeddingFailure {
+                    path: text_paths[0].to_string(),
+                    error: err.to_string(),
+                });
+                output.push(None);
+            }
+            Err(err) => {
+                debug!("retry a failed embedding batch chunk by chunk, {err}");
+                for (text, path) in texts.iter().zip(text_paths) {
+                    check_aborted(self.loader_options.abort_signal.as_ref())?;
+                    let text_data = EmbeddingsData::new(vec![text.clone()], false);
+                    match self.embeddings_with_fallback(text_data).await {
+                        Ok(mut v) => output.push(v.pop()),
+                        Err(err) => {
+                            failures.push(EmbeddingFailure {
+                                path: path.to_string(),
+                                error: err.to_string(),
+                            });
+                            output.push(None);
+                        }
+                    }
+                }
+            }
+        }
+        Ok((output, failures))
+    }
+
+    async fn embeddings_with_fallback(&self, data: EmbeddingsData) -> Result<EmbeddingsOutput> {
+        let dimension = self
+            .embedding_model
+            .data()
+            .output_vector_size
+            .or_else(|| self.data.vectors.values().next().map(|v| v.len()));
+        let clients =
+            std::iter::once(&self.embedding_client).chain(&self.fallback_embedding_clients);
+        let mut last_err = None;
+        for client in clients {
+            for attempt in 0..=self.embedding_retries {
+                if attempt > 0 {
+                    tokio::time::sleep(Duration::from_secs(1 << (attempt - 1).min(5))).await;
+                }
+                if let Some(rate_limiter) = &self.embedding_rate_limiter {
+                    rate_limiter
+                        .acquire(self.loader_options.abort_signal.as_ref())
+                        .await?;
+                }
+                match client.embeddings(data.clone()).await {
+                    Ok(output) => {
+                        if let (Some(expect), Some(actual)) =
+                            (dimension, output.first().map(|v| v.len()))
+                        {
+                            if expect != actual {
+                                bail!(
+                                    "Embedding model '{}' returned {actual} dimensions, but the rag expects {expect}; the rag must be rebuilt to use it",
+                                    client.model().id()
+                                );
+                            }
+                        }
+                        return Ok(output);
+                    }
+                    Err(err) => {
+                        warn!(
+                            "embedding with '{}' failed (attempt {}): {err}",
+                            client.model().id(),
+                            attempt + 1
+                        );
+                        last_err = Some(err);
+                    }
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("No embedding client")))
+    }
+}
+
+/// Spaces out requests evenly so that no more than the configured number start per minute.
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next: parking_lot::Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    fn per_minute(requests: u32) -> Option<Self> {
+        if requests == 0 {
+            return None;
+        }
+        Some(Self {
+            interval: Duration::from_secs(60) / requests,
+            next: Default::default(),
+        })
+    }
+
+    /// Books the next free slot and returns how long to wait for it.
+    fn reserve(&self, now: Instant) -> Duration {
+        let mut next = self.next.lock();
+        let slot = next.map_or(now, |v| v.max(now));
+        *next = Some(slot + self.interval);
+        slot - now
+    }
+
+    async fn acquire(&self, abort_signal: Option<&AbortSignal>) -> Result<()> {
+        let now = Instant::now();
+        let deadline = now + self.reserve(now);
+        loop {
+            check_aborted(abort_signal)?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            tokio::time::sleep((deadline - now).min(Duration::from_millis(100))).await;
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RagPlan {
+    pub files: Vec<RagPlanFile>,
+    pub total_bytes: u64,
+    pub skipped: Vec<RagPlanSkipped>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimate: Option<RagEstimate>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RagEstimate {
+    pub documents: usize,
+    pub chars: usize,
+    pub tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl RagEstimate {
+    pub fn add_documents(&mut self, documents: &[RagDocument]) {
+        for document in documents {
+            self.documents += 1;
+            self.chars += document.page_content.chars().count();
+            self.tokens += estimate_token_length(&document.page_content);
+        }
+    }
+}
+
+/// How the files of a rag have drifted from what was indexed.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct RagStatus {
+    pub missing: Vec<String>,
+    pub changed: Vec<String>,
+    pub new: Vec<String>,
+}
+
+impl RagStatus {
+    pub fn new(indexed: &[&str], manifest: &RagManifest, listed: &[String]) -> Self {
+        let mut status = Self::default();
+        for path in indexed
+            .iter()
+            .filter(|v| !is_url(v) && !manifest.imported.contains(**v))
+        {
+            if !Path::new(path).exists() {
+                status.missing.push(path.to_string());
+            } else if !manifest
+                .files
+                .get(*path)
+                .is_some_and(|v| v.is_current(path))
+            {
+                status.changed.push(path.to_string());
+            }
+        }
+        status.new = listed
+            .iter()
+            .filter(|v| {
+                !is_url(v) && !indexed.contains(&v.as_str()) && !manifest.imported.contains(*v)
+            })
+            .cloned()
+            .collect();
+        status
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty() && self.new.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RagPlanFile {
+    pub path: String,
+    pub loader: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RagPlanSkipped {
+    pub path: String,
+    pub reason: String,
+}
+
+/// A chunk returned by `Rag::search`. `score` is its vector similarity to the
+/// query, `None` when only the keyword search found it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RagChunk {
+    pub text: String,
+    pub score: Option<f32>,
+}
+
+/// The similarity of the closest chunk, `None` when none has a vector score.
+pub fn best_chunk_score(chunks: &[RagChunk]) -> Option<f32> {
+    chunks.iter().filter_map(|v| v.score).reduce(f32::max)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -477,6 +1611,72 @@ impl RagData {
         self.vectors.extend(vector_ids.into_iter().zip(embeddings));
     }
 
+    pub fn has_same_settings(&self, other: &RagData) -> bool {
+        self.embedding_model == other.embedding_model
+            && self.chunk_size == other.chunk_size
+            && self.chunk_overlap == other.chunk_overlap
+    }
+
+    /// Appends the files of `other`, re-numbering their vectors after the present ones.
+    pub fn append(&mut self, other: RagData) {
+        let base_file_index = self.files.len();
+        self.files.extend(other.files);
+        self.vectors
+            .extend(other.vectors.into_iter().map(|(id, vector)| {
+                let (file_index, document_index) = split_document_id(id);
+                let id = combine_document_id(base_file_index + file_index, document_index);
+                (id, vector)
+            }));
+    }
+
+    /// A copy holding the files from `start` on, numbered from 0.
+    pub fn files_from(&self, start: usize) -> RagData {
+        let mut data = RagData::new(
+            self.embedding_model.clone(),
+            self.chunk_size,
+            self.chunk_overlap,
+        );
+        data.files = self.files[start.min(self.files.len())..].to_vec();
+        data.vectors = self
+            .vectors
+            .iter()
+            .filter_map(|(id, vector)| {
+                let (file_index, document_index) = split_document_id(*id);
+                let file_index = file_index.checked_sub(start)?;
+                Some((
+                    combine_document_id(file_index, document_index),
+                    vector.clone(),
+                ))
+            })
+            .collect();
+        data
+    }
+
+    /// Keeps only the files matching the predicate, re-numbering the remaining vectors.
+    /// Returns the number of removed documents.
+    pub fn retain_files<F: FnMut(&RagFile) -> bool>(&mut self, mut f: F) -> usize {
+        let mut removed = 0;
+        let mut files = vec![];
+        let mut vectors = IndexMap::new();
+        for (file_index, file) in std::mem::take(&mut self.files).into_iter().enumerate() {
+            if !f(&file) {
+                removed += file.documents.len();
+                continue;
+            }
+            let new_file_index = files.len();
+            for document_index in 0..file.documents.len() {
+                let id = combine_document_id(file_index, document_index);
+                if let Some(vector) = self.vectors.swap_remove(&id) {
+                    vectors.insert(combine_document_id(new_file_index, document_index), vector);
+                }
+            }
+            files.push(file);
+        }
+        self.files = files;
+        self.vectors = vectors;
+        removed
+    }
+
     pub fn build_hnsw(&self) -> Hnsw<'static, f32, DistCosine> {
         let hnsw = Hnsw::new(32, self.vectors.len(), 16, 200, DistCosine {});
         let list: Vec<_> = self.vectors.iter().map(|(k, v)| (v, *k)).collect();
@@ -489,7 +1689,11 @@ impl RagData {
         for (file_index, file) in self.files.iter().enumerate() {
             for (document_index, document) in file.documents.iter().enumerate() {
                 let id = combine_document_id(file_index, document_index);
-                corpus.push((id, document.page_content.clone()));
+                let text = match document.metadata.get(KEYWORDS_METADATA) {
+                    Some(keywords) => format!("{}\n{keywords}", document.page_content),
+                    None => document.page_content.clone(),
+                };
+                corpus.push((id, text));
             }
         }
         BM25::new(corpus, BM25Options::default())
@@ -516,7 +1720,6 @@ impl RagDocument {
         }
     }
 
-    #[allow(unused)]
     pub fn with_metadata(mut self, metadata: RagMetadata) -> Self {
         self.metadata = metadata;
         self
@@ -536,6 +1739,168 @@ pub type RagMetadata = IndexMap<String, String>;
 
 pub type DocumentId = usize;
 
+async fn load_rag_file(
+    path: &str,
+    loader_options: &LoaderOptions,
+) -> Result<(String, Vec<RagDocument>, Option<FileFingerprint>)> {
+    let (extension, documents) = if is_url(path) {
+        load_url(path, loader_options).await
+    } else {
+        let (path, loader_options) = (path.to_string(), loader_options.clone());
+        tokio::task::spawn_blocking(move || {
+            let extension = Path::new(&path)
+                .extension()
+                .map(|v| v.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            let documents = load(&path, &extension, &loader_options)?;
+            Ok((extension, documents))
+        })
+        .await?
+    }
+    .with_context(|| format!("Failed to load file at '{path}'"))?;
+    let mut documents = loader_options.transforms.apply(documents);
+    if loader_options.detect_language {
+        for document in documents.iter_mut() {
+            if document.metadata.contains_key("language") {
+                continue;
+            }
+            if let Some(language) = detect_language(&document.page_content) {
+                document
+                    .metadata
+                    .insert("language".into(), language.to_string());
+            }
+        }
+    }
+    let fingerprint = match is_url(path) {
+        true => None,
+        false => Some(FileFingerprint::new(path)?),
+    };
+    Ok((extension, documents, fingerprint))
+}
+
+/// The files among `file_paths` that are indexed, or whose content matches an indexed file.
+fn find_present_files(
+    indexed: &[&str],
+    manifest: &RagManifest,
+    file_paths: &[String],
+) -> Result<Vec<String>> {
+    let hashes: HashSet<&str> = manifest.files.values().map(|v| v.hash.as_str()).collect();
+    let mut present = vec![];
+    for path in file_paths {
+        let same_content = !is_url(path)
+            && !hashes.is_empty()
+            && hashes.contains(FileFingerprint::new(path)?.hash.as_str());
+        if indexed.contains(&path.as_str()) || same_content {
+            present.push(path.clone());
+        }
+    }
+    Ok(present)
+}
+
+/// The lowercased extension of a local file, `None` for URLs.
+fn file_extension(path: &str) -> Option<String> {
+    if is_url(path) {
+        return None;
+    }
+    Path::new(path)
+        .extension()
+        .map(|v| v.to_string_lossy().to_lowercase())
+}
+
+/// Lists the files that were skipped for a reason other than the suffix filter,
+/// which only gets a count since it usually matches many files on purpose. Files
+/// of one type skipped for a missing tool are counted together.
+fn summarize_skipped_files(skipped: &[SkippedFile], indexed: usize) -> Option<String> {
+    let (unsupported, others): (Vec<_>, Vec<_>) = skipped
+        .iter()
+        .partition(|v| v.reason == SkipReason::UnsupportedExtension);
+    if others.is_empty() {
+        return None;
+    }
+    let missing_tool_key = |file: &SkippedFile| {
+        (file.reason == SkipReason::MissingTool)
+            .then(|| (file_extension(&file.path), file.detail.clone()))
+    };
+    let mut missing_tools: HashMap<(Option<String>, Option<String>), usize> = HashMap::new();
+    for key in others.iter().filter_map(|v| missing_tool_key(v)) {
+        *missing_tools.entry(key).or_default() += 1;
+    }
+    let mut reported = HashSet::new();
+    let mut lines = vec![format!(
+        "Indexed {indexed} file(s), skipped {} file(s):",
+        others.len()
+    )];
+    for file in others {
+        if let Some(key) = missing_tool_key(file) {
+            let count = missing_tools[&key];
+            if count > 1 {
+                if reported.insert(key.clone()) {
+                    let (extension, detail) = key;
+                    let kind = extension.map(|v| format!(".{v} ")).unwrap_or_default();
+                    let detail = detail.map(|v| format!(": {v}")).unwrap_or_default();
+                    lines.push(format!("- {count} {kind}file(s) ({}){detail}", file.reason));
+                }
+                continue;
+            }
+        }
+        match &file.detail {
+            Some(detail) => lines.push(format!("- {} ({}): {detail}", file.path, file.reason)),
+            None => lines.push(format!("- {} ({})", file.path, file.reason)),
+        }
+    }
+    if !unsupported.is_empty() {
+        lines.push(format!(
+            "{} more file(s) did not match the suffix filter",
+            unsupported.len()
+        ));
+    }
+    Some(lines.join("\n"))
+}
+
+/// Fails when more chunks failed to embed than `max_ratio` allows, and otherwise
+/// returns a summary of the skipped chunks grouped by file.
+fn check_embedding_failures(
+    failures: &[EmbeddingFailure],
+    total: usize,
+    max_ratio: f32,
+) -> Result<Option<String>> {
+    if failures.is_empty() {
+        return Ok(None);
+    }
+    let mut files: IndexMap<&str, (usize, &str)> = IndexMap::new();
+    for failure in failures {
+        files
+            .entry(failure.path.as_str())
+            .or_insert((0, failure.error.as_str()))
+            .0 += 1;
+    }
+    let details = files
+        .iter()
+        .map(|(path, (count, error))| format!("- {path}: {count} chunk(s), {error}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let ratio = failures.len() as f32 / total.max(1) as f32;
+    if ratio > max_ratio {
+        bail!(
+            "Failed to embed {} of {total} chunks, more than the allowed {:.0}%:\n{details}",
+            failures.len(),
+            max_ratio * 100.0
+        );
+    }
+    Ok(Some(format!(
+        "Skipped {} of {total} chunks that failed to embed:\n{details}",
+        failures.len()
+    )))
+}
+
+/// Drops the documents whose hash is already in `seen`, recording the others.
+/// Returns the number of dropped documents.
+fn dedup_documents(documents: &mut Vec<RagDocument>, seen: &mut HashSet<String>) -> usize {
+    let documents_len = documents.len();
+    documents.retain(|v| seen.insert(sha256(&v.page_content)));
+    documents_len - documents.len()
+}
+
 pub fn combine_document_id(file_index: usize, document_index: usize) -> DocumentId {
     file_index << (usize::BITS / 2) | document_index
 }
@@ -547,6 +1912,74 @@ pub fn split_document_id(value: DocumentId) -> (usize, usize) {
     (high, low)
 }
 
+async fn list_paths<T: AsRef<Path>>(
+    paths: &[T],
+    loader_options: &mut LoaderOptions,
+) -> Result<Vec<String>> {
+    let mut file_paths = vec![];
+    for path in paths {
+        let url = path.as_ref().display().to_string();
+        if is_url(&url) {
+            if !file_paths.contains(&url) {
+                file_paths.push(url);
+            }
+            continue;
+        }
+        let path = path
+            .as_ref()
+            .absolutize()
+            .with_context(|| anyhow!("Invalid path '{}'", path.as_ref().display()))?;
+        let path_str = path.display().to_string();
+        let (path_str, suffixes, pattern) = parse_glob(&path_str)?;
+        let suffixes = if suffixes.is_empty() {
+            None
+        } else {
+            Some(&suffixes)
+        };
+        let start = file_paths.len();
+        list_files(
+            &mut file_paths,
+            Path::new(&path_str),
+            suffixes,
+            pattern.as_deref(),
+            loader_options,
+        )
+        .await?;
+        if let Some(suffixes) = suffixes {
+            for file_path in &file_paths[start..] {
+                if is_archive(Path::new(file_path)) {
+                    loader_options
+                        .archive_suffixes
+                        .insert(file_path.clone(), suffixes.clone());
+                }
+            }
+        }
+    }
+    Ok(file_paths)
+}
+
+/// Builds the matcher for a retrieval scope. A scope without glob characters is a
+/// path prefix, and a relative scope may match anywhere in the path.
+pub fn scope_matcher(scope: &str) -> Result<globset::GlobMatcher> {
+    let scope = scope.trim();
+    let mut pattern = match scope.contains(['*', '?', '{', '[']) {
+        true => scope.to_string(),
+        false => format!("{}/**", scope.trim_end_matches('/')),
+    };
+    if !Path::new(scope).is_absolute() && !pattern.starts_with("**") {
+        pattern = format!("**/{pattern}");
+    }
+    let glob = globset::GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Invalid scope '{scope}'"))?;
+    Ok(glob.compile_matcher())
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
 fn select_embedding_model(models: &[&Model]) -> Result<String> {
     let models: Vec<_> = models
         .iter()
@@ -595,7 +2028,7 @@ fn set_chunk_overlay(default_value: usize) -> Result<usize> {
 fn add_doc_paths() -> Result<Vec<String>> {
     let text = Text::new("Add document paths:")
         .with_validator(required!("This field is required"))
-        .with_help_message("e.g. file1;dir2/;dir3/**/*.md")
+        .with_help_message("e.g. file1;dir2/;dir3/**/*.md;https://example.com/doc.pdf")
         .prompt()?;
     let paths = text.split(';').map(|v| v.to_string()).collect();
     Ok(paths)
@@ -631,3 +2064,649 @@ fn reciprocal_rank_fusion(
         .map(|(v, _)| v)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+
+    /// Embeds a text as its counts of `a` and `b`, and reranks by the count of `a`.
+    #[derive(Clone)]
+    struct FakeClient {
+        config: GlobalConfig,
+        model: Model,
+        embedded: Arc<parking_lot::Mutex<Vec<String>>>,
+        rerank_delay: Duration,
+    }
+
+    impl FakeClient {
+        fn new() -> Self {
+            Self {
+                config: Arc::new(parking_lot::RwLock::new(Config::default())),
+                model: Model::default(),
+                embedded: Default::default(),
+                rerank_delay: Duration::ZERO,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Client for FakeClient {
+        fn global_config(&self) -> &GlobalConfig {
+            &self.config
+        }
+
+        fn extra_config(&self) -> Option<&ExtraConfig> {
+            None
+        }
+
+        fn patches_config(&self) -> Option<&ModelPatches> {
+            None
+        }
+
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn model(&self) -> &Model {
+            &self.model
+        }
+
+        fn model_mut(&mut self) -> &mut Model {
+            &mut self.model
+        }
+
+        async fn chat_completions_inner(
+            &self,
+            _client: &reqwest::Client,
+            _data: ChatCompletionsData,
+        ) -> Result<ChatCompletionsOutput> {
+            bail!("No chat api")
+        }
+
+        async fn chat_completions_streaming_inner(
+            &self,
+            _client: &reqwest::Client,
+            _handler: &mut SseHandler,
+            _data: ChatCompletionsData,
+        ) -> Result<()> {
+            bail!("No chat api")
+        }
+
+        async fn embeddings(&self, data: EmbeddingsData) -> Result<Vec<Vec<f32>>> {
+            if !data.query {
+                self.embedded.lock().extend(data.texts.iter().cloned());
+            }
+            Ok(data
+                .texts
+                .iter()
+                .map(|v| {
+                    vec![
+                        v.matches('a').count() as f32 + 0.01,
+                        v.matches('b').count() as f32 + 0.01,
+                    ]
+                })
+                .collect())
+        }
+
+        async fn rerank(&self, data: RerankData) -> Result<RerankOutput> {
+            tokio::time::sleep(self.rerank_delay).await;
+            Ok(data
+                .documents
+                .iter()
+                .enumerate()
+                .map(|(index, v)| RerankResult {
+                    index,
+                    relevance_score: v.matches('a').count() as f64,
+                })
+                .collect())
+        }
+    }
+
+    fn test_rag(client: &FakeClient, path: &Path) -> Rag {
+        let data = RagData::new("fake".into(), 1000, 0);
+        Rag {
+            name: "test".into(),
+            path: path.display().to_string(),
+            embedding_model: client.model.clone(),
+            hnsw: data.build_hnsw(),
+            bm25: data.build_bm25(),
+            data,
+            embedding_client: Box::new(client.clone()),
+            fallback_embedding_clients: vec![],
+            embedding_retries: 0,
+            max_embedding_failure_ratio: 1.0,
+            embedding_concurrency: 1,
+            embedding_rate_limiter: None,
+            embedding_failures: vec![],
+            skipped_files: vec![],
+            indexed_files: 0,
+            loader_options: LoaderOptions::default(),
+            manifest: RagManifest::default(),
+            progress_callback: None,
+        }
+    }
+
+    /// A fresh directory under the temp dir holding `files`.
+    fn test_dir(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("aichat-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_rerank_with_budget() {
+        let mut client = FakeClient::new();
+        let mut rag = test_rag(&client, Path::new("rag.bin"));
+        let texts = ["b", "aa", "aaa", "a"];
+        let file = RagFile {
+            path: "a.txt".into(),
+            documents: texts.iter().map(|v| RagDocument::new(*v)).collect(),
+        };
+        let ids: Vec<_> = (0..texts.len())
+            .map(|v| combine_document_id(0, v))
+            .collect();
+        rag.data
+            .add(vec![file], ids.clone(), vec![vec![1.0, 0.0]; texts.len()]);
+        let rerank = |client: &FakeClient, budget: u64| {
+            let client = Box::new(client.clone());
+            let rag = &rag;
+            let ids = ids.clone();
+            async move {
+                let ids = rag
+                    .rerank_with_budget(client, "a", ids, 2, 1.0, Duration::from_millis(budget))
+                    .await
+                    .unwrap();
+                ids.into_iter()
+                    .map(|id| rag.data.get(id).unwrap().page_content.clone())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(rerank(&client, 1000).await, ["aaa", "aa"]);
+        client.rerank_delay = Duration::from_millis(100);
+        // Only the first batch is reranked in time, "b" falling below the minimum score;
+        // the second batch keeps its similarity order.
+        assert_eq!(rerank(&client, 150).await, ["aa", "aaa"]);
+        // Unranked candidates are not filtered by the minimum score.
+        assert_eq!(rerank(&client, 0).await, ["b", "aa"]);
+    }
+
+    #[tokio::test]
+    async fn test_vector_search_min_score() {
+        let dir = test_dir("vector-search", &[("a.txt", "aaaa"), ("b.txt", "bbbb")]);
+        let client = FakeClient::new();
+        let mut rag = test_rag(&client, &dir.join("rag.bin"));
+        rag.add_paths(&[&dir], None).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let texts = |ids: Vec<(DocumentId, f32)>| -> Vec<String> {
+            ids.into_iter()
+                .map(|(id, _)| rag.data.get(id).unwrap().page_content.clone())
+                .collect()
+        };
+        // The threshold is a minimum cosine similarity, so raising it drops the far chunk.
+        let ids = rag.vector_search("a", 2, 0.0, None).await.unwrap();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.iter().all(|(_, score)| (-1.0..=1.0).contains(score)));
+        let ids = rag.vector_search("a", 2, 0.5, None).await.unwrap();
+        assert_eq!(texts(ids), ["aaaa"]);
+        let ids = rag.vector_search("a", 2, 1.01, None).await.unwrap();
+        assert!(ids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import_documents() {
+        let dir = test_dir(
+            "import",
+            &[
+                ("a.txt", "aaaa"),
+                (
+                    "embedded.jsonl",
+                    "{\"page_content\": \"bbb\", \"embedding\": [0.0, 1.0]}\n\n{\"page_content\": \"ab\", \"metadata\": {\"page\": \"2\"}, \"embedding\": [1.0, 1.0]}\n",
+                ),
+                ("mismatch.json", r#"[{"page_content": "b", "embedding": [1.0]}]"#),
+            ],
+        );
+        let client = FakeClient::new();
+        let mut rag = test_rag(&client, &dir.join("rag.bin"));
+        rag.add_paths(&[dir.join("a.txt")], None).await.unwrap();
+        let embedded = dir.join("embedded.jsonl").display().to_string();
+
+        assert_eq!(rag.import_documents(&embedded).unwrap(), 2);
+        assert_eq!(rag.data.files.len(), 2);
+        assert_eq!(rag.data.files[1].documents[1].metadata["page"], "2");
+        assert!(rag.manifest.imported.contains(&embedded));
+        // Importing the same file again replaces its documents.
+        assert_eq!(rag.import_documents(&embedded).unwrap(), 2);
+        assert_eq!(rag.data.vectors.len(), 3);
+
+        let err = rag
+            .import_documents(&dir.join("mismatch.json").display().to_string())
+            .unwrap_err();
+        assert!(err.to_string().contains("has 1 dimensions"));
+        assert_eq!(rag.data.files.len(), 2);
+
+        // Syncing keeps the imported documents although no document path lists them.
+        rag.sync(&[dir.join("a.txt")], None).await.unwrap();
+        assert_eq!(
+            rag.files(),
+            [dir.join("a.txt").display().to_string(), embedded]
+        );
+        assert_eq!(client.embedded.lock().as_slice(), ["aaaa"]);
+        let ids = rag.vector_search("b", 1, 0.0, None).await.unwrap();
+        assert_eq!(rag.data.get(ids[0].0).unwrap().page_content, "bbb");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_present_files() {
+        let dir = std::env::temp_dir().join(format!("aichat-present-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        for (name, contents) in [("a.md", "a"), ("copy.md", "a"), ("b.md", "b")] {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        let mut manifest = RagManifest::default();
+        manifest
+            .files
+            .insert(path("a.md"), FileFingerprint::new(&path("a.md")).unwrap());
+        let indexed = [path("a.md")];
+        let indexed: Vec<&str> = indexed.iter().map(|v| v.as_str()).collect();
+        let file_paths = vec![path("a.md"), path("copy.md"), path("b.md")];
+        let present = find_present_files(&indexed, &manifest, &file_paths);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            present.unwrap(),
+            vec![file_paths[0].clone(), file_paths[1].clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_document_transforms() {
+        let path =
+            std::env::temp_dir().join(format!("aichat-transform-{}.txt", std::process::id()));
+        std::fs::write(&path, "Contact jane@example.com for access.\n\nFOOTER").unwrap();
+        let mut config = Config::default();
+        config.register_document_transform(|mut document: RagDocument| {
+            document.page_content = document.page_content.replace("jane@example.com", "[email]");
+            vec![document]
+        });
+        config.register_document_transform(|mut document: RagDocument| {
+            document.page_content = document
+                .page_content
+                .trim_end_matches("FOOTER")
+                .trim()
+                .into();
+            vec![document]
+        });
+        let options = LoaderOptions::new(&config);
+        let (_, documents, _) = load_rag_file(&path.display().to_string(), &options)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let texts: Vec<_> = documents.iter().map(|v| v.page_content.as_str()).collect();
+        assert_eq!(texts, ["Contact [email] for access."]);
+
+        let documents = vec![RagDocument::new("a"), RagDocument::new("b")];
+        assert_eq!(DocumentTransforms::default().apply(documents).len(), 2);
+    }
+
+    #[test]
+    fn test_summarize_skipped_files() {
+        let skipped = |path: &str, reason, detail: Option<&str>| SkippedFile {
+            path: path.into(),
+            reason,
+            detail: detail.map(|v| v.into()),
+        };
+        let files = [skipped("a.png", SkipReason::UnsupportedExtension, None)];
+        assert_eq!(summarize_skipped_files(&files, 3), None);
+        let files = [
+            skipped("a.png", SkipReason::UnsupportedExtension, None),
+            skipped(
+                "b.docx",
+                SkipReason::MissingTool,
+                Some("Need to install pandoc"),
+            ),
+            skipped("c.bin", SkipReason::Binary, None),
+        ];
+        assert_eq!(
+            summarize_skipped_files(&files, 3).unwrap(),
+            "Indexed 3 file(s), skipped 2 file(s):\n- b.docx (missing_tool): Need to install pandoc\n- c.bin (binary)\n1 more file(s) did not match the suffix filter"
+        );
+        let need_pdftotext = Some("Need to install pdftotext");
+        let files = [
+            skipped("a.pdf", SkipReason::MissingTool, need_pdftotext),
+            skipped("b.md", SkipReason::ParseError, Some("invalid utf-8")),
+            skipped("docs/c.PDF", SkipReason::MissingTool, need_pdftotext),
+        ];
+        assert_eq!(
+            summarize_skipped_files(&files, 40).unwrap(),
+            "Indexed 40 file(s), skipped 3 file(s):\n- 2 .pdf file(s) (missing_tool): Need to install pdftotext\n- b.md (parse_error): invalid utf-8"
+        );
+    }
+
+    #[test]
+    fn test_best_chunk_score() {
+        let chunk = |score| RagChunk {
+            text: "x".into(),
+            score,
+        };
+        assert_eq!(best_chunk_score(&[]), None);
+        assert_eq!(best_chunk_score(&[chunk(None)]), None);
+        assert_eq!(
+            best_chunk_score(&[chunk(Some(0.4)), chunk(None), chunk(Some(0.7))]),
+            Some(0.7)
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        assert!(RateLimiter::per_minute(0).is_none());
+        let limiter = RateLimiter::per_minute(120).unwrap();
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(now), Duration::ZERO);
+        assert_eq!(limiter.reserve(now), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(now), Duration::from_millis(1000));
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_check_embedding_failures() {
+        let failure = |path: &str| EmbeddingFailure {
+            path: path.to_string(),
+            error: "bad input".to_string(),
+        };
+        assert!(check_embedding_failures(&[], 10, 0.1).unwrap().is_none());
+        let summary = check_embedding_failures(&[failure("a.md")], 10, 0.1)
+            .unwrap()
+            .unwrap();
+        assert!(summary.contains("1 of 10"));
+        assert!(summary.contains("- a.md: 1 chunk(s), bad input"));
+        let failures = [failure("a.md"), failure("a.md"), failure("b.md")];
+        let err = check_embedding_failures(&failures, 10, 0.1).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("3 of 10"));
+        assert!(err.contains("- a.md: 2 chunk(s)"));
+        assert!(err.contains("- b.md: 1 chunk(s)"));
+    }
+
+    #[test]
+    fn test_scope_matcher() {
+        let matcher = scope_matcher("legal/**").unwrap();
+        assert!(matcher.is_match("/docs/legal/contracts/a.md"));
+        assert!(!matcher.is_match("/docs/finance/a.md"));
+        let matcher = scope_matcher("legal/").unwrap();
+        assert!(matcher.is_match("/docs/legal/a.md"));
+        assert!(!matcher.is_match("/docs/illegal/a.md"));
+        let matcher = scope_matcher("/docs/finance").unwrap();
+        assert!(matcher.is_match("/docs/finance/2024/q1.csv"));
+        assert!(!matcher.is_match("/other/docs/finance/q1.csv"));
+        let matcher = scope_matcher("*.pdf").unwrap();
+        assert!(matcher.is_match("/docs/a.pdf"));
+        assert!(scope_matcher("legal/[").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let dir = test_dir("sync", &[("a.txt", "aaaa"), ("b.txt", "bbbb")]);
+        let client = FakeClient::new();
+        let mut rag = test_rag(&client, &dir.join("rag.bin"));
+        assert!(rag.sync(&[&dir], None).await.unwrap());
+        assert_eq!(client.embedded.lock().len(), 2);
+
+        // Touching a file does not re-embed it, editing one re-embeds only that one.
+        let file = std::fs::File::options()
+            .write(true)
+            .open(dir.join("a.txt"))
+            .unwrap();
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert!(!rag.sync(&[&dir], None).await.unwrap());
+        std::fs::write(dir.join("b.txt"), "bbbbb").unwrap();
+        assert!(rag.sync(&[&dir], None).await.unwrap());
+        std::fs::remove_file(dir.join("a.txt")).unwrap();
+        assert!(rag.sync(&[&dir], None).await.unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut embedded = client.embedded.lock().clone();
+        embedded[..2].sort();
+        assert_eq!(embedded, ["aaaa", "bbbb", "bbbbb"]);
+        assert_eq!(rag.data.files.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resume_checkpoint() {
+        let dir = test_dir("resume", &[("a.txt", "aaaa"), ("b.txt", "bbbb")]);
+        let client = FakeClient::new();
+        let rag_path = dir.join("rag.bin");
+        let mut rag = test_rag(&client, &rag_path);
+        let mut checkpoint = RagCheckpoint::new(&rag_path);
+        let paths = [dir.join("a.txt"), dir.join("b.txt")];
+        rag.add_paths_with_checkpoints(&paths, Some(&mut checkpoint), None)
+            .await
+            .unwrap();
+        std::fs::write(dir.join("b.txt"), "bbbbb").unwrap();
+
+        // The changed file is dropped and embedded again, the other one is not.
+        let mut rag = test_rag(&client, &rag_path);
+        let mut checkpoint = RagCheckpoint::new(&rag_path);
+        rag.resume_checkpoint(&mut checkpoint).unwrap();
+        let a = dir.join("a.txt").display().to_string();
+        assert_eq!(rag.files(), [a.as_str()]);
+        assert_eq!(rag.manifest.files.keys().collect::<Vec<_>>(), [&a]);
+        rag.add_paths_with_checkpoints(&paths, Some(&mut checkpoint), None)
+            .await
+            .unwrap();
+        assert_eq!(rag.data.files.len(), 2);
+        assert_eq!(client.embedded.lock().as_slice(), ["aaaa", "bbbb", "bbbbb"]);
+
+        // A checkpoint built with another chunk size is discarded.
+        let mut rag = test_rag(&client, &rag_path);
+        rag.data.chunk_size = 500;
+        let mut checkpoint = RagCheckpoint::new(&rag_path);
+        rag.resume_checkpoint(&mut checkpoint).unwrap();
+        assert!(rag.data.files.is_empty());
+        let loaded = checkpoint.load().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(loaded.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_progress_callback() {
+        let dir = test_dir("progress", &[("a.txt", "aaaa"), ("b.txt", "bbbb")]);
+        let client = FakeClient::new();
+        let mut rag = test_rag(&client, &dir.join("rag.bin"));
+        let reports = Arc::new(parking_lot::Mutex::new(vec![]));
+        rag.progress_callback = Some(Arc::new({
+            let reports = reports.clone();
+            move |progress: &RagProgress| reports.lock().push(progress.clone())
+        }));
+        let paths = [dir.join("a.txt"), dir.join("b.txt")];
+        rag.add_paths(&paths, None).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let reports = reports.lock();
+        let phases: Vec<_> = reports
+            .iter()
+            .map(|v| (v.phase, v.current, v.total))
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                (RagPhase::Loading, 1, 2),
+                (RagPhase::Loading, 2, 2),
+                (RagPhase::Embedding, 1, 2),
+                (RagPhase::Embedding, 2, 2),
+            ]
+        );
+        assert_eq!(
+            reports[0].to_string(),
+            format!("Loading files [1/2] {}", paths[0].display())
+        );
+        assert_eq!(reports[3].path, paths[1].display().to_string());
+    }
+
+    #[test]
+    fn test_export() {
+        let client = FakeClient::new();
+        let mut rag = test_rag(&client, Path::new("rag.bin"));
+        let export = |rag: &Rag| -> serde_json::Value {
+            serde_yaml::from_str(&rag.export().unwrap()).unwrap()
+        };
+        assert!(export(&rag).get("embedding_failures").is_none());
+        assert!(export(&rag).get("skipped_files").is_none());
+        rag.embedding_failures.push(EmbeddingFailure {
+            path: "a.md".into(),
+            error: "rate limited".into(),
+        });
+        assert_eq!(
+            export(&rag)["embedding_failures"],
+            json!([{ "path": "a.md", "error": "rate limited" }])
+        );
+        rag.skipped_files.push(SkippedFile {
+            path: "b.png".into(),
+            reason: SkipReason::UnsupportedExtension,
+            detail: None,
+        });
+        assert_eq!(
+            export(&rag)["skipped_files"],
+            json!([{ "path": "b.png", "reason": "unsupported_extension" }])
+        );
+    }
+
+    #[test]
+    fn test_dedup_documents() {
+        let mut seen = HashSet::from([sha256("a")]);
+        let mut documents = ["a", "b", "c", "b"].map(RagDocument::new).to_vec();
+        assert_eq!(dedup_documents(&mut documents, &mut seen), 2);
+        let texts: Vec<_> = documents.iter().map(|v| v.page_content.as_str()).collect();
+        assert_eq!(texts, ["b", "c"]);
+        assert_eq!(seen.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_add_paths_dedup() {
+        let dir = test_dir(
+            "dedup",
+            &[
+                ("a.txt", "aaaa"),
+                ("b.txt", "aaaa"),
+                ("c.txt", "ab"),
+                ("d.txt", "ab"),
+            ],
+        );
+        let client = FakeClient::new();
+        let mut rag = test_rag(&client, &dir.join("rag.bin"));
+        rag.add_paths(&[dir.join("a.txt")], None).await.unwrap();
+        // Copies of indexed chunks and of each other are not embedded.
+        let paths = ["b.txt", "c.txt", "d.txt"].map(|v| dir.join(v));
+        rag.add_paths(&paths, None).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(client.embedded.lock().as_slice(), ["aaaa", "ab"]);
+        assert_eq!(rag.data.files.len(), 4);
+        assert_eq!(rag.data.vectors.len(), 2);
+    }
+
+    #[test]
+    fn test_retain_files() {
+        let mut data = RagData::new("fake".into(), 1000, 0);
+        let files = ["a", "b", "c"].map(|path| RagFile {
+            path: path.into(),
+            documents: vec![RagDocument::new(path), RagDocument::new(path.repeat(2))],
+        });
+        let ids = (0..3)
+            .flat_map(|file| (0..2).map(move |doc| combine_document_id(file, doc)))
+            .collect();
+        let vectors = (0..6).map(|v| vec![v as f32]).collect();
+        data.add(files.into(), ids, vectors);
+
+        assert_eq!(data.retain_files(|file| file.path != "b"), 2);
+        assert_eq!(data.files.len(), 2);
+        // The documents of "c" move to the freed file index, keeping their vectors.
+        assert_eq!(
+            data.get(combine_document_id(1, 1)).unwrap().page_content,
+            "cc"
+        );
+        assert_eq!(data.vectors[&combine_document_id(1, 0)], [4.0]);
+        assert_eq!(data.vectors[&combine_document_id(1, 1)], [5.0]);
+        assert!(!data.vectors.contains_key(&combine_document_id(2, 0)));
+        assert_eq!(data.retain_files(|_| true), 0);
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let dir = test_dir("prune", &[("a.txt", "aaaa"), ("b.txt", "bbbb")]);
+        let client = FakeClient::new();
+        let rag_path = dir.join("rag.bin");
+        let mut rag = test_rag(&client, &rag_path);
+        rag.add_paths(&[dir.join("a.txt"), dir.join("b.txt")], None)
+            .await
+            .unwrap();
+        assert_eq!(rag.prune().unwrap(), (0, 0));
+        assert!(!rag_path.exists());
+
+        std::fs::remove_file(dir.join("a.txt")).unwrap();
+        assert_eq!(rag.prune().unwrap(), (1, 1));
+        let b = dir.join("b.txt").display().to_string();
+        assert_eq!(rag.files(), [b.as_str()]);
+        assert_eq!(rag.manifest.files.keys().collect::<Vec<_>>(), [&b]);
+        // The index is rebuilt over the remaining document; searching it isn't reliable
+        // with a single point.
+        assert_eq!(rag.hnsw.get_nb_point(), 1);
+        // The pruned rag is saved with its manifest.
+        let data = read_rag_index(&rag_path).unwrap();
+        assert_eq!(data.files.len(), 1);
+        let manifest = RagManifest::load(&rag_path).unwrap().unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rag_status() {
+        let dir = std::env::temp_dir().join(format!("aichat-rag-status-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let (same, changed, missing, new) = (
+            path("same.md"),
+            path("changed.md"),
+            path("missing.md"),
+            path("new.md"),
+        );
+        std::fs::write(&same, "same").unwrap();
+        std::fs::write(&changed, "before").unwrap();
+        let mut manifest = RagManifest::default();
+        for path in [&same, &changed] {
+            manifest
+                .files
+                .insert(path.clone(), FileFingerprint::new(path).unwrap());
+        }
+        std::fs::write(&changed, "after").unwrap();
+        std::fs::write(&new, "new").unwrap();
+
+        let indexed = [same.as_str(), changed.as_str(), missing.as_str()];
+        let listed = [same.clone(), changed.clone(), new.clone()];
+        let status = RagStatus::new(&indexed, &manifest, &listed);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            status,
+            RagStatus {
+                missing: vec![missing],
+                changed: vec![changed],
+                new: vec![new],
+            }
+        );
+        assert!(!status.is_clean());
+    }
+
+    #[test]
+    fn test_rag_estimate() {
+        let mut estimate = RagEstimate::default();
+        estimate.add_documents(&[RagDocument::new("abcd abcd"), RagDocument::new("你好")]);
+        assert_eq!(estimate.documents, 2);
+        assert_eq!(estimate.chars, 11);
+        assert_eq!(estimate.tokens, 3 + 3);
+    }
+}
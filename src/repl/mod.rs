@@ -33,7 +33,7 @@ lazy_static! {
 const MENU_NAME: &str = "completion_menu";
 
 lazy_static! {
-    static ref REPL_COMMANDS: [ReplCommand; 26] = [
+    static ref REPL_COMMANDS: [ReplCommand; 31] = [
         ReplCommand::new(".help", "Show this help message", AssertState::pass()),
         ReplCommand::new(".info", "View system info", AssertState::pass()),
         ReplCommand::new(".model", "Change the current LLM", AssertState::pass()),
@@ -92,6 +92,31 @@ lazy_static! {
             "Init or use a rag",
             AssertState::False(StateFlags::AGENT)
         ),
+        ReplCommand::new(
+            ".rag scope",
+            "Scope retrieval to documents matching a path or glob",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".rag add",
+            "Add files to the rag without re-indexing the rest",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".rag import",
+            "Import documents with precomputed embeddings from a JSON file",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".rag prune",
+            "Remove the documents of deleted files from the rag",
+            AssertState::True(StateFlags::RAG),
+        ),
+        ReplCommand::new(
+            ".rag status",
+            "Check the rag for missing, changed or new files",
+            AssertState::True(StateFlags::RAG),
+        ),
         ReplCommand::new(
             ".info rag",
             "View rag info",
@@ -225,6 +250,10 @@ impl Repl {
                         let info = self.config.read().agent_info()?;
                         println!("{}", info);
                     }
+                    Some("agent --resolved") => {
+                        let info = self.config.read().agent_role_info()?;
+                        println!("{}", info);
+                    }
                     Some(_) => unknown_command()?,
                     None => {
                         let output = self.config.read().sysinfo()?;
@@ -259,19 +288,116 @@ impl Repl {
                 ".session" => {
                     self.config.write().use_session(args)?;
                 }
-                ".rag" => {
-                    Config::use_rag(&self.config, args, self.abort_signal.clone()).await?;
-                }
-                ".agent" => match args {
-                    Some(name) => {
-                        Config::use_agent(&self.config, name, None, self.abort_signal.clone())
+                ".rag" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    Some(("scope", scope)) => {
+                        let scope = scope.trim();
+                        self.config
+                            .write()
+                            .set_rag_scope(Some(scope).filter(|v| !v.is_empty()))?;
+                        match scope.is_empty() {
+                            true => println!("Cleared the rag scope"),
+                            false => println!("Scoped the rag to '{scope}'"),
+                        }
+                    }
+                    Some(("add", paths)) => {
+                        let mut paths =
+                            shell_words::split(paths).with_context(|| "Invalid args")?;
+                        let force = paths.iter().any(|v| v == "--force");
+                        paths.retain(|v| v != "--force");
+                        if paths.is_empty() {
+                            println!("Usage: .rag add <paths>... [--force]");
+                        } else {
+                            let added = Config::add_rag_documents(
+                                &self.config,
+                                &paths,
+                                force,
+                                self.abort_signal.clone(),
+                            )
                             .await?;
+                            println!("Added {} file(s) to the rag", added.len());
+                        }
+                    }
+                    Some(("import", path)) => {
+                        let path = path.trim();
+                        if path.is_empty() {
+                            println!("Usage: .rag import <file>");
+                        } else {
+                            let imported = Config::import_rag_documents(&self.config, path)?;
+                            println!("Imported {imported} document(s) to the rag");
+                        }
+                    }
+                    Some(("prune", "")) => {
+                        let (files, documents) = Config::prune_rag(&self.config)?;
+                        println!("Pruned {files} file(s) and {documents} document(s) from the rag");
+                    }
+                    Some(("status", "")) => {
+                        let status = Config::rag_status(&self.config).await?;
+                        match status.is_clean() {
+                            true => println!("The rag is up to date"),
+                            false => print!("{}", serde_yaml::to_string(&status)?),
+                        }
+                    }
+                    _ => {
+                        Config::use_rag(&self.config, args, self.abort_signal.clone()).await?;
+                    }
+                },
+                ".agent" => match args {
+                    Some("reload") if self.config.read().agent.is_some() => {
+                        if Config::reload_agent(&self.config, self.abort_signal.clone()).await? {
+                            println!("Reloaded the agent");
+                        } else {
+                            println!("The agent is up to date");
+                        }
+                    }
+                    Some(args)
+                        if args.starts_with("rag ") && self.config.read().agent.is_some() =>
+                    {
+                        let path = args["rag ".len()..].trim();
+                        Config::set_agent_rag(&self.config, path)?;
+                        println!("Switched the agent's rag to '{path}'");
+                    }
+                    Some(args) => {
+                        let (name, skip_rag) = match args.strip_suffix("--no-rag") {
+                            Some(name) => (name.trim(), true),
+                            None => (args, false),
+                        };
+                        Config::use_agent(
+                            &self.config,
+                            name,
+                            None,
+                            skip_rag,
+                            self.abort_signal.clone(),
+                        )
+                        .await?;
+                    }
+                    None => {
+                        let (active, name) = {
+                            let config = self.config.read();
+                            let name = config.current_agent_name().map(|v| v.to_string());
+                            (config.agent.is_some(), name)
+                        };
+                        match name {
+                            Some(name) if active => println!("Current agent: {name}"),
+                            Some(name) => {
+                                Config::use_agent(
+                                    &self.config,
+                                    &name,
+                                    None,
+                                    false,
+                                    self.abort_signal.clone(),
+                                )
+                                .await?
+                            }
+                            None => println!(
+                                r#"Usage: .agent <name> [--no-rag] | .agent reload | .agent rag <path>"#
+                            ),
+                        }
                     }
-                    None => println!(r#"Usage: .agent <name>"#),
                 },
                 ".starter" => match args {
                     Some(value) => {
-                        let input = Input::from_str(&self.config, value, None);
+                        let value = self.config.read().fill_starter(value)?;
+                        let input = Input::from_str(&self.config, &value, None);
                         ask(&self.config, self.abort_signal.clone(), input, true).await?;
                     }
                     None => {
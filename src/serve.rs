@@ -263,6 +263,7 @@ impl Server {
             temperature,
             top_p,
             functions: None,
+            response_format: None,
             stream,
         };
 
@@ -5,6 +5,7 @@ use std::sync::{
 
 pub type AbortSignal = Arc<AbortSignalInner>;
 
+#[derive(Debug)]
 pub struct AbortSignalInner {
     ctrlc: AtomicBool,
     ctrld: AtomicBool,